@@ -1,11 +1,20 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use std::{fs, time::Duration};
-use tempfile::NamedTempFile;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
 
 use once_cell::sync::Lazy;
-use patterns_of_distributed_systems::{KVStore, WriteBatch};
+use patterns_of_distributed_systems::wal::group_commit::GroupCommitWal;
+use patterns_of_distributed_systems::wal::segmented_log::{SegmentedWal, WALConfig};
+use patterns_of_distributed_systems::wal::{
+    Bincode, Codec, Json, MemoryStorage, Rkyv, Storage, WalEntry,
+};
+use patterns_of_distributed_systems::{DurabilityPolicy, KVStore, WriteBatch};
 
-const READ_WAL_PATH: &str = "/tmp/wal-read.log";
+const READ_WAL_PATH: &str = "/tmp/wal-read";
 
 fn criterion_config() -> Criterion {
     Criterion::default()
@@ -19,12 +28,12 @@ Benchmark 1: 1 000 individual puts
 fn bench_put_400(c: &mut Criterion) {
     c.bench_function("put_400", |b| {
         b.iter(|| {
-            let tmp = NamedTempFile::new().expect("");
-            let mut store =
-                KVStore::new(true, tmp.path().to_str().expect("")).expect("err with store");
+            let tmp = TempDir::new().expect("");
+            let mut store = KVStore::new(true, tmp.path().join("kv").to_str().expect(""))
+                .expect("err with store");
 
             for i in 0..400 {
-                store.put(&format!("k{i}"), "value");
+                store.put(&format!("k{i}"), "value").expect("put");
             }
 
             black_box(store);
@@ -38,16 +47,16 @@ Benchmark 2: 200 batches × 3 elements
 fn bench_batch_200x3(c: &mut Criterion) {
     c.bench_function("batch_200x3", |b| {
         b.iter(|| {
-            let tmp = NamedTempFile::new().expect("");
-            let mut store =
-                KVStore::new(true, tmp.path().to_str().expect("")).expect("err with store");
+            let tmp = TempDir::new().expect("");
+            let mut store = KVStore::new(true, tmp.path().join("kv").to_str().expect(""))
+                .expect("err with store");
 
             for batch_idx in 0..200 {
                 let mut batch = WriteBatch::default();
                 for item_idx in 0..3 {
                     batch.put(&format!("k{}_{}", batch_idx, item_idx), "value");
                 }
-                store.put_batch(batch);
+                store.put_batch(batch).expect("put");
             }
 
             black_box(store);
@@ -61,11 +70,10 @@ Benchmark 3: replaying an *existing* WAL
 static PREPARED: Lazy<()> = Lazy::new(|| {
     // Build a WAL once (same size as benchmark 1) so each iteration only
     // measures the *read* path.
-    let _ = fs::remove_file(READ_WAL_PATH); // ignore error if missing
     let mut store =
         KVStore::new(true /* truncate */, READ_WAL_PATH).expect("Error with opening store");
     for i in 0..500 {
-        store.put(&format!("k{i}"), "value");
+        store.put(&format!("k{i}"), "value").expect("put");
     }
 });
 
@@ -81,11 +89,275 @@ fn bench_read_existing(c: &mut Criterion) {
     });
 }
 
+/* ---------------------------------------------------------------------
+Benchmark 3b: replaying the same WAL, one Vec per frame vs mmap
+------------------------------------------------------------------ */
+fn existing_wal() -> SegmentedWal {
+    SegmentedWal::open(WALConfig {
+        path: READ_WAL_PATH.into(),
+        max_log_size: u64::MAX,
+        ..Default::default()
+    })
+    .expect("Error with opening wal")
+}
+
+fn bench_replay_existing(c: &mut Criterion) {
+    Lazy::force(&PREPARED);
+    let mut group = c.benchmark_group("replay_existing");
+
+    group.bench_function("copy_per_frame", |b| {
+        let mut wal = existing_wal();
+        b.iter(|| {
+            for frame in wal.read_from(1).expect("") {
+                let frame = frame.expect("");
+                black_box(frame.zero_copy().expect(""));
+            }
+        })
+    });
+
+    group.bench_function("mmap", |b| {
+        let mut wal = existing_wal();
+        b.iter(|| {
+            wal.replay(|frame| {
+                black_box(frame.entry);
+            })
+            .expect("")
+        })
+    });
+
+    group.finish();
+}
+
+/* ---------------------------------------------------------------------
+Benchmark 4: 8 threads × 25 fsynced puts, one fsync per put vs group commit
+------------------------------------------------------------------ */
+const THREADS: usize = 8;
+const PUTS_PER_THREAD: usize = 25;
+
+fn synced_wal(dir: &TempDir) -> SegmentedWal {
+    SegmentedWal::open(WALConfig {
+        path: dir.path().join("wal").to_str().expect("").into(),
+        max_log_size: u64::MAX,
+        durability: DurabilityPolicy::EveryWrite,
+        ..Default::default()
+    })
+    .expect("Error with opening wal")
+}
+
+fn put_from_threads(put: impl Fn(WalEntry) + Sync) {
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let put = &put;
+            s.spawn(move || {
+                for i in 0..PUTS_PER_THREAD {
+                    put(WalEntry::Set(format!("t{t}k{i}"), "value".into()));
+                }
+            });
+        }
+    });
+}
+
+fn bench_concurrent_put(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_put_8x25");
+    group.sample_size(10);
+
+    group.bench_function("mutex_fsync_per_put", |b| {
+        b.iter(|| {
+            let dir = TempDir::new().expect("");
+            let wal = Mutex::new(synced_wal(&dir));
+            put_from_threads(|cmd| {
+                wal.lock().expect("").write(cmd).expect("write");
+            });
+            black_box(wal);
+        })
+    });
+
+    group.bench_function("group_commit", |b| {
+        b.iter(|| {
+            let dir = TempDir::new().expect("");
+            let wal = Arc::new(GroupCommitWal::new(synced_wal(&dir)).expect(""));
+            put_from_threads(|cmd| {
+                wal.write(cmd).expect("write");
+            });
+            black_box(wal);
+        })
+    });
+
+    group.finish();
+}
+
+/* ---------------------------------------------------------------------
+Benchmark 5: 500 puts then a full read, per codec
+------------------------------------------------------------------ */
+fn in_memory_wal<C: Codec<WalEntry>>(
+    storage: &MemoryStorage,
+    codec: C,
+) -> SegmentedWal<WalEntry, C> {
+    SegmentedWal::open_with_codec(
+        WALConfig {
+            path: "/codec".into(),
+            max_log_size: u64::MAX,
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        },
+        codec,
+    )
+    .expect("Error with opening wal")
+}
+
+fn bench_codec<C: Codec<WalEntry> + Copy>(c: &mut Criterion, name: &str, codec: C) {
+    let storage = MemoryStorage::default();
+    let mut wal = in_memory_wal(&storage, codec);
+    for i in 0..500 {
+        wal.write(WalEntry::Set(format!("k{i}"), "value".into()))
+            .expect("write");
+    }
+    // What the log takes on disk, headers and padding included: blobs are padded to 16 bytes,
+    // so codecs whose entries differ by less can take the same room
+    let bytes: u64 = storage
+        .list("/codec".as_ref())
+        .expect("")
+        .iter()
+        .map(|path| storage.open(path, false).expect("").len().expect(""))
+        .sum();
+    drop(wal);
+
+    let mut group = c.benchmark_group(format!("codec_{name}"));
+    group.throughput(Throughput::Bytes(bytes));
+    group.bench_function("put_500", |b| {
+        b.iter(|| {
+            let storage = MemoryStorage::default();
+            let mut wal = in_memory_wal(&storage, codec);
+            for i in 0..500 {
+                wal.write(WalEntry::Set(format!("k{i}"), "value".into()))
+                    .expect("write");
+            }
+            black_box(wal);
+        })
+    });
+    group.bench_function("read_500", |b| {
+        let mut wal = in_memory_wal(&storage, codec);
+        b.iter(|| {
+            for frame in wal.read_from(1).expect("") {
+                black_box(frame.expect("").deserialize().expect(""));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_codecs(c: &mut Criterion) {
+    bench_codec(c, "rkyv", Rkyv);
+    bench_codec(c, "bincode", Bincode);
+    bench_codec(c, "json", Json);
+}
+
+/* ---------------------------------------------------------------------
+Benchmark 6: fsynced puts, growing segments vs preallocated vs recycled ones
+------------------------------------------------------------------ */
+fn bench_segment_allocation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fsynced_put");
+    for (name, preallocate, recycle) in [
+        ("growing", false, 0),
+        ("preallocated", true, 0),
+        ("preallocated_recycled", true, 4),
+    ] {
+        group.bench_function(name, |b| {
+            let dir = TempDir::new().expect("");
+            let mut wal = SegmentedWal::open(WALConfig {
+                path: dir.path().join("wal").to_str().expect("").into(),
+                max_log_size: 256 * 1024,
+                durability: DurabilityPolicy::EveryWrite,
+                preallocate,
+                recycle,
+                ..Default::default()
+            })
+            .expect("Error with opening wal");
+            let mut i = 0u64;
+            b.iter(|| {
+                i = wal
+                    .write(WalEntry::Set(format!("k{i}"), "value".into()))
+                    .expect("write");
+                // Keeps a few segments around, the older ones are dropped or recycled
+                if i.is_multiple_of(4096) {
+                    wal.truncate_before(i).expect("truncate");
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/* ---------------------------------------------------------------------
+Benchmark 7: puts over a fixed set of keys, once every buffer has grown,
+checking that none allocates
+------------------------------------------------------------------ */
+/// Counts the heap allocations of the current thread, so the benchmark can check its own.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+fn allocations() -> u64 {
+    ALLOCATIONS.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const STEADY_KEYS: usize = 64;
+
+fn bench_steady_state_put(c: &mut Criterion) {
+    let keys: Vec<String> = (0..STEADY_KEYS).map(|i| format!("k{i}")).collect();
+    c.bench_function("steady_state_put", |b| {
+        b.iter_custom(|iters| {
+            let tmp = TempDir::new().expect("");
+            // One segment however many puts criterion asks for, rolling allocates
+            let mut store = KVStore::from_config(WALConfig {
+                path: tmp.path().join("kv").to_str().expect("").into(),
+                max_log_size: u64::MAX,
+                preallocate: false,
+                ..Default::default()
+            })
+            .expect("err with store");
+            for key in &keys {
+                store.put(key, "value").expect("put");
+            }
+
+            let (before, start) = (allocations(), Instant::now());
+            for i in 0..iters as usize {
+                store.put(&keys[i % STEADY_KEYS], "value").expect("put");
+            }
+            let elapsed = start.elapsed();
+            let allocated = allocations() - before;
+            assert_eq!(allocated, 0, "{allocated} heap allocations in {iters} puts");
+            elapsed
+        })
+    });
+}
+
 /* --------------------------------------------------------------------- */
 criterion_group! {
     name = kvstore_benches;
     config = criterion_config();
-    targets = bench_put_400, bench_batch_200x3, bench_read_existing
+    targets = bench_put_400, bench_batch_200x3, bench_read_existing, bench_replay_existing, bench_concurrent_put, bench_codecs, bench_segment_allocation, bench_steady_state_put
 }
 
 criterion_main!(kvstore_benches);
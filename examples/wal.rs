@@ -1,10 +1,7 @@
 #![allow(dead_code, unused, unused_imports)]
-use rkyv::{access, rancor::Failure};
-use rkyv::{deserialize, rancor::Error, Archive, Deserialize, Serialize};
-use std::io::{self, Write};
-use std::io::{Read, Seek, SeekFrom};
-use std::process::abort;
-use std::{collections::HashMap, fs::File};
+use patterns_of_distributed_systems::wal::simple_wal::{WALConfig, WriteAheadLog};
+use rkyv::{Archive, Deserialize, Serialize};
+use std::collections::HashMap;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut kv = KVStore::new(true)?;
@@ -25,7 +22,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 #[derive(Debug)]
 struct KVStore {
     kv: HashMap<String, String>,
-    wal: WriteAheadLog,
+    wal: WriteAheadLog<Command>,
 }
 
 impl KVStore {
@@ -33,6 +30,7 @@ impl KVStore {
         let cfg = WALConfig {
             path: "/tmp/wal.log".into(),
             truncate,
+            ..Default::default()
         };
         let wal = WriteAheadLog::open(cfg)?;
         let mut store = Self {
@@ -62,7 +60,7 @@ impl KVStore {
     }
 
     pub fn put(&mut self, key: &str, value: &str) {
-        self.append_log(WalEntry::Set(SetValueCommand {
+        self.append_log(Command::Set(SetValueCommand {
             key: key.into(),
             value: value.into(),
         }));
@@ -70,12 +68,13 @@ impl KVStore {
     }
 
     pub fn put_batch(&mut self, batch: WriteBatch) {
-        self.append_log(WalEntry::Batch(WriteBatchCommand {
+        self.append_log(Command::Batch(WriteBatchCommand {
             kv: batch.elements.clone(),
         }));
     }
 
-    fn append_log(&mut self, entry: WalEntry) {
+    fn append_log(&mut self, entry: Command) {
+        println!("\tAppending log {:?}", entry);
         self.wal.write(entry);
     }
 
@@ -93,8 +92,8 @@ impl KVStore {
             match result {
                 Err(e) => println!("Error reading: {:?}", e),
                 Ok(cmd) => match cmd {
-                    WalEntry::Set(c) => self.apply_put(&c.key, &c.value),
-                    WalEntry::Batch(b) => self.apply_batch(b.kv),
+                    Command::Set(c) => self.apply_put(&c.key, &c.value),
+                    Command::Batch(b) => self.apply_batch(b.kv),
                 },
             }
         }
@@ -123,124 +122,9 @@ pub struct WriteBatchCommand {
     pub kv: HashMap<String, String>,
 }
 
+/// The log entries of this store. Any type rkyv can archive can be logged.
 #[derive(Archive, Deserialize, Serialize, Debug)]
-pub enum WalEntry {
+pub enum Command {
     Set(SetValueCommand),
     Batch(WriteBatchCommand),
 }
-
-impl WalEntry {
-    fn serialize(&self) -> rkyv::util::AlignedVec {
-        rkyv::to_bytes::<Error>(self).expect("serialize WalEntry")
-    }
-
-    fn deserialize<'a>(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
-        // This is not too efficient since we are deserializing and thus copying data
-        // We Should pass around the archived reference
-        let archived = rkyv::access::<ArchivedWalEntry, Failure>(bytes)?;
-        Ok(rkyv::deserialize::<WalEntry, Error>(archived)?)
-    }
-    fn zero_copy(bytes: &[u8]) -> Result<&ArchivedWalEntry, Box<dyn std::error::Error>> {
-        Ok(rkyv::access::<ArchivedWalEntry, Failure>(bytes)?)
-    }
-}
-
-#[derive(Default, Debug)]
-struct WALConfig {
-    path: String,
-    truncate: bool,
-}
-
-#[derive(Debug)]
-struct WriteAheadLog {
-    file: File,
-}
-
-impl WriteAheadLog {
-    /// Opens a new R/W WAL
-    ///
-    pub fn open(cfg: WALConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut f_opts = File::options();
-        f_opts.read(true).write(true).create(true);
-        match cfg.truncate {
-            true => f_opts.truncate(true),
-            false => f_opts.append(true),
-        };
-
-        let f = f_opts.open(&cfg.path)?;
-        Ok(Self { file: f })
-    }
-}
-
-impl WriteAheadLog {
-    /// Writes to a log file with the following structure
-    ///
-    ///┌──────────────┬───────────┐┌──────────────┬───────────┐┌──────────────┬───────────┐
-    ///│ 4‑byte len = │  N bytes  ││ 4‑byte len = │  M bytes  ││ 4‑byte len = │  K bytes  │ …
-    ///│  first blob  │〈archive〉││ second blob  │〈archive〉││ third blob   │〈archive〉│
-    ///└──────────────┴───────────┘└──────────────┴───────────┘└──────────────┴───────────┘
-    ///
-    /// It's not calling flush() constantly since we are not using a BufWriter as of now.
-    pub fn write(&mut self, cmd: WalEntry) -> Result<(), std::io::Error> {
-        let blob = cmd.serialize();
-        let blob_len = blob.len() as u32;
-
-        self.file.write_all(&blob_len.to_le_bytes())?;
-        self.file.write_all(&blob)?;
-        println!("\tAppending log {:?}", cmd);
-        Ok(())
-    }
-
-    pub fn read(&mut self) -> Vec<Result<WalEntry, Box<dyn std::error::Error + 'static>>> {
-        // Of course, reading the entire file and sending a Vec is not optimal.
-        // We should just have a generator
-
-        // Make sure we start at the beginning.
-        if let Err(e) = self.file.rewind() {
-            return vec![Err(Box::new(e))];
-        }
-
-        let mut out: Vec<Result<WalEntry, Box<dyn std::error::Error>>> = Vec::new();
-        let mut len_buf = [0u8; 4];
-
-        loop {
-            // 1. Read the length prefix
-            match self.file.read_exact(&mut len_buf) {
-                Ok(()) => {}
-                // Clean EOF – we’re done.
-                Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(e) => {
-                    out.push(Err(Box::new(e)));
-                    break;
-                }
-            }
-            let len = u32::from_le_bytes(len_buf) as usize;
-
-            // 2. Pull the archive itself
-            let mut buf = vec![0u8; len];
-            if let Err(e) = self.file.read_exact(&mut buf) {
-                out.push(Err(Box::new(e)));
-                break;
-            }
-
-            // 3. Validate + deserialize
-            let deser = WalEntry::deserialize(&buf);
-            out.push(deser);
-        }
-
-        out
-    }
-}
-
-impl Drop for WriteAheadLog {
-    /// Safeguard against "safe" exits.
-    ///
-    /// Does not work in external kill signals like sigkill, oom, power loss, segfault...
-    fn drop(&mut self) {
-        println!("  --> Flushing");
-        if let Err(e) = self.file.flush() {
-            eprintln!("WAL: failed to flush on drop: {e}");
-            // TODO: return this as a critical error in the error stack
-        }
-    }
-}
@@ -0,0 +1,300 @@
+//! Maintenance of WAL directories from the command line. Run `walctl help` for the commands.
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::process::exit;
+use std::str::FromStr;
+
+use patterns_of_distributed_systems::wal::inspect::{self, Segment};
+use patterns_of_distributed_systems::wal::segmented_log::{WALConfig, DEFAULT_MAX_LOG_SIZE};
+use patterns_of_distributed_systems::wal::{
+    migrate_simple_wal, ArchivedWalEntry, CodecId, KeyFile, KeyProvider, WalEntry,
+};
+
+const USAGE: &str = "\
+Usage: walctl <command> [arguments]
+
+Commands:
+  list <wal dir>
+      Lists the segments with their start index, size and entry count.
+  dump <wal dir | segment>
+      Prints every frame as a line of JSON: index, generation and entry.
+  verify <wal dir>
+      Checks every frame, the hash chain and that indexes are contiguous across segments.
+      Exits with 1 at the first problem.
+  stats <wal dir>
+      Prints how many entries of each type the log holds and the bytes they take.
+  repair <wal dir>
+      Cuts the log at the first corrupt or truncated frame and deletes the segments after
+      it. Only checksums are checked, no key is needed. The WAL must not be open.
+  migrate <simple_wal file> <wal dir> [--generation N] [--start-index N] [--segment-size BYTES]
+      Converts a simple_wal log of KVStore entries into a segmented WAL, written to <wal dir>
+      once complete. Entries get indexes from start-index + 1 (0 by default) and the given
+      generation (0 by default).
+  help
+      Prints this message.
+
+Every command but repair and migrate takes --key <key file> to read encrypted logs.
+";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, args)) = args.split_first() else {
+        usage()
+    };
+    let args = Args::parse(args)?;
+    match command.as_str() {
+        "list" => list(&args),
+        "dump" => dump(&args),
+        "verify" => verify(&args),
+        "stats" => stats(&args),
+        "repair" => repair(&args),
+        "migrate" => migrate(&args),
+        "help" | "--help" | "-h" => {
+            print!("{USAGE}");
+            Ok(())
+        }
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprint!("{USAGE}");
+    exit(2)
+}
+
+fn key(args: &Args) -> Option<Box<dyn KeyProvider>> {
+    let path = args.options.get("key")?;
+    Some(Box::new(KeyFile::new(path)))
+}
+
+/// Opens every segment of the WAL in `dir`, oldest first.
+fn segments(dir: &str, key: Option<&dyn KeyProvider>) -> Result<Vec<Segment>> {
+    let paths =
+        inspect::segment_paths(Path::new(dir)).with_context(|| format!("cannot read {dir}"))?;
+    paths
+        .iter()
+        .map(|path| {
+            Segment::open(path, key).with_context(|| format!("cannot open {}", path.display()))
+        })
+        .collect()
+}
+
+fn list(args: &Args) -> Result<()> {
+    let key = key(args);
+    println!(
+        "{:<50} {:>20} {:>12} {:>10}",
+        "segment", "start index", "bytes", "entries"
+    );
+    for mut segment in segments(args.positional(0, "wal dir")?, key.as_deref())? {
+        let mut entries = 0;
+        let problem = loop {
+            match segment.read_next() {
+                Ok(Some(_)) => entries += 1,
+                Ok(None) => break String::new(),
+                Err(e) => break format!("  {e}"),
+            }
+        };
+        println!(
+            "{:<50} {:>20} {:>12} {:>10}{problem}",
+            segment.path().display(),
+            segment.start_index(),
+            segment.size()?,
+            entries
+        );
+    }
+    Ok(())
+}
+
+fn dump(args: &Args) -> Result<()> {
+    let key = key(args);
+    let target = args.positional(0, "wal dir | segment")?;
+    let segments = match Path::new(target).is_dir() {
+        true => segments(target, key.as_deref())?,
+        false => vec![Segment::open(Path::new(target), key.as_deref())?],
+    };
+    for mut segment in segments {
+        while let Some(frame) = segment
+            .read_next()
+            .with_context(|| format!("in {}", segment.path().display()))?
+        {
+            let line = DumpedFrame {
+                index: frame.index,
+                generation: frame.generation,
+                entry: segment.decode(&frame)?,
+            };
+            let line = serde_json::to_string(&line)?;
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+/// A line of `walctl dump`.
+#[derive(serde::Serialize)]
+struct DumpedFrame {
+    index: u64,
+    generation: u64,
+    entry: WalEntry,
+}
+
+fn verify(args: &Args) -> Result<()> {
+    let key = key(args);
+    let (mut segments_seen, mut entries) = (0, 0);
+    let mut next_index = None;
+    let mut chain = None;
+    for mut segment in segments(args.positional(0, "wal dir")?, key.as_deref())? {
+        let path = segment.path().display().to_string();
+        if let Some(expected) = next_index.filter(|&next| next != segment.start_index()) {
+            fail(format!(
+                "{path}: starts at index {} but the previous segment ends before {expected}",
+                segment.start_index()
+            ));
+        }
+        chain = Some(
+            segment
+                .verify_chain(chain)
+                .unwrap_or_else(|e| fail(format!("{path}: {e}"))),
+        );
+        next_index = Some(segment.start_index());
+        loop {
+            let offset = segment.offset();
+            let frame = match segment.read_next() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => fail(format!("{path}: {e}")),
+            };
+            if let Err(e) = segment.decode(&frame) {
+                fail(format!(
+                    "{path}: entry {} at offset {offset}: {e}",
+                    frame.index
+                ));
+            }
+            entries += 1;
+            next_index = Some(frame.index + 1);
+        }
+        segments_seen += 1;
+    }
+    println!("ok: {entries} entries in {segments_seen} segments");
+    Ok(())
+}
+
+fn fail(problem: String) -> ! {
+    eprintln!("{problem}");
+    exit(1)
+}
+
+fn stats(args: &Args) -> Result<()> {
+    // Entry type -> (entries, blob bytes)
+    let mut types: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    let key = key(args);
+    for mut segment in segments(args.positional(0, "wal dir")?, key.as_deref())? {
+        while let Some(frame) = segment.read_next()? {
+            let kind = match segment.codec() {
+                // Looked at in place, no need to deserialize
+                CodecId::Rkyv => match frame.zero_copy()? {
+                    ArchivedWalEntry::Set(..) => "Set",
+                    ArchivedWalEntry::Batch(..) => "Batch",
+                },
+                _ => match segment.decode(&frame)? {
+                    WalEntry::Set(..) => "Set",
+                    WalEntry::Batch(..) => "Batch",
+                },
+            };
+            let stats = types.entry(kind).or_default();
+            stats.0 += 1;
+            stats.1 += frame.buf.len() as u64;
+        }
+    }
+    println!(
+        "{:<10} {:>12} {:>14} {:>14}",
+        "type", "entries", "bytes", "bytes/entry"
+    );
+    for (kind, (entries, bytes)) in types {
+        println!(
+            "{kind:<10} {entries:>12} {bytes:>14} {:>14.1}",
+            bytes as f64 / entries as f64
+        );
+    }
+    Ok(())
+}
+
+fn repair(args: &Args) -> Result<()> {
+    let dir = args.positional(0, "wal dir")?;
+    match inspect::repair(Path::new(dir))? {
+        None => println!("nothing to repair in {dir}"),
+        Some(repair) => println!(
+            "cut {} at offset {}, from index {}: dropped {} bytes and {} later segments",
+            repair.segment.display(),
+            repair.offset,
+            repair.index,
+            repair.dropped_bytes,
+            repair.dropped_segments
+        ),
+    }
+    Ok(())
+}
+
+fn migrate(args: &Args) -> Result<()> {
+    let src = args.positional(0, "simple_wal file")?;
+    let dest = args.positional(1, "wal dir")?;
+    let generation = args.option("generation", 0)?;
+    let cfg = WALConfig {
+        path: dest.into(),
+        start_index: args.option("start-index", 0)?,
+        max_log_size: args.option("segment-size", DEFAULT_MAX_LOG_SIZE)?,
+        ..Default::default()
+    };
+    let migrated = migrate_simple_wal::<WalEntry>(Path::new(src), cfg, generation)
+        .with_context(|| format!("cannot migrate {src} to {dest}"))?;
+    println!("migrated {migrated} entries from {src} to {dest}");
+    Ok(())
+}
+
+/// Positional arguments and `--name value` options, in any order.
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut positional = vec![];
+        let mut options = HashMap::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let Some(value) = args.next() else {
+                        bail!("--{name} needs a value");
+                    };
+                    options.insert(name.to_owned(), value.clone());
+                }
+                None => positional.push(arg.clone()),
+            }
+        }
+        Ok(Self {
+            positional,
+            options,
+        })
+    }
+
+    fn positional(&self, i: usize, what: &str) -> Result<&str> {
+        match self.positional.get(i) {
+            Some(arg) => Ok(arg),
+            None => bail!("missing <{what}>, see `walctl help`"),
+        }
+    }
+
+    fn option<T: FromStr>(&self, name: &str, default: T) -> Result<T>
+    where
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match self.options.get(name) {
+            Some(value) => value
+                .parse()
+                .with_context(|| format!("invalid --{name} {value}")),
+            None => Ok(default),
+        }
+    }
+}
@@ -1,35 +1,65 @@
 use std::collections::HashMap;
 
-use crate::wal::wal::{ArchivedWalEntry, WALConfig, WalEntry, WriteAheadLog};
+use crate::wal::segmented_log::{SegmentedWal, WALConfig};
+use crate::wal::{
+    ArchivedWalEntry, CompressionStats, DurabilityPolicy, WalEntry, WalResult, WriteAheadLog,
+};
 
+/// A key-value store kept in memory and rebuilt from its WAL on open. Any
+/// [`WriteAheadLog`] will do, a [`SegmentedWal`] by default.
 #[derive(Debug)]
-pub struct KVStore {
+pub struct KVStore<W = SegmentedWal> {
     kv: HashMap<String, String>,
-    wal: WriteAheadLog,
+    wal: W,
+    /// The entry of every [`KVStore::put`], refilled each time so its strings are reused.
+    set: WalEntry,
 }
 
 impl KVStore {
+    /// `file` is the directory the WAL keeps its segments and manifest in.
     pub fn new(truncate: bool, file: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let cfg = WALConfig {
-            path: file.into(),
-            truncate,
-        };
-        Self::from_walcfg(cfg)
+        Self::with_durability(truncate, file, DurabilityPolicy::default())
     }
 
     pub fn open(file: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new(false, file)
+    }
+
+    /// Like [`KVStore::new`] but choosing how durable each `put` is before it returns.
+    pub fn with_durability(
+        truncate: bool,
+        file: &str,
+        durability: DurabilityPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let cfg = WALConfig {
             path: file.into(),
-            truncate: false,
+            truncate,
+            durability,
+            ..Default::default()
         };
-        Self::from_walcfg(cfg)
+        Self::from_config(cfg)
+    }
+
+    /// Opens the store on a WAL configured by hand, e.g. kept in a
+    /// [`MemoryStorage`](crate::wal::MemoryStorage).
+    pub fn from_config(cfg: WALConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::with_wal(SegmentedWal::open(cfg)?)?)
     }
 
-    fn from_walcfg(cfg: WALConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let wal = WriteAheadLog::open(cfg)?;
+    /// How much the values written since opening were compressed, see
+    /// [`WALConfig::compression`].
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.wal.compression_stats()
+    }
+}
+
+impl<W: WriteAheadLog<WalEntry>> KVStore<W> {
+    /// Opens the store on an already opened WAL, replaying it.
+    pub fn with_wal(wal: W) -> WalResult<Self> {
         let mut store = Self {
             wal,
             kv: HashMap::default(),
+            set: WalEntry::Set(String::new(), String::new()),
         };
 
         store.apply_log()?;
@@ -37,54 +67,79 @@ impl KVStore {
     }
 }
 
-impl Drop for KVStore {
+impl<W> Drop for KVStore<W> {
     fn drop(&mut self) {
         // TODO: try to do a snapshot before closing if we are gracefully closing the store.
         // If not, just do nothing. WAL should handle the ir drop.
     }
 }
 
-impl KVStore {
+impl<W: WriteAheadLog<WalEntry>> KVStore<W> {
     pub fn get(&self, key: &str) -> Option<&String> {
         self.kv.get(key)
     }
 
-    pub fn put(&mut self, key: &str, value: &str) {
-        self.append_log(WalEntry::Set(key.into(), value.into()));
+    /// Sets `key` once the WAL acknowledged it. Nothing changes if it failed.
+    ///
+    /// Setting a key already there to a value no longer than the longest one written before
+    /// allocates nothing, provided the WAL does not either, as a [`SegmentedWal`] past its
+    /// first writes.
+    pub fn put(&mut self, key: &str, value: &str) -> WalResult<()> {
+        if let WalEntry::Set(k, v) = &mut self.set {
+            k.clear();
+            k.push_str(key);
+            v.clear();
+            v.push_str(value);
+        }
+        self.wal.append(&self.set)?;
         self.apply_put(key, value);
+        Ok(())
     }
 
-    pub fn put_batch(&mut self, batch: WriteBatch) {
-        self.append_log(WalEntry::Batch(batch.elements.clone()));
-        self.apply_batch(batch.elements);
-    }
-
-    fn append_log(&mut self, entry: WalEntry) {
-        let _ = self.wal.write(entry);
+    /// Sets every key of `batch` at once, see [`KVStore::put`].
+    pub fn put_batch(&mut self, batch: WriteBatch) -> WalResult<()> {
+        let entry = WalEntry::Batch(batch.elements);
+        self.wal.append(&entry)?;
+        if let WalEntry::Batch(elements) = entry {
+            self.apply_batch(elements);
+        }
+        Ok(())
     }
 
     fn apply_put(&mut self, key: &str, value: &str) {
-        self.kv.insert(key.into(), value.into());
+        set(&mut self.kv, key, value);
     }
 
     fn apply_batch(&mut self, kv: HashMap<String, String>) {
         self.kv.extend(kv);
     }
     /// Reads content from WAL and applies it to the state
-    fn apply_log(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        while let Some(wal_entry) = self.wal.read_next()? {
-            match wal_entry.zero_copy()? {
-                ArchivedWalEntry::Set(k, v) => self.apply_put(k, v),
-                ArchivedWalEntry::Batch(kv) => {
-                    // TODO: I should ideally use `self.apply_batch()` ?
-                    self.kv.extend(
-                        kv.iter()
-                            .map(|(k, v)| (k.as_str().to_owned(), v.as_str().to_owned())),
-                    );
+    ///
+    /// Entries are borrowed from the WAL, see [`WriteAheadLog::replay`]: only keys seen for the
+    /// first time and values longer than the one they replace are allocated.
+    fn apply_log(&mut self) -> WalResult<()> {
+        let kv = &mut self.kv;
+        self.wal.replay(|_, entry| match entry {
+            ArchivedWalEntry::Set(k, v) => set(kv, k, v),
+            ArchivedWalEntry::Batch(batch) => {
+                for (k, v) in batch.iter() {
+                    set(kv, k, v);
                 }
             }
+        })
+    }
+}
+
+/// Sets `key` in `kv`, reusing the string of the value it replaces.
+fn set(kv: &mut HashMap<String, String>, key: &str, value: &str) {
+    match kv.get_mut(key) {
+        Some(v) => {
+            v.clear();
+            v.push_str(value);
+        }
+        None => {
+            kv.insert(key.into(), value.into());
         }
-        Ok(())
     }
 }
 
@@ -103,125 +158,199 @@ impl WriteBatch {
 mod tests {
 
     use super::{KVStore, WriteBatch};
-    use tempfile::NamedTempFile;
+    use crate::wal::segmented_log::{SegmentedWal, WALConfig};
+    use crate::wal::{simple_wal, wal, MemoryStorage, WalEntry, WriteAheadLog};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// A WAL the suite runs against, reopened with every store.
+    trait Backend {
+        type Wal: WriteAheadLog<WalEntry>;
+
+        fn open(&self, truncate: bool) -> KVStore<Self::Wal>;
+    }
+
+    #[derive(Default)]
+    struct Segmented(MemoryStorage);
+
+    impl Backend for Segmented {
+        type Wal = SegmentedWal;
+
+        fn open(&self, truncate: bool) -> KVStore {
+            KVStore::from_config(WALConfig {
+                path: "/kv".into(),
+                truncate,
+                max_log_size: 1 << 20,
+                storage: Arc::new(self.0.clone()),
+                ..Default::default()
+            })
+            .expect("")
+        }
+    }
+
+    struct Simple(TempDir);
+
+    impl Backend for Simple {
+        type Wal = simple_wal::WriteAheadLog;
+
+        fn open(&self, truncate: bool) -> KVStore<Self::Wal> {
+            let wal = simple_wal::WriteAheadLog::open(simple_wal::WALConfig {
+                path: self.0.path().join("kv.log").to_str().expect("").into(),
+                truncate,
+                ..Default::default()
+            })
+            .expect("");
+            KVStore::with_wal(wal).expect("")
+        }
+    }
+
+    struct Indexed(TempDir);
 
-    fn get_store(file: &NamedTempFile) -> KVStore {
-        KVStore::open(file.path().to_str().expect("")).expect("")
+    impl Backend for Indexed {
+        type Wal = wal::WriteAheadLog;
+
+        fn open(&self, truncate: bool) -> KVStore<Self::Wal> {
+            let wal = wal::WriteAheadLog::open(wal::WALConfig {
+                path: self.0.path().join("kv.log").to_str().expect("").into(),
+                truncate,
+                ..Default::default()
+            })
+            .expect("");
+            KVStore::with_wal(wal).expect("")
+        }
+    }
+
+    /// Runs every test of the suite against each WAL, one module per WAL.
+    macro_rules! on_every_wal {
+        ($($test:ident),* $(,)?) => {
+            mod on_segmented_wal {
+                $(#[test]
+                fn $test() {
+                    super::$test(super::Segmented::default())
+                })*
+            }
+            mod on_simple_wal {
+                $(#[test]
+                fn $test() {
+                    super::$test(super::Simple(tempfile::TempDir::new().expect("")))
+                })*
+            }
+            mod on_wal {
+                $(#[test]
+                fn $test() {
+                    super::$test(super::Indexed(tempfile::TempDir::new().expect("")))
+                })*
+            }
+        };
     }
 
-    #[test]
-    fn empty_store_returns_none() {
-        let tmp = NamedTempFile::new().expect("");
-        let store = KVStore::new(true, tmp.path().to_str().expect("")).expect("");
+    on_every_wal!(
+        empty_store_returns_none,
+        put_and_get_roundtrip,
+        batch_put_extend_store,
+        wal_persists_between_sessions,
+        batch_and_single_put_mix_order,
+        overwrite_after_reopen,
+        batch_put_empty_is_noop,
+        overrides_existing_file_if_new,
+    );
+
+    fn empty_store_returns_none(backend: impl Backend) {
+        let store = backend.open(true);
         assert_eq!(store.get("missing"), None);
     }
 
-    #[test]
-    fn put_and_get_roundtrip() {
-        let tmp = NamedTempFile::new().expect("");
-        let mut store = get_store(&tmp);
+    fn put_and_get_roundtrip(backend: impl Backend) {
+        let mut store = backend.open(false);
 
-        store.put("foo", "bar");
+        store.put("foo", "bar").expect("");
         assert_eq!(store.get("foo"), Some(&"bar".to_string()));
 
         // Overwrite
-        store.put("foo", "baz");
+        store.put("foo", "baz").expect("");
         assert_eq!(store.get("foo"), Some(&"baz".to_string()));
     }
 
-    #[test]
-    fn batch_put_extend_store() {
-        let tmp = NamedTempFile::new().expect("");
-        let mut store = get_store(&tmp);
+    fn batch_put_extend_store(backend: impl Backend) {
+        let mut store = backend.open(false);
 
         let mut batch = WriteBatch::default();
         batch.put("k1", "v1");
         batch.put("k2", "v2");
         batch.put("k3", "v3");
 
-        store.put_batch(batch);
+        store.put_batch(batch).expect("");
 
         assert_eq!(store.get("k1"), Some(&"v1".to_string()));
         assert_eq!(store.get("k2"), Some(&"v2".to_string()));
         assert_eq!(store.get("k3"), Some(&"v3".to_string()));
     }
 
-    #[test]
-    fn wal_persists_between_sessions() {
-        let tmp = NamedTempFile::new().expect("");
+    fn wal_persists_between_sessions(backend: impl Backend) {
         {
-            let mut store = get_store(&tmp);
-            store.put("a", "1");
+            let mut store = backend.open(false);
+            store.put("a", "1").expect("");
 
             let mut batch = WriteBatch::default();
             batch.put("b", "2");
             batch.put("c", "3");
-            store.put_batch(batch);
+            store.put_batch(batch).expect("");
         }
 
-        let store = get_store(&tmp);
+        let store = backend.open(false);
 
         assert_eq!(store.get("a"), Some(&"1".to_string()));
         assert_eq!(store.get("b"), Some(&"2".to_string()));
         assert_eq!(store.get("c"), Some(&"3".to_string()));
     }
 
-    #[test]
-    fn batch_and_single_put_mix_order() {
-        let tmp = NamedTempFile::new().expect("");
-        let mut store = get_store(&tmp);
+    fn batch_and_single_put_mix_order(backend: impl Backend) {
+        let mut store = backend.open(false);
         // start with batch
         let mut batch = WriteBatch::default();
         batch.put("b1", "x");
         batch.put("b2", "y");
-        store.put_batch(batch);
+        store.put_batch(batch).expect("");
 
         // then single
-        store.put("single", "z");
+        store.put("single", "z").expect("");
 
         assert_eq!(store.get("b1"), Some(&"x".to_string()));
         assert_eq!(store.get("b2"), Some(&"y".to_string()));
         assert_eq!(store.get("single"), Some(&"z".to_string()));
     }
 
-    #[test]
-    fn overwrite_after_reopen() {
-        let tmp = NamedTempFile::new().expect("");
+    fn overwrite_after_reopen(backend: impl Backend) {
         {
-            let mut store = get_store(&tmp);
-            store.put("dup", "old");
+            let mut store = backend.open(false);
+            store.put("dup", "old").expect("");
         }
         {
-            let mut store = get_store(&tmp);
+            let mut store = backend.open(false);
             assert_eq!(store.get("dup"), Some(&"old".to_string()));
-            store.put("dup", "new");
+            store.put("dup", "new").expect("");
             assert_eq!(store.get("dup"), Some(&"new".to_string()));
         }
         {
-            let store = get_store(&tmp);
+            let store = backend.open(false);
             assert_eq!(store.get("dup"), Some(&"new".to_string()));
         }
     }
 
-    #[test]
-    fn batch_put_empty_is_noop() {
-        let tmp = NamedTempFile::new().expect("");
-        let mut store = get_store(&tmp);
+    fn batch_put_empty_is_noop(backend: impl Backend) {
+        let mut store = backend.open(false);
 
         let batch = WriteBatch::default(); // empty
-        store.put_batch(batch);
+        store.put_batch(batch).expect("");
 
         assert!(store.get("anything").is_none());
     }
-    #[test]
-    fn overrides_existing_file_if_new() {
-        let tmp = NamedTempFile::new().expect("");
+    fn overrides_existing_file_if_new(backend: impl Backend) {
         {
-            let mut store =
-                KVStore::new(true, tmp.path().to_str().expect("")).expect("err with store");
-            store.put("hello", "world");
+            let mut store = backend.open(true);
+            store.put("hello", "world").expect("");
         }
-        let store2 = KVStore::new(true, tmp.path().to_str().expect("")).expect("err with store");
+        let store2 = backend.open(true);
 
         assert!(store2.get("hello").is_none());
     }
@@ -1,5 +1,5 @@
 mod kv_store;
-mod simple_wal;
-mod wal;
+pub mod wal;
 
 pub use kv_store::{KVStore, WriteBatch};
+pub use wal::DurabilityPolicy;
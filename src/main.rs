@@ -3,16 +3,16 @@ use std::error::Error;
 use patterns_of_distributed_systems::{KVStore, WriteBatch};
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let path = "/tmp/kv-wal.log";
+    let path = "/tmp/kv-wal";
     {
         let mut kvstore = KVStore::new(true, path)?;
 
-        kvstore.put("Hello", "World");
+        kvstore.put("Hello", "World")?;
 
         let mut batch = WriteBatch::default();
         batch.put("b1", "plai");
         batch.put("b2", "cards");
-        kvstore.put_batch(batch);
+        kvstore.put_batch(batch)?;
     }
     let kv2 = KVStore::open(path)?;
 
@@ -0,0 +1,41 @@
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Thread running a task every interval until it is dropped.
+#[derive(Debug)]
+pub(crate) struct BackgroundTask {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundTask {
+    pub(crate) fn spawn(
+        name: &str,
+        every: Duration,
+        mut task: impl FnMut() + Send + 'static,
+    ) -> io::Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::Builder::new().name(name.into()).spawn(move || {
+            // Woken up early either when asked to stop or when the owner is gone
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                task();
+            }
+        })?;
+        Ok(Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
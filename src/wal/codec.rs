@@ -0,0 +1,160 @@
+use rkyv::rancor::Error;
+use rkyv::ser::allocator::Arena;
+use rkyv::util::AlignedVec;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::mem;
+
+use super::{deserialize, LogEntry, WalError, WalResult};
+
+/// Identifies a [`Codec`] in the header of every segment written with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecId {
+    Rkyv = 0,
+    Bincode = 1,
+    Json = 2,
+}
+
+impl CodecId {
+    pub(crate) fn from_u8(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CodecId::Rkyv),
+            1 => Some(CodecId::Bincode),
+            2 => Some(CodecId::Json),
+            _ => None,
+        }
+    }
+}
+
+/// How entries of type `T` become the blob of a frame, and back.
+///
+/// Picked per log through the type of the WAL, e.g. `SegmentedWal<RaftEntry, Json>`. The
+/// [`Codec::ID`] is recorded in every segment, so a log is never read with a codec other than
+/// the one it was written with.
+pub trait Codec<T> {
+    const ID: CodecId;
+
+    /// Encodes `entry` into `buf`, which comes in empty. `arena` is scratch memory kept from
+    /// one entry to the next, for codecs that need some: once `buf` and `arena` have grown to
+    /// fit the entries of a log, encoding them should not allocate.
+    fn encode(entry: &T, buf: &mut AlignedVec, arena: &mut Arena) -> WalResult<()>;
+
+    fn decode(blob: &[u8]) -> WalResult<T>;
+}
+
+/// rkyv archives, the default. The only codec whose entries can be read in place, see
+/// [`SegmentedWal::replay`](super::segmented_log::SegmentedWal::replay).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rkyv;
+
+impl<T: LogEntry> Codec<T> for Rkyv {
+    const ID: CodecId = CodecId::Rkyv;
+
+    /// Serialized right into `buf`, with `arena` as the scratch space rkyv would otherwise
+    /// allocate for every entry.
+    fn encode(entry: &T, buf: &mut AlignedVec, arena: &mut Arena) -> WalResult<()> {
+        let out = mem::take(buf);
+        *buf = rkyv::api::high::to_bytes_in_with_alloc::<_, _, Error>(entry, out, arena.acquire())?;
+        Ok(())
+    }
+
+    fn decode(blob: &[u8]) -> WalResult<T> {
+        deserialize(blob)
+    }
+}
+
+/// Compact binary through bincode's serde support: varint integers and no field names.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Bincode {
+    const ID: CodecId = CodecId::Bincode;
+
+    fn encode(entry: &T, buf: &mut AlignedVec, _: &mut Arena) -> WalResult<()> {
+        bincode::serde::encode_into_std_write(entry, buf, bincode::config::standard())
+            .map_err(|e| codec_error(CodecId::Bincode, e))?;
+        Ok(())
+    }
+
+    fn decode(blob: &[u8]) -> WalResult<T> {
+        let (entry, _) = bincode::serde::decode_from_slice(blob, bincode::config::standard())
+            .map_err(|e| codec_error(CodecId::Bincode, e))?;
+        Ok(entry)
+    }
+}
+
+/// One JSON document per entry, so a log can be read with `strings` or a text editor. The
+/// largest and slowest of the codecs, meant for debugging.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Json {
+    const ID: CodecId = CodecId::Json;
+
+    fn encode(entry: &T, buf: &mut AlignedVec, _: &mut Arena) -> WalResult<()> {
+        serde_json::to_writer(buf, entry).map_err(|e| codec_error(CodecId::Json, e))
+    }
+
+    fn decode(blob: &[u8]) -> WalResult<T> {
+        serde_json::from_slice(blob).map_err(|e| codec_error(CodecId::Json, e))
+    }
+}
+
+fn codec_error(codec: CodecId, e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> WalError {
+    WalError::Codec {
+        codec,
+        source: e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::WalEntry;
+    use std::collections::HashMap;
+
+    fn roundtrip<C: Codec<WalEntry>>() -> usize {
+        let batch = HashMap::from([("k1".into(), "v1".into()), ("k2".into(), "v2".into())]);
+        let entry = WalEntry::Batch(batch.clone());
+        let (mut buf, mut arena) = (AlignedVec::new(), Arena::new());
+        C::encode(&entry, &mut buf, &mut arena).expect("");
+        let WalEntry::Batch(decoded) = C::decode(&buf).expect("") else {
+            panic!("expected a Batch");
+        };
+        assert_eq!(decoded, batch);
+
+        // Encoded again in the same memory
+        let (first, at) = (buf.to_vec(), buf.as_ptr());
+        buf.clear();
+        C::encode(&entry, &mut buf, &mut arena).expect("");
+        assert_eq!((buf.as_slice(), buf.as_ptr()), (first.as_slice(), at));
+        buf.len()
+    }
+
+    #[test]
+    fn every_codec_roundtrips() {
+        let rkyv = roundtrip::<Rkyv>();
+        let bincode = roundtrip::<Bincode>();
+        let json = roundtrip::<Json>();
+        assert!(bincode < rkyv && bincode < json, "{rkyv} {bincode} {json}");
+    }
+
+    #[test]
+    fn json_is_readable() {
+        let mut buf = AlignedVec::new();
+        Json::encode(
+            &WalEntry::Set("k".into(), "v".into()),
+            &mut buf,
+            &mut Arena::new(),
+        )
+        .expect("");
+        assert_eq!(std::str::from_utf8(&buf).expect(""), r#"{"Set":["k","v"]}"#);
+        assert!(matches!(
+            <Json as Codec<WalEntry>>::decode(b"{\"Set\":"),
+            Err(WalError::Codec {
+                codec: CodecId::Json,
+                ..
+            })
+        ));
+    }
+}
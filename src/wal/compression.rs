@@ -0,0 +1,160 @@
+use rkyv::util::AlignedVec;
+
+use super::{WalError, WalResult};
+
+/// Whether blobs are compressed before they are written, see [`WALConfig`].
+///
+/// Reading does not depend on it: every frame says whether its own blob is compressed, so a
+/// log written with any setting, or a mix of them, reads back the same.
+///
+/// [`WALConfig`]: super::segmented_log::WALConfig
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 for blobs of at least `min_size` bytes. Smaller ones rarely shrink enough to be
+    /// worth decompressing. A blob that would not get smaller is stored as is.
+    Lz4 { min_size: usize },
+}
+
+/// Bit of the frame flags set when the blob is LZ4 compressed.
+pub(crate) const COMPRESSED: u8 = 1;
+
+/// Uncompressed length in front of LZ4 blocks, as `lz4_flex::compress_prepend_size` writes it.
+const SIZE_PREFIX_LEN: usize = 4;
+
+impl Compression {
+    /// Compresses `blob` into `out`, replacing its content, if this setting and the content of
+    /// `blob` allow it. True if `out` then holds a smaller blob, which [`decompress`] reads.
+    pub(crate) fn compress_into(&self, blob: &[u8], out: &mut AlignedVec<16>) -> bool {
+        let Compression::Lz4 { min_size } = *self else {
+            return false;
+        };
+        if blob.len() < min_size {
+            return false;
+        }
+        let max_len = lz4_flex::block::get_maximum_output_size(blob.len());
+        out.clear();
+        out.resize(SIZE_PREFIX_LEN + max_len, 0);
+        out[..SIZE_PREFIX_LEN].copy_from_slice(&(blob.len() as u32).to_le_bytes());
+        match lz4_flex::block::compress_into(blob, &mut out[SIZE_PREFIX_LEN..]) {
+            Ok(len) if SIZE_PREFIX_LEN + len < blob.len() => {
+                out.resize(SIZE_PREFIX_LEN + len, 0);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Decompresses a blob written with [`COMPRESSED`], found in the frame at `offset`.
+pub(crate) fn decompress(blob: &[u8], offset: u64) -> WalResult<Vec<u8>> {
+    let (len, compressed) = uncompressed_size(blob, offset)?;
+    let mut out = vec![0; len];
+    decompress_exact(compressed, &mut out, offset)?;
+    Ok(out)
+}
+
+/// Like [`decompress`], but into `out`, replacing its content. `out` stays aligned, so the
+/// entry can be read in place from there.
+pub(crate) fn decompress_into(blob: &[u8], offset: u64, out: &mut AlignedVec<16>) -> WalResult<()> {
+    let (len, compressed) = uncompressed_size(blob, offset)?;
+    out.clear();
+    out.resize(len, 0);
+    decompress_exact(compressed, out, offset)
+}
+
+fn uncompressed_size(blob: &[u8], offset: u64) -> WalResult<(usize, &[u8])> {
+    lz4_flex::block::uncompressed_size(blob).map_err(|_| corrupt(offset))
+}
+
+fn decompress_exact(compressed: &[u8], out: &mut [u8], offset: u64) -> WalResult<()> {
+    match lz4_flex::decompress_into(compressed, out) {
+        Ok(written) if written == out.len() => Ok(()),
+        _ => Err(corrupt(offset)),
+    }
+}
+
+fn corrupt(offset: u64) -> WalError {
+    WalError::CorruptFrame {
+        offset,
+        reason: "cannot decompress blob",
+    }
+}
+
+/// How much the frames written since the WAL was opened were compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub frames: u64,
+    /// Frames stored compressed, the others were too small or did not shrink.
+    pub compressed_frames: u64,
+    /// Blob bytes as encoded by the codec.
+    pub raw_bytes: u64,
+    /// Blob bytes once compressed, as written but for padding and the tag and salt of
+    /// encryption.
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    pub(crate) fn record(&mut self, raw_len: usize, stored_len: usize) {
+        self.frames += 1;
+        if stored_len != raw_len {
+            self.compressed_frames += 1;
+        }
+        self.raw_bytes += raw_len as u64;
+        self.stored_bytes += stored_len as u64;
+    }
+
+    /// Stored bytes per raw byte: 0.25 means blobs take a quarter of their encoded size. 1 when
+    /// nothing was written.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            return 1.0;
+        }
+        self.stored_bytes as f64 / self.raw_bytes as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_compresses_large_blobs_that_shrink() {
+        let lz4 = Compression::Lz4 { min_size: 64 };
+        let mut compressed = AlignedVec::new();
+        assert!(!lz4.compress_into(&[b'a'; 63], &mut compressed));
+        assert!(!Compression::None.compress_into(&[b'a'; 4096], &mut compressed));
+        // Nothing to gain from random bytes
+        let mut x = 0x9e3779b97f4a7c15u64;
+        let noise: Vec<u8> = (0..1024)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        assert!(!lz4.compress_into(&noise, &mut compressed));
+
+        let blob = b"value ".repeat(100);
+        assert!(
+            lz4.compress_into(&blob, &mut compressed),
+            "repetitive text shrinks"
+        );
+        assert!(compressed.len() < blob.len() / 4);
+        assert_eq!(
+            compressed.as_slice(),
+            lz4_flex::compress_prepend_size(&blob)
+        );
+        let mut out = AlignedVec::new();
+        decompress_into(&compressed, 0, &mut out).expect("");
+        assert_eq!(out.as_slice(), blob.as_slice());
+        assert_eq!(decompress(&compressed, 0).expect(""), blob);
+
+        assert!(matches!(
+            decompress_into(&compressed[..compressed.len() - 1], 7, &mut out),
+            Err(WalError::CorruptFrame { offset: 7, .. })
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::background::BackgroundTask;
+use super::storage::StorageFile;
+
+/// How hard the WAL tries to get an entry onto stable storage before `write` returns.
+///
+/// Each variant is a different point in the latency/durability trade-off. Only
+/// [`DurabilityPolicy::EveryWrite`] survives a power loss without losing acknowledged writes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DurabilityPolicy {
+    /// `sync_data` before every `write` returns.
+    EveryWrite,
+    /// A background thread syncs the log every interval. Up to one interval of writes can be
+    /// lost on power loss.
+    Interval(Duration),
+    /// `write` syncs once at least this many bytes were appended since the last sync.
+    Bytes(u64),
+    /// Never sync, the OS page cache decides when data reaches the disk. Survives a process
+    /// crash but not a power loss.
+    #[default]
+    OsManaged,
+}
+
+/// Applies a [`DurabilityPolicy`] to the file a WAL is appending to.
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    policy: DurabilityPolicy,
+    unsynced_bytes: u64,
+    background: Option<BackgroundSync>,
+}
+
+impl Syncer {
+    pub(crate) fn new(policy: DurabilityPolicy, file: &dyn StorageFile) -> io::Result<Self> {
+        let background = match policy {
+            DurabilityPolicy::Interval(every) => Some(BackgroundSync::spawn(every, file)?),
+            _ => None,
+        };
+        Ok(Self {
+            policy,
+            unsynced_bytes: 0,
+            background,
+        })
+    }
+
+    /// To be called once `bytes` were appended to `file`. Returns when the policy is honoured.
+    pub(crate) fn written(&mut self, file: &dyn StorageFile, bytes: u64) -> io::Result<()> {
+        self.appended(bytes);
+        self.commit(file)
+    }
+
+    /// Accounts for `bytes` appended without honouring the policy yet, see [`Syncer::commit`].
+    pub(crate) fn appended(&mut self, bytes: u64) {
+        self.unsynced_bytes += bytes;
+    }
+
+    /// Honours the policy for everything appended so far.
+    pub(crate) fn commit(&mut self, file: &dyn StorageFile) -> io::Result<()> {
+        match self.policy {
+            DurabilityPolicy::EveryWrite => self.sync(file),
+            DurabilityPolicy::Bytes(threshold) if self.unsynced_bytes >= threshold => {
+                self.sync(file)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Syncs anything written since the last sync unless the OS is in charge.
+    ///
+    /// Used when a file stops being written to: on close or when a segment is rolled.
+    pub(crate) fn sync_pending(&mut self, file: &dyn StorageFile) -> io::Result<()> {
+        match self.policy {
+            DurabilityPolicy::OsManaged => Ok(()),
+            _ if self.unsynced_bytes == 0 => Ok(()),
+            _ => self.sync(file),
+        }
+    }
+
+    /// Points the background thread, if any, to a new file. E.g. after rolling a segment.
+    pub(crate) fn switch_file(&mut self, file: &dyn StorageFile) -> io::Result<()> {
+        if let Some(background) = &self.background {
+            *background.file.lock().expect("WAL sync thread panicked") = file.try_clone()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs `file` now, whatever the policy.
+    pub(crate) fn sync(&mut self, file: &dyn StorageFile) -> io::Result<()> {
+        file.sync()?;
+        self.unsynced_bytes = 0;
+        Ok(())
+    }
+}
+
+/// Thread syncing a (cloned) file handle every interval until it is dropped.
+#[derive(Debug)]
+struct BackgroundSync {
+    file: Arc<Mutex<Box<dyn StorageFile>>>,
+    /// Never read, only held: dropping it stops the thread.
+    #[allow(dead_code)]
+    task: BackgroundTask,
+}
+
+impl BackgroundSync {
+    fn spawn(every: Duration, file: &dyn StorageFile) -> io::Result<Self> {
+        let file = Arc::new(Mutex::new(file.try_clone()?));
+        let thread_file = Arc::clone(&file);
+        let task = BackgroundTask::spawn("wal-sync", every, move || {
+            let file = thread_file.lock().expect("WAL writer panicked");
+            if let Err(e) = file.sync() {
+                eprintln!("WAL: background sync failed: {e}");
+            }
+        })?;
+        Ok(Self { file, task })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    #[test]
+    fn bytes_policy_syncs_once_threshold_is_reached() {
+        let file = tempfile().expect("");
+        let mut syncer = Syncer::new(DurabilityPolicy::Bytes(100), &file).expect("");
+
+        syncer.written(&file, 60).expect("");
+        assert_eq!(syncer.unsynced_bytes, 60);
+        syncer.written(&file, 60).expect("");
+        assert_eq!(syncer.unsynced_bytes, 0);
+    }
+
+    #[test]
+    fn os_managed_never_syncs() {
+        let file = tempfile().expect("");
+        let mut syncer = Syncer::new(DurabilityPolicy::OsManaged, &file).expect("");
+
+        syncer.written(&file, 1 << 20).expect("");
+        syncer.sync_pending(&file).expect("");
+        assert_eq!(syncer.unsynced_bytes, 1 << 20);
+    }
+
+    #[test]
+    fn interval_thread_stops_on_drop() {
+        let file = tempfile().expect("");
+        let syncer =
+            Syncer::new(DurabilityPolicy::Interval(Duration::from_secs(3600)), &file).expect("");
+        // Would hang for an hour if the thread was not woken up
+        drop(syncer);
+    }
+}
@@ -0,0 +1,217 @@
+use chacha20poly1305::aead::{rand_core::RngCore, AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+use rkyv::util::AlignedVec;
+use std::fmt::{self, Debug};
+use std::fs::{self, File};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use super::{WalError, WalResult};
+
+pub const KEY_LEN: usize = 32;
+
+/// A 256-bit XChaCha20-Poly1305 key.
+pub type Key = [u8; KEY_LEN];
+
+/// Bit of the frame flags set when the blob is encrypted.
+pub(crate) const ENCRYPTED: u8 = 2;
+
+const TAG_LEN: usize = 16;
+/// Random bytes completing the nonce of each frame, see [`Cipher::seal`].
+const SALT_LEN: usize = 8;
+
+/// Where the key encrypting the WAL comes from: a file, a KMS, an HSM...
+///
+/// Asked once when the WAL is opened.
+pub trait KeyProvider: Debug + Send + Sync {
+    fn key(&self) -> io::Result<Key>;
+}
+
+/// A key kept in a local file holding exactly [`KEY_LEN`] raw bytes.
+#[derive(Debug, Clone)]
+pub struct KeyFile {
+    path: PathBuf,
+}
+
+impl KeyFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Writes a new random key to `path`, readable by its owner only on unix, elsewhere as the
+    /// directory holding it allows. Never overwrites an existing file: losing a key means losing
+    /// every entry encrypted with it.
+    pub fn generate(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut key = Key::default();
+        OsRng.fill_bytes(&mut key);
+        let mut options = File::options();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&path)?;
+        file.write_all(&key)?;
+        file.sync_all()?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl KeyProvider for KeyFile {
+    fn key(&self) -> io::Result<Key> {
+        let bytes = fs::read(&self.path)?;
+        bytes.try_into().map_err(|bytes: Vec<u8>| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "key file {} holds {} bytes, expected {KEY_LEN}",
+                    self.path.display(),
+                    bytes.len()
+                ),
+            )
+        })
+    }
+}
+
+/// Authenticated encryption of frame blobs with XChaCha20-Poly1305.
+#[derive(Clone)]
+pub(crate) struct Cipher(XChaCha20Poly1305);
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the key
+        f.write_str("Cipher")
+    }
+}
+
+impl Cipher {
+    pub(crate) fn new(provider: &dyn KeyProvider) -> WalResult<Self> {
+        Ok(Self(XChaCha20Poly1305::new(&provider.key()?.into())))
+    }
+
+    /// Encrypts `blob`, that of the frame (`index`, `generation`) with `flags`, in place and
+    /// appends the tag and salt: `ciphertext | tag | salt`.
+    ///
+    /// The 24-byte nonce is the index, the generation and a random salt. The index and
+    /// generation make it unique across a log and tie the blob to its place in it. The salt
+    /// keeps it unique when a truncated index is written again with the same generation. The
+    /// tag also covers the header fields saying how to read the blob, see [`header_aad`].
+    pub(crate) fn seal(&self, index: u64, generation: u64, flags: u8, blob: &mut AlignedVec<16>) {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let aad = header_aad(index, generation, blob.len() + TAG_LEN + SALT_LEN, flags);
+        let tag = self
+            .0
+            .encrypt_in_place_detached(&nonce(index, generation, &salt), &aad, blob)
+            .expect("blob too large to encrypt");
+        blob.extend_from_slice(&tag);
+        blob.extend_from_slice(&salt);
+    }
+
+    /// Decrypts in place a blob written by [`Cipher::seal`] for the frame found at `offset`.
+    /// The plaintext ends up at the start of `blob`, its length is returned.
+    pub(crate) fn open(
+        &self,
+        index: u64,
+        generation: u64,
+        flags: u8,
+        blob: &mut [u8],
+        offset: u64,
+    ) -> WalResult<usize> {
+        let failed = WalError::AuthenticationFailed { offset };
+        let aad = header_aad(index, generation, blob.len(), flags);
+        let Some(len) = blob.len().checked_sub(TAG_LEN + SALT_LEN) else {
+            return Err(failed);
+        };
+        let (ciphertext, rest) = blob.split_at_mut(len);
+        let (tag, salt) = rest.split_at(TAG_LEN);
+        let salt: &[u8; SALT_LEN] = salt.try_into().expect("salt length");
+        self.0
+            .decrypt_in_place_detached(
+                &nonce(index, generation, salt),
+                &aad,
+                ciphertext,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| failed)?;
+        Ok(len)
+    }
+}
+
+/// Index, generation, blob length and flags as the frame header holds them. Authenticated
+/// along with the blob, so changing any of them fails [`Cipher::open`].
+fn header_aad(index: u64, generation: u64, blob_len: usize, flags: u8) -> [u8; 21] {
+    let mut aad = [0u8; 21];
+    aad[0..8].copy_from_slice(&index.to_le_bytes());
+    aad[8..16].copy_from_slice(&generation.to_le_bytes());
+    aad[16..20].copy_from_slice(&(blob_len as u32).to_le_bytes());
+    aad[20] = flags;
+    aad
+}
+
+fn nonce(index: u64, generation: u64, salt: &[u8; SALT_LEN]) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[0..8].copy_from_slice(&index.to_le_bytes());
+    nonce[8..16].copy_from_slice(&generation.to_le_bytes());
+    nonce[16..24].copy_from_slice(salt);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn blobs_only_open_with_their_key_and_place() {
+        let dir = TempDir::new().expect("");
+        let key = KeyFile::generate(dir.path().join("wal.key")).expect("");
+        assert!(KeyFile::generate(key.path()).is_err());
+        let cipher = Cipher::new(&key).expect("");
+
+        let mut buf = AlignedVec::new();
+        buf.extend_from_slice(b"secret value");
+        cipher.seal(7, 2, ENCRYPTED, &mut buf);
+        assert!(!buf.windows(6).any(|w| w == b"secret"));
+
+        let sealed = buf.to_vec();
+        let mut blob = sealed.clone();
+        let len = cipher.open(7, 2, ENCRYPTED, &mut blob, 0).expect("");
+        assert_eq!(&blob[..len], b"secret value");
+
+        // Moved to another index or generation, flagged otherwise, tampered with, or read with
+        // another key
+        let other =
+            Cipher::new(&KeyFile::generate(dir.path().join("other.key")).expect("")).expect("");
+        let mut flipped = sealed.clone();
+        flipped[0] ^= 1;
+        let compressed = ENCRYPTED | crate::wal::compression::COMPRESSED;
+        for (cipher, index, generation, flags, mut blob) in [
+            (&cipher, 8, 2, ENCRYPTED, sealed.clone()),
+            (&cipher, 7, 3, ENCRYPTED, sealed.clone()),
+            (&cipher, 7, 2, compressed, sealed.clone()),
+            (&cipher, 7, 2, ENCRYPTED, flipped),
+            (&cipher, 7, 2, ENCRYPTED, sealed[..10].to_vec()),
+            (&other, 7, 2, ENCRYPTED, sealed.clone()),
+        ] {
+            assert!(matches!(
+                cipher.open(index, generation, flags, &mut blob, 42),
+                Err(WalError::AuthenticationFailed { offset: 42 })
+            ));
+        }
+    }
+
+    #[test]
+    fn key_files_must_hold_a_whole_key() {
+        let dir = TempDir::new().expect("");
+        let path = dir.path().join("short.key");
+        fs::write(&path, [1u8; 16]).expect("");
+        let err = KeyFile::new(&path).key().expect_err("");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
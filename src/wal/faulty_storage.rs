@@ -0,0 +1,749 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use super::storage::{moved, FileBytes, Storage, StorageFile};
+
+/// An I/O error [`FaultyStorage`] can be told to fail with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoFault {
+    /// `EIO`, e.g. a dying disk.
+    Eio,
+    /// `ENOSPC`, the disk is full.
+    Enospc,
+}
+
+impl IoFault {
+    fn error(self) -> io::Error {
+        // Same numbers on Linux and macOS
+        match self {
+            IoFault::Eio => io::Error::from_raw_os_error(5),
+            IoFault::Enospc => io::Error::from_raw_os_error(28),
+        }
+    }
+}
+
+/// What a simulated [`FaultyStorage::crash`] does to the writes that were not synced yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crash {
+    /// They are all lost.
+    DropUnsynced,
+    /// The oldest ones up to a random point survive, the last of them possibly cut short.
+    TornWrite,
+    /// Any of them may survive, regardless of the order they were written in. Whatever a lost
+    /// write would have filled before a surviving one reads as zeros.
+    Reorder,
+    /// Like [`Crash::TornWrite`], then a bit of what survived is flipped.
+    BitFlip,
+}
+
+impl Crash {
+    pub const ALL: [Crash; 4] = [
+        Crash::DropUnsynced,
+        Crash::TornWrite,
+        Crash::Reorder,
+        Crash::BitFlip,
+    ];
+}
+
+/// Wraps another [`Storage`] and simulates what disks and file systems do to a program that
+/// stops without warning.
+///
+/// Writes go through to the inner storage right away, which plays the page cache. On the
+/// side it tracks what would survive a power loss:
+/// - bytes written to a file, until the file is synced,
+/// - files created, renamed or removed in a directory, until the directory is synced,
+/// - directories renamed, until the directory they were renamed into is synced.
+///
+/// [`FaultyStorage::crash`] rewrites the inner storage to what survived. Creating directories
+/// and shrinking files are durable right away. The randomness comes from a seed, so a failing
+/// run can be replayed.
+#[derive(Debug, Clone)]
+pub struct FaultyStorage {
+    inner: Arc<dyn Storage>,
+    state: Arc<Mutex<FaultState>>,
+    /// The crash this instance was created after. Once another crash happens, it and every
+    /// file it opened fail with `EIO`: the program using them is gone.
+    epoch: u64,
+}
+
+#[derive(Debug)]
+struct FaultState {
+    epoch: u64,
+    rng: Rng,
+    /// Every operation that changes something gets the next number, see
+    /// [`FaultyStorage::fail_after`].
+    ops: u64,
+    faults: HashMap<u64, IoFault>,
+    files: Vec<TrackedFile>,
+    /// Files as the program sees them.
+    live: HashMap<PathBuf, usize>,
+    /// Files a crash leaves behind, as of the last sync of their directory.
+    durable: HashMap<PathBuf, usize>,
+    /// Directories renamed, from and to, until the directory holding `to` is synced.
+    renamed_dirs: Vec<(PathBuf, PathBuf)>,
+}
+
+/// A file of the inner storage. The handle keeps its content reachable even once it has been
+/// removed or renamed, which a crash may undo.
+#[derive(Debug)]
+struct TrackedFile {
+    file: Box<dyn StorageFile>,
+    synced_len: u64,
+    /// Written since the last sync, oldest first: offset and bytes.
+    unsynced: Vec<(u64, Vec<u8>)>,
+    /// Synced bytes written over since the last sync, as they were before: offset and bytes,
+    /// oldest first.
+    overwritten: Vec<(u64, Vec<u8>)>,
+}
+
+impl FaultyStorage {
+    pub fn new(inner: impl Storage + 'static, seed: u64) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            state: Arc::new(Mutex::new(FaultState {
+                epoch: 0,
+                rng: Rng(seed),
+                ops: 0,
+                faults: HashMap::new(),
+                files: vec![],
+                live: HashMap::new(),
+                durable: HashMap::new(),
+                renamed_dirs: vec![],
+            })),
+            epoch: 0,
+        }
+    }
+
+    /// Makes the operation that changes something after the next `ops` ones fail with `fault`,
+    /// without any effect. Reads never fail.
+    pub fn fail_after(&self, ops: u64, fault: IoFault) {
+        let mut state = self.lock();
+        let at = state.ops + ops;
+        state.faults.insert(at, fault);
+    }
+
+    /// Flips bit `bit` of the file at `path` in place, as if the disk rotted underneath.
+    pub fn flip_bit(&self, path: &Path, bit: u64) -> io::Result<()> {
+        let _alive = self.alive()?;
+        let file = self.inner.open(path, false)?;
+        let mut bytes = file.map()?.to_vec();
+        let byte = bytes.get_mut((bit / 8) as usize).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "bit past the end of the file")
+        })?;
+        *byte ^= 1 << (bit % 8);
+        file.truncate(0)?;
+        file.append(&bytes)?;
+        file.sync()
+    }
+
+    /// Simulates a power loss, and returns the storage as the program finds it when it starts
+    /// again.
+    ///
+    /// This instance, its clones and all the files they opened are dead from now on.
+    pub fn crash(&self, crash: Crash) -> io::Result<FaultyStorage> {
+        let mut state = self.lock();
+        state.epoch += 1;
+
+        let durable: Vec<(PathBuf, usize)> = state
+            .durable
+            .iter()
+            .map(|(p, &id)| (p.clone(), id))
+            .collect();
+        let mut survivors = Vec::with_capacity(durable.len());
+        for (path, id) in durable {
+            survivors.push((path, state.surviving(id, crash)?));
+        }
+        for path in state.live.keys() {
+            self.inner.remove(path)?;
+        }
+        for (path, content) in survivors {
+            if let Some(dir) = path.parent() {
+                // Where a rename that did not survive took it from
+                self.inner.create_dir(dir)?;
+            }
+            let file = self.inner.open(&path, true)?;
+            file.truncate(0)?;
+            file.append(&content)?;
+            file.sync()?;
+            if let Some(dir) = path.parent() {
+                self.inner.sync_dir(dir)?;
+            }
+        }
+
+        // Everything left is durable, files get tracked again as they are opened
+        state.files.clear();
+        state.live.clear();
+        state.durable.clear();
+        state.renamed_dirs.clear();
+        state.faults.clear();
+        Ok(Self {
+            inner: Arc::clone(&self.inner),
+            state: Arc::clone(&self.state),
+            epoch: state.epoch,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FaultState> {
+        self.state.lock().expect("faulty storage poisoned")
+    }
+
+    /// The state, unless a crash happened since this instance was created.
+    fn alive(&self) -> io::Result<MutexGuard<'_, FaultState>> {
+        let state = self.lock();
+        match state.epoch == self.epoch {
+            true => Ok(state),
+            false => Err(IoFault::Eio.error()),
+        }
+    }
+
+    /// The tracked file at `path`. One that existed before we wrapped the storage or before
+    /// the last crash is durable.
+    fn tracked(&self, state: &mut FaultState, path: &Path) -> io::Result<Option<usize>> {
+        if let Some(&id) = state.live.get(path) {
+            return Ok(Some(id));
+        }
+        if !self.inner.exists(path) {
+            return Ok(None);
+        }
+        let file = self.inner.open(path, false)?;
+        let id = state.track(file)?;
+        state.live.insert(path.into(), id);
+        state.durable.insert(path.into(), id);
+        Ok(Some(id))
+    }
+
+    /// Renames the directory `from`, with the files in it, to `to`.
+    fn rename_dir(&self, state: &mut FaultState, from: &Path, to: &Path) -> io::Result<()> {
+        for path in self.inner.list(from)? {
+            self.tracked(state, &path)?;
+        }
+        state.op()?;
+        self.inner.rename(from, to)?;
+        state.live = mem::take(&mut state.live)
+            .into_iter()
+            .map(|(path, id)| (moved(path, from, to), id))
+            .collect();
+        state.renamed_dirs.push((from.into(), to.into()));
+        Ok(())
+    }
+
+    fn handle(&self, id: usize) -> Box<dyn StorageFile> {
+        Box::new(FaultyFile {
+            storage: self.clone(),
+            id,
+        })
+    }
+}
+
+impl FaultState {
+    /// Counts an operation that changes something, failing it if a fault is due.
+    fn op(&mut self) -> io::Result<()> {
+        let op = self.ops;
+        self.ops += 1;
+        match self.faults.remove(&op) {
+            Some(fault) => Err(fault.error()),
+            None => Ok(()),
+        }
+    }
+
+    fn track(&mut self, file: Box<dyn StorageFile>) -> io::Result<usize> {
+        self.files.push(TrackedFile {
+            synced_len: file.len()?,
+            file,
+            unsynced: vec![],
+            overwritten: vec![],
+        });
+        Ok(self.files.len() - 1)
+    }
+
+    /// What is left of file `id` after `crash`.
+    fn surviving(&mut self, id: usize, crash: Crash) -> io::Result<Vec<u8>> {
+        let file = &self.files[id];
+        let mut content = file.file.map()?[..file.synced_len as usize].to_vec();
+        for (offset, before) in file.overwritten.iter().rev() {
+            let start = *offset as usize;
+            content[start..start + before.len()].copy_from_slice(before);
+        }
+        let mut persist = |(offset, bytes): &(u64, Vec<u8>), len: usize| {
+            let (start, end) = (*offset as usize, *offset as usize + len);
+            if content.len() < end {
+                content.resize(end, 0);
+            }
+            content[start..end].copy_from_slice(&bytes[..len]);
+        };
+
+        let writes = &file.unsynced;
+        match crash {
+            Crash::DropUnsynced => {}
+            Crash::TornWrite | Crash::BitFlip => {
+                let kept = self.rng.below(writes.len() as u64 + 1) as usize;
+                if let Some((last, whole)) = writes[..kept].split_last() {
+                    whole.iter().for_each(|w| persist(w, w.1.len()));
+                    let torn = self.rng.below(last.1.len() as u64 + 1);
+                    persist(last, torn as usize);
+                }
+            }
+            Crash::Reorder => {
+                for w in writes {
+                    if self.rng.below(2) == 0 {
+                        persist(w, w.1.len());
+                    }
+                }
+            }
+        }
+
+        let synced = file.synced_len;
+        if crash == Crash::BitFlip && content.len() as u64 > synced {
+            let bit = synced * 8 + self.rng.below((content.len() as u64 - synced) * 8);
+            content[(bit / 8) as usize] ^= 1 << (bit % 8);
+        }
+        Ok(content)
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        let mut state = self.alive()?;
+        if let Some(id) = self.tracked(&mut state, path)? {
+            return Ok(self.handle(id));
+        }
+        if !create {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ));
+        }
+        state.op()?;
+        let file = self.inner.open(path, true)?;
+        let id = state.track(file)?;
+        state.live.insert(path.into(), id);
+        Ok(self.handle(id))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let _alive = self.alive()?;
+        self.inner.list(dir)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.alive()?;
+        self.tracked(&mut state, path)?;
+        state.op()?;
+        self.inner.remove(path)?;
+        state.live.remove(path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.alive()?;
+        if !state.live.contains_key(from) && self.inner.list(from).is_ok() {
+            return self.rename_dir(&mut state, from, to);
+        }
+        self.tracked(&mut state, from)?;
+        self.tracked(&mut state, to)?;
+        state.op()?;
+        self.inner.rename(from, to)?;
+        if let Some(id) = state.live.remove(from) {
+            state.live.insert(to.into(), id);
+        }
+        Ok(())
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.alive()?;
+        state.op()?;
+        self.inner.create_dir(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.alive()?;
+        state.op()?;
+        self.inner.sync_dir(dir)?;
+        let state = &mut *state;
+        let (renamed, pending) = mem::take(&mut state.renamed_dirs)
+            .into_iter()
+            .partition(|(_, to)| to.parent() == Some(dir));
+        state.renamed_dirs = pending;
+        for (from, to) in renamed {
+            state.durable = mem::take(&mut state.durable)
+                .into_iter()
+                .map(|(path, id)| (moved(path, &from, &to), id))
+                .collect();
+        }
+        state.durable.retain(|path, _| path.parent() != Some(dir));
+        for (path, &id) in &state.live {
+            if path.parent() == Some(dir) {
+                state.durable.insert(path.clone(), id);
+            }
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.alive().is_ok() && self.inner.exists(path)
+    }
+}
+
+/// A file opened through a [`FaultyStorage`].
+#[derive(Debug)]
+struct FaultyFile {
+    storage: FaultyStorage,
+    id: usize,
+}
+
+impl FaultyFile {
+    /// Runs `f` on the tracked file, as an operation that changes something if `changes`.
+    fn with<T>(
+        &self,
+        changes: bool,
+        f: impl FnOnce(&mut TrackedFile) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut state = self.storage.alive()?;
+        if changes {
+            state.op()?;
+        }
+        f(&mut state.files[self.id])
+    }
+}
+
+impl StorageFile for FaultyFile {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.with(true, |tracked| {
+            let start = tracked.file.len()?;
+            tracked.file.append(buf)?;
+            tracked.unsynced.push((start, buf.to_vec()));
+            Ok(())
+        })
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.with(true, |tracked| {
+            let synced_end = tracked.synced_len.min(offset + buf.len() as u64);
+            if offset < synced_end {
+                let mut before = vec![0; (synced_end - offset) as usize];
+                tracked.file.read_at(&mut before, offset)?;
+                tracked.overwritten.push((offset, before));
+            }
+            tracked.file.write_at(buf, offset)?;
+            tracked.unsynced.push((offset, buf.to_vec()));
+            Ok(())
+        })
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.with(false, |tracked| tracked.file.read_at(buf, offset))
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.with(false, |tracked| tracked.file.len())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.with(true, |tracked| {
+            tracked.file.sync()?;
+            tracked.synced_len = tracked.file.len()?;
+            tracked.unsynced.clear();
+            tracked.overwritten.clear();
+            Ok(())
+        })
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.with(true, |tracked| {
+            let old_len = tracked.file.len()?;
+            tracked.file.truncate(len)?;
+            tracked.synced_len = tracked.synced_len.min(len);
+            for (offset, bytes) in tracked
+                .unsynced
+                .iter_mut()
+                .chain(tracked.overwritten.iter_mut())
+            {
+                bytes.truncate(len.saturating_sub(*offset) as usize);
+            }
+            tracked.unsynced.retain(|(_, bytes)| !bytes.is_empty());
+            tracked.overwritten.retain(|(_, bytes)| !bytes.is_empty());
+            if len > old_len {
+                // The zeros it grew by are not durable either
+                tracked
+                    .unsynced
+                    .push((old_len, vec![0; (len - old_len) as usize]));
+            }
+            Ok(())
+        })
+    }
+
+    fn modified(&self) -> io::Result<SystemTime> {
+        self.with(false, |tracked| tracked.file.modified())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn StorageFile>> {
+        drop(self.storage.alive()?);
+        Ok(self.storage.handle(self.id))
+    }
+
+    fn map(&self) -> io::Result<FileBytes> {
+        self.with(false, |tracked| tracked.file.map())
+    }
+}
+
+/// splitmix64: plenty for picking faults, and reproducible from a seed.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`, 0 if `n` is 0.
+    fn below(&mut self, n: u64) -> u64 {
+        match n {
+            0 => 0,
+            n => self.next() % n,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::{KVStore, WriteBatch};
+    use crate::wal::segmented_log::WALConfig;
+    use crate::wal::{DurabilityPolicy, MemoryStorage};
+
+    fn storage() -> (FaultyStorage, &'static Path) {
+        let storage = FaultyStorage::new(MemoryStorage::default(), 7);
+        let dir = Path::new("/wal");
+        storage.create_dir(dir).expect("");
+        (storage, dir)
+    }
+
+    fn content(storage: &FaultyStorage, path: &Path) -> Vec<u8> {
+        storage
+            .open(path, false)
+            .expect("")
+            .map()
+            .expect("")
+            .to_vec()
+    }
+
+    #[test]
+    fn only_synced_writes_to_synced_names_survive() {
+        let (storage, dir) = storage();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let file = storage.open(&a, true).expect("");
+        file.append(b"synced").expect("");
+        file.sync().expect("");
+        file.append(b" lost").expect("");
+        storage.sync_dir(dir).expect("");
+        // Neither the file nor its content is durable without a sync of the directory
+        storage.open(&b, true).expect("").sync().expect("");
+        storage.rename(&a, &dir.join("c")).expect("");
+
+        let after = storage.crash(Crash::DropUnsynced).expect("");
+        assert_eq!(content(&after, &a), b"synced");
+        assert!(!after.exists(&b));
+        assert!(!after.exists(&dir.join("c")));
+        // Whatever was running before the crash is gone
+        assert!(file.append(b"zombie").is_err());
+        assert!(storage.open(&a, false).is_err());
+    }
+
+    #[test]
+    fn renamed_directories_survive_once_their_parent_is_synced() {
+        let (storage, dir) = storage();
+        let file = storage.open(&dir.join("a"), true).expect("");
+        file.append(b"kept").expect("");
+        file.sync().expect("");
+        storage.sync_dir(dir).expect("");
+        let moved = Path::new("/moved");
+        storage.rename(dir, moved).expect("");
+
+        let after = storage.crash(Crash::DropUnsynced).expect("");
+        assert_eq!(content(&after, &dir.join("a")), b"kept");
+        assert!(!after.exists(&moved.join("a")));
+
+        after.rename(dir, moved).expect("");
+        after.sync_dir(Path::new("/")).expect("");
+        let after = after.crash(Crash::DropUnsynced).expect("");
+        assert_eq!(content(&after, &moved.join("a")), b"kept");
+        assert!(!after.exists(&dir.join("a")));
+    }
+
+    #[test]
+    fn unsynced_writes_may_be_torn_or_reordered() {
+        let mut torn = 0;
+        let mut holes = 0;
+        for seed in 0..64 {
+            let storage = FaultyStorage::new(MemoryStorage::default(), seed);
+            let dir = Path::new("/wal");
+            storage.create_dir(dir).expect("");
+            let path = dir.join("a");
+            let file = storage.open(&path, true).expect("");
+            storage.sync_dir(dir).expect("");
+            for w in [b"aaaa", b"bbbb", b"cccc"] {
+                file.append(w).expect("");
+            }
+
+            let after = storage.crash(Crash::TornWrite).expect("");
+            let kept = content(&after, &path);
+            assert!(b"aaaabbbbcccc".starts_with(&kept), "{kept:?}");
+            torn += !kept.len().is_multiple_of(4) as u32;
+
+            let file = after.open(&path, false).expect("");
+            file.append(b"dddd").expect("");
+            file.append(b"eeee").expect("");
+            let end = kept.len();
+            let after = after.crash(Crash::Reorder).expect("");
+            let kept = content(&after, &path);
+            holes += (kept.len() > end + 4 && kept[end..end + 4] == [0; 4]) as u32;
+        }
+        assert!(torn > 0 && holes > 0, "{torn} torn, {holes} holes");
+    }
+
+    #[test]
+    fn synced_bytes_written_over_come_back_unless_synced_again() {
+        let (storage, dir) = storage();
+        let path = dir.join("a");
+        let file = storage.open(&path, true).expect("");
+        storage.sync_dir(dir).expect("");
+        file.allocate(8).expect("");
+        file.sync().expect("");
+        file.write_at(b"abcd", 2).expect("");
+        file.write_at(b"xy", 4).expect("");
+
+        let after = storage.crash(Crash::DropUnsynced).expect("");
+        assert_eq!(content(&after, &path), [0; 8]);
+        let file = after.open(&path, false).expect("");
+        file.write_at(b"abcd", 6).expect("");
+        file.sync().expect("");
+        let after = after.crash(Crash::DropUnsynced).expect("");
+        assert_eq!(content(&after, &path), b"\0\0\0\0\0\0abcd");
+    }
+
+    #[test]
+    fn injected_errors_have_no_effect() {
+        let (storage, dir) = storage();
+        let file = storage.open(&dir.join("a"), true).expect("");
+        storage.fail_after(1, IoFault::Enospc);
+        file.append(b"ok").expect("");
+        let err = file.append(b"full").expect_err("");
+        assert_eq!(err.raw_os_error(), Some(28));
+        file.append(b"!").expect("");
+        assert_eq!(content(&storage, &dir.join("a")), b"ok!");
+
+        storage.fail_after(0, IoFault::Eio);
+        assert_eq!(file.sync().expect_err("").raw_os_error(), Some(5));
+    }
+
+    #[test]
+    fn flipped_bits_are_durable() {
+        let (storage, dir) = storage();
+        let path = dir.join("a");
+        let file = storage.open(&path, true).expect("");
+        file.append(&[0, 0]).expect("");
+        file.sync().expect("");
+        storage.sync_dir(dir).expect("");
+        storage.flip_bit(&path, 9).expect("");
+
+        let after = storage.crash(Crash::DropUnsynced).expect("");
+        assert_eq!(content(&after, &path), [0, 2]);
+    }
+
+    /// Values every key may hold after a crash: the last acknowledged one, or any written
+    /// after it. `None` is only fine for keys never acknowledged.
+    #[derive(Debug, Default)]
+    struct Expected(HashMap<String, Vec<(String, bool)>>);
+
+    impl Expected {
+        fn written(&mut self, key: &str, value: &str, acknowledged: bool) {
+            let writes = self.0.entry(key.into()).or_default();
+            if acknowledged {
+                writes.clear();
+            }
+            writes.push((value.into(), acknowledged));
+        }
+
+        /// Checks what `store` recovered, which is durable from now on.
+        fn check(&mut self, store: &KVStore, seed: u64) {
+            for (key, writes) in &mut self.0 {
+                let found = store.get(key);
+                let acknowledged = writes.first().is_some_and(|(_, ack)| *ack);
+                let possible = found.is_none() && !acknowledged
+                    || writes.iter().any(|(v, _)| Some(v) == found);
+                assert!(
+                    possible,
+                    "seed {seed}: {key} is {found:?} after the crash, written: {writes:?}"
+                );
+                *writes = found.map(|v| (v.clone(), true)).into_iter().collect();
+            }
+        }
+    }
+
+    fn open(storage: &FaultyStorage, preallocate: bool) -> KVStore {
+        KVStore::from_config(WALConfig {
+            path: "/kv".into(),
+            max_log_size: 1024,
+            preallocate,
+            durability: DurabilityPolicy::EveryWrite,
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        })
+        .expect("reopen after crash")
+    }
+
+    fn simulate(seed: u64) {
+        let mut rng = Rng(seed);
+        let mut storage = FaultyStorage::new(MemoryStorage::default(), seed);
+        let mut expected = Expected::default();
+        // Half of the runs write into zeroed space rather than at the end of the segments
+        let preallocate = seed % 2 == 1;
+        for round in 0..8 {
+            let mut store = open(&storage, preallocate);
+            expected.check(&store, seed);
+
+            if rng.below(2) == 0 {
+                let fault = [IoFault::Eio, IoFault::Enospc][rng.below(2) as usize];
+                storage.fail_after(rng.below(100), fault);
+            }
+            for op in 0..rng.below(60) {
+                let value = format!("{round}.{op}");
+                let keys: Vec<String> = (0..=rng.below(3))
+                    .map(|_| format!("k{}", rng.below(12)))
+                    .collect();
+                let written = match keys.as_slice() {
+                    [key] => store.put(key, &value),
+                    keys => {
+                        let mut batch = WriteBatch::default();
+                        keys.iter().for_each(|key| batch.put(key, &value));
+                        store.put_batch(batch)
+                    }
+                };
+                for key in &keys {
+                    expected.written(key, &value, written.is_ok());
+                }
+            }
+
+            let crash = Crash::ALL[rng.below(4) as usize];
+            storage = storage.crash(crash).expect("");
+            drop(store);
+        }
+        let store = open(&storage, preallocate);
+        expected.check(&store, seed);
+    }
+
+    #[test]
+    fn acknowledged_writes_survive_crashes() {
+        for seed in 0..200 {
+            simulate(seed);
+        }
+    }
+}
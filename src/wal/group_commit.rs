@@ -0,0 +1,149 @@
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use super::segmented_log::SegmentedWal;
+use super::{Codec, WalEntry, WalError, WalResult};
+
+/// An entry waiting to be appended and where to send its log index once it is durable.
+type Request<T> = (T, Sender<WalResult<u64>>);
+
+/// Group commit writer in front of a [`SegmentedWal`].
+///
+/// Concurrent callers enqueue their entries and block. A single leader thread takes everything
+/// queued so far, appends the whole group and then honours the durability policy once for all
+/// of it. Pair it with [`DurabilityPolicy::EveryWrite`](super::DurabilityPolicy::EveryWrite) so
+/// a group shares one fsync instead of paying one per entry. While the leader is syncing, new
+/// callers pile up and become the next group.
+#[derive(Debug)]
+pub struct GroupCommitWal<T = WalEntry> {
+    requests: Option<Sender<Request<T>>>,
+    leader: Option<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> GroupCommitWal<T> {
+    pub fn new<C: Codec<T> + 'static>(wal: SegmentedWal<T, C>) -> WalResult<Self> {
+        let (requests, queue) = mpsc::channel();
+        let leader = thread::Builder::new()
+            .name("wal-group-commit".into())
+            .spawn(move || Self::lead(wal, queue))?;
+        Ok(Self {
+            requests: Some(requests),
+            leader: Some(leader),
+        })
+    }
+
+    /// Appends `cmd` and returns its log index once the group it landed in is committed.
+    pub fn write(&self, cmd: T) -> WalResult<u64> {
+        let (reply, index) = mpsc::channel();
+        self.requests
+            .as_ref()
+            .ok_or(WalError::Closed)?
+            .send((cmd, reply))
+            .map_err(|_| WalError::Closed)?;
+        index.recv().map_err(|_| WalError::Closed)?
+    }
+
+    fn lead<C: Codec<T>>(mut wal: SegmentedWal<T, C>, queue: Receiver<Request<T>>) {
+        while let Ok(first) = queue.recv() {
+            let mut group = vec![first];
+            group.extend(queue.try_iter());
+
+            let mut appended = Vec::with_capacity(group.len());
+            for (cmd, reply) in group {
+                match wal.append(&cmd) {
+                    Ok(index) => appended.push((index, reply)),
+                    // The caller may have given up waiting, nothing to do then.
+                    Err(e) => drop(reply.send(Err(e))),
+                }
+            }
+
+            match wal.commit() {
+                Ok(()) => {
+                    for (index, reply) in appended {
+                        let _ = reply.send(Ok(index));
+                    }
+                }
+                Err(e) => {
+                    for (_, reply) in appended {
+                        let _ = reply.send(Err(shared_error(&e)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A failed commit fails the whole group, but `WalError` can't be cloned.
+fn shared_error(e: &WalError) -> WalError {
+    match e {
+        WalError::IO(io) => io::Error::new(io.kind(), io.to_string()).into(),
+        other => io::Error::other(other.to_string()).into(),
+    }
+}
+
+impl<T> Drop for GroupCommitWal<T> {
+    /// Lets the leader drain the queue, then closes the underlying WAL.
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(leader) = self.leader.take() {
+            if leader.join().is_err() {
+                eprintln!("WAL: group commit leader panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::segmented_log::WALConfig;
+    use crate::wal::DurabilityPolicy;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn cfg(dir: &TempDir) -> WALConfig {
+        WALConfig {
+            path: dir.path().join("wal").to_str().expect("").into(),
+            max_log_size: u64::MAX,
+            durability: DurabilityPolicy::EveryWrite,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn concurrent_writers_get_unique_contiguous_indexes() {
+        let dir = TempDir::new().expect("");
+        let wal =
+            Arc::new(GroupCommitWal::new(SegmentedWal::open(cfg(&dir)).expect("")).expect(""));
+
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let wal = Arc::clone(&wal);
+                thread::spawn(move || {
+                    (0..25)
+                        .map(|i| {
+                            wal.write(WalEntry::Set(format!("t{t}k{i}"), "v".into()))
+                                .expect("write")
+                        })
+                        .collect::<Vec<u64>>()
+                })
+            })
+            .collect();
+        let mut indexes: Vec<u64> = writers
+            .into_iter()
+            .flat_map(|w| w.join().expect(""))
+            .collect();
+        indexes.sort();
+        assert_eq!(indexes, (1..=200).collect::<Vec<u64>>());
+
+        drop(wal);
+        let mut reopened = SegmentedWal::<WalEntry>::open(cfg(&dir)).expect("");
+        let indexes: Vec<u64> = reopened
+            .read_range(..)
+            .expect("")
+            .map(|frame| frame.expect("").index)
+            .collect();
+        assert_eq!(indexes, (1..=200).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,345 @@
+use rkyv::rancor::Error;
+use std::path::{Path, PathBuf};
+
+use super::encryption::Cipher;
+use super::manifest::Manifest;
+use super::mapped::MappedSegment;
+use super::segmented_log::{WalSegment, FRAMES_START};
+use super::storage::{FileSystem, Storage};
+use super::{
+    Bincode, ChainHash, Codec, CodecId, Json, KeyProvider, WalEntry, WalError, WalFrame, WalResult,
+};
+
+/// Paths of the segments of the WAL in `dir`, oldest first, as listed in its manifest.
+pub fn segment_paths(dir: &Path) -> WalResult<Vec<PathBuf>> {
+    let manifest = Manifest::load(&FileSystem, dir)?.ok_or_else(|| WalError::InvalidManifest {
+        path: Manifest::path(dir),
+        reason: "no manifest, not a WAL directory".into(),
+    })?;
+    Ok(manifest
+        .segments
+        .iter()
+        .map(|&start| WalSegment::file_path(dir, start))
+        .collect())
+}
+
+/// A segment file of a [`SegmentedWal`](super::segmented_log::SegmentedWal) of [`WalEntry`]s
+/// read on its own, e.g. to look inside it with `walctl`. Nothing needs the WAL to be open.
+#[derive(Debug)]
+pub struct Segment {
+    inner: WalSegment,
+    cipher: Option<Cipher>,
+}
+
+impl Segment {
+    /// Opens the segment at `path`. Encrypted frames can only be read with the `key` of the log.
+    pub fn open(path: &Path, key: Option<&dyn KeyProvider>) -> WalResult<Self> {
+        Ok(Self {
+            inner: WalSegment::open_file(&FileSystem, path)?,
+            cipher: key.map(Cipher::new).transpose()?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Index of the first entry, as recorded in the header.
+    pub fn start_index(&self) -> u64 {
+        self.inner.start_index
+    }
+
+    pub fn codec(&self) -> CodecId {
+        self.inner.header.codec
+    }
+
+    /// Size of the file, header included.
+    pub fn size(&self) -> WalResult<u64> {
+        self.inner.size()
+    }
+
+    /// Byte offset of the frame [`Segment::read_next`] returns next.
+    pub fn offset(&self) -> u64 {
+        self.inner.read_offset
+    }
+
+    /// The next frame, checked, decrypted and decompressed. Fails at the first frame that does
+    /// not check or whose index does not follow the previous one, and stays there.
+    pub fn read_next(&mut self) -> WalResult<Option<WalFrame>> {
+        self.inner.read_next(self.cipher.as_ref())
+    }
+
+    /// The entry of `frame`, decoded with the codec of the segment.
+    pub fn decode(&self, frame: &WalFrame) -> WalResult<WalEntry> {
+        match self.codec() {
+            CodecId::Rkyv => Ok(rkyv::deserialize::<WalEntry, Error>(frame.zero_copy()?)?),
+            CodecId::Bincode => <Bincode as Codec<WalEntry>>::decode(&frame.buf),
+            CodecId::Json => <Json as Codec<WalEntry>>::decode(&frame.buf),
+        }
+    }
+
+    /// Checks the hash chain of every frame, see
+    /// [`SegmentedWal::verify_chain`](super::segmented_log::SegmentedWal::verify_chain).
+    /// `prev` is the hash returned for the previous segment, if it was checked too.
+    pub fn verify_chain(&self, prev: Option<ChainHash>) -> WalResult<ChainHash> {
+        MappedSegment::new(&*self.inner.file, &self.inner.path)?.verify_chain(prev)
+    }
+}
+
+/// What [`repair`] dropped from the end of a log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    /// Segment that was cut, at the first frame that did not check.
+    pub segment: PathBuf,
+    /// Byte offset the segment was cut at.
+    pub offset: u64,
+    /// Log index of the first entry dropped.
+    pub index: u64,
+    /// Later segments deleted whole.
+    pub dropped_segments: usize,
+    /// Bytes cut from the segment or deleted with the later ones, preallocated space
+    /// included.
+    pub dropped_bytes: u64,
+}
+
+/// Keeps the longest valid prefix of the WAL in `dir`: cuts it at the first frame that is
+/// corrupt, truncated or out of order, and deletes every later segment. `None` if the whole log
+/// checks. The WAL must not be open meanwhile.
+///
+/// Only checksums, flags and indexes are checked, blobs are neither decrypted nor
+/// decompressed, so no key is needed. Crash safe like
+/// [`SegmentedWal::truncate_after`](super::segmented_log::SegmentedWal::truncate_after).
+pub fn repair(dir: &Path) -> WalResult<Option<Repair>> {
+    let storage = &FileSystem;
+    let Some(mut manifest) = Manifest::load(storage, dir)? else {
+        return Ok(None);
+    };
+    let mut next_index = None;
+    // Where the frames of the previous segment end, not its file: it may be preallocated
+    let mut previous_end = FRAMES_START;
+    for (pos, &start) in manifest.segments.iter().enumerate() {
+        let mut segment = WalSegment::open_file(storage, &WalSegment::file_path(dir, start))?;
+        if next_index.is_some_and(|next| next != start) {
+            // The previous segment ends at a frame boundary, nothing more to cut there
+            return drop_after(
+                storage,
+                dir,
+                &mut manifest,
+                pos - 1,
+                previous_end,
+                next_index,
+            );
+        }
+        next_index = Some(start);
+        loop {
+            match segment.check_next() {
+                Ok(Some(header)) => next_index = Some(header.index + 1),
+                Ok(None) => {
+                    previous_end = segment.read_offset;
+                    break;
+                }
+                Err(
+                    WalError::CorruptFrame { .. }
+                    | WalError::TruncatedFrame { .. }
+                    | WalError::NonContiguous { .. },
+                ) => {
+                    let offset = segment.read_offset;
+                    return drop_after(storage, dir, &mut manifest, pos, offset, next_index);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Cuts the segment at `pos` of the manifest at `offset` and deletes the later ones, dropping
+/// them from the manifest first.
+fn drop_after(
+    storage: &dyn Storage,
+    dir: &Path,
+    manifest: &mut Manifest,
+    pos: usize,
+    offset: u64,
+    next_index: Option<u64>,
+) -> WalResult<Option<Repair>> {
+    let later = manifest.segments.split_off(pos + 1);
+    manifest.store(storage, dir)?;
+    let mut dropped_bytes = 0;
+    for &start in &later {
+        let path = WalSegment::file_path(dir, start);
+        dropped_bytes += storage.open(&path, false)?.len()?;
+        storage.remove(&path)?;
+    }
+    storage.sync_dir(dir)?;
+
+    let mut segment =
+        WalSegment::open_file(storage, &WalSegment::file_path(dir, manifest.segments[pos]))?;
+    dropped_bytes += segment.size()? - offset;
+    segment.cut(offset)?;
+    Ok(Some(Repair {
+        index: next_index.unwrap_or(segment.start_index),
+        segment: segment.path,
+        offset,
+        dropped_segments: later.len(),
+        dropped_bytes,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::segmented_log::{SegmentedWal, WALConfig};
+    use crate::wal::{ArchivedWalEntry, KeyFile};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn cfg(dir: &TempDir) -> WALConfig {
+        WALConfig {
+            path: dir.path().to_str().expect("").into(),
+            max_log_size: 400,
+            ..Default::default()
+        }
+    }
+
+    fn write_log(dir: &TempDir, n: u64) -> Vec<PathBuf> {
+        write_log_with(cfg(dir), dir, n)
+    }
+
+    fn write_log_with(cfg: WALConfig, dir: &TempDir, n: u64) -> Vec<PathBuf> {
+        let mut wal = SegmentedWal::open(cfg).expect("");
+        for i in 0..n {
+            let entry = match i % 3 {
+                0 => WalEntry::Batch(HashMap::from([(format!("k{i}"), "v".into())])),
+                _ => WalEntry::Set(format!("k{i}"), "v".into()),
+            };
+            wal.write(entry).expect("");
+        }
+        segment_paths(dir.path()).expect("")
+    }
+
+    #[test]
+    fn segments_are_read_frame_by_frame() {
+        let dir = TempDir::new().expect("");
+        let paths = write_log(&dir, 12);
+        assert!(paths.len() > 2, "{paths:?}");
+
+        let mut next = 1;
+        let mut chain = None;
+        for path in &paths {
+            let mut segment = Segment::open(path, None).expect("");
+            assert_eq!(segment.start_index(), next);
+            assert_eq!(segment.codec(), CodecId::Rkyv);
+            chain = Some(segment.verify_chain(chain).expect(""));
+            while let Some(frame) = segment.read_next().expect("") {
+                assert_eq!(frame.index, next);
+                let is_batch = matches!(frame.zero_copy().expect(""), ArchivedWalEntry::Batch(_));
+                assert_eq!(is_batch, (next - 1) % 3 == 0);
+                assert!(
+                    matches!(segment.decode(&frame).expect(""), WalEntry::Batch(_)) == is_batch
+                );
+                next += 1;
+            }
+            assert_eq!(segment.offset(), segment.size().expect(""));
+        }
+        assert_eq!(next, 13);
+        assert!(segment_paths(&dir.path().join("nope")).is_err());
+    }
+
+    #[test]
+    fn repair_keeps_the_longest_valid_prefix() {
+        let dir = TempDir::new().expect("");
+        let paths = write_log(&dir, 12);
+        assert_eq!(repair(dir.path()).expect(""), None);
+
+        // Second frame of the second segment
+        let mut segment = Segment::open(&paths[1], None).expect("");
+        let first = segment.read_next().expect("").expect("").index;
+        let offset = segment.offset();
+        let mut bytes = fs::read(&paths[1]).expect("");
+        let size = bytes.len() as u64;
+        bytes[offset as usize + 70] ^= 1;
+        fs::write(&paths[1], bytes).expect("");
+        let later: u64 = paths[2..]
+            .iter()
+            .map(|p| fs::metadata(p).expect("").len())
+            .sum();
+
+        let repaired = repair(dir.path()).expect("").expect("");
+        assert_eq!(
+            repaired,
+            Repair {
+                segment: paths[1].clone(),
+                offset,
+                index: first + 1,
+                dropped_segments: paths.len() - 2,
+                dropped_bytes: size - offset + later,
+            }
+        );
+        assert_eq!(segment_paths(dir.path()).expect(""), paths[..2]);
+        assert!(!paths[2].exists());
+        let wal = SegmentedWal::<WalEntry>::open(cfg(&dir)).expect("");
+        assert_eq!(wal.last_index(), first);
+        assert_eq!(repair(dir.path()).expect(""), None);
+    }
+
+    #[test]
+    fn repair_needs_no_key() {
+        let keys = TempDir::new().expect("");
+        let key = KeyFile::generate(keys.path().join("wal.key")).expect("");
+        let dir = TempDir::new().expect("");
+        let encrypted = WALConfig {
+            encryption: Some(Arc::new(key)),
+            ..cfg(&dir)
+        };
+        let paths = write_log_with(encrypted, &dir, 12);
+        assert_eq!(repair(dir.path()).expect(""), None);
+
+        let last = paths.last().expect("");
+        let mut bytes = fs::read(last).expect("");
+        bytes[FRAMES_START as usize + 70] ^= 1;
+        fs::write(last, bytes).expect("");
+        let repaired = repair(dir.path()).expect("").expect("");
+        assert_eq!((&repaired.segment, repaired.offset), (last, FRAMES_START));
+    }
+
+    #[test]
+    fn repair_cuts_preallocated_segments_after_their_last_frame() {
+        let dir = TempDir::new().expect("");
+        // Segments of 4 entries, with room to spare
+        let preallocated = || WALConfig {
+            max_log_size: 4096,
+            max_segment_entries: Some(4),
+            preallocate: true,
+            ..cfg(&dir)
+        };
+        let paths = write_log_with(preallocated(), &dir, 12);
+        assert!(paths.len() > 2, "{paths:?}");
+        // The second segment goes missing from the manifest, the third one does not follow
+        let mut manifest = Manifest::load(&FileSystem, dir.path())
+            .expect("")
+            .expect("");
+        manifest.segments.remove(1);
+        manifest.store(&FileSystem, dir.path()).expect("");
+
+        let mut first = Segment::open(&paths[0], None).expect("");
+        let mut last = 0;
+        while let Some(frame) = first.read_next().expect("") {
+            last = frame.index;
+        }
+        let size = first.size().expect("");
+        assert!(first.offset() < size, "{} {size}", first.offset());
+
+        let repaired = repair(dir.path()).expect("").expect("");
+        assert_eq!(
+            (repaired.segment, repaired.offset, repaired.index),
+            (paths[0].clone(), first.offset(), last + 1)
+        );
+        assert_eq!(repaired.dropped_segments, paths.len() - 2);
+        assert_eq!(fs::metadata(&paths[0]).expect("").len(), first.offset());
+        let wal = SegmentedWal::<WalEntry>::open(preallocated()).expect("");
+        assert_eq!(wal.last_index(), last);
+    }
+}
@@ -0,0 +1,193 @@
+use rkyv::ser::allocator::Arena;
+use rkyv::util::AlignedVec;
+
+use super::{zero_copy, Codec, LogEntry, Rkyv, WalResult};
+
+/// What every WAL here offers, so code like the [`KVStore`](crate::KVStore) can run on any of
+/// them: the single file [`simple_wal`](super::simple_wal) and [`wal`](super::wal) logs, or the
+/// [`SegmentedWal`](super::segmented_log::SegmentedWal).
+///
+/// Entries get contiguous indexes, the first one [`WriteAheadLog::first_index`].
+pub trait WriteAheadLog<T> {
+    /// Entries of the log in order, with their index.
+    type Reader: Iterator<Item = WalResult<(u64, T)>>;
+
+    /// Appends `entry` and returns its index once it is as durable as the log's durability
+    /// policy promises. The log only reads `entry`, which the caller may reuse for the next one.
+    fn append(&mut self, entry: &T) -> WalResult<u64>;
+
+    /// Reads the log from `index` on. Past the last entry the reader is empty.
+    fn read_from(&mut self, index: u64) -> WalResult<Self::Reader>;
+
+    /// Lowest index still in the log.
+    fn first_index(&self) -> u64;
+
+    /// Index of the last entry, `first_index() - 1` if there is none.
+    fn last_index(&self) -> u64;
+
+    /// Calls `apply` with every entry of the log in order and its index, the entry archived and
+    /// only borrowed for the call, e.g. to rebuild state on startup. Unlike
+    /// [`WriteAheadLog::read_from`], nothing needs to be allocated per entry.
+    ///
+    /// By default entries are read with `read_from` and archived again in a reused buffer.
+    /// Logs keeping archives lend them instead, as the
+    /// [`SegmentedWal`](super::segmented_log::SegmentedWal) does from its mapped segments.
+    fn replay(&mut self, apply: impl FnMut(u64, &T::Archived)) -> WalResult<()>
+    where
+        T: LogEntry,
+        Self: Sized,
+    {
+        replay_archived_again(self, apply)
+    }
+
+    /// Drops every entry after `index`. The next one appended gets `index + 1`.
+    fn truncate_after(&mut self, index: u64) -> WalResult<()>;
+
+    /// Makes everything appended so far durable, whatever the durability policy.
+    fn sync(&mut self) -> WalResult<()>;
+
+    /// Syncs what the durability policy still owes and closes the log. Unlike dropping it,
+    /// failures are returned.
+    fn close(self) -> WalResult<()>
+    where
+        Self: Sized;
+}
+
+/// [`WriteAheadLog::replay`] through owned entries, archived again one after the other.
+pub(super) fn replay_archived_again<T: LogEntry, W: WriteAheadLog<T>>(
+    wal: &mut W,
+    mut apply: impl FnMut(u64, &T::Archived),
+) -> WalResult<()> {
+    let (mut archive, mut arena) = (AlignedVec::new(), Arena::new());
+    for entry in wal.read_from(wal.first_index())? {
+        let (index, entry) = entry?;
+        archive.clear();
+        <Rkyv as Codec<T>>::encode(&entry, &mut archive, &mut arena)?;
+        apply(index, zero_copy::<T>(&archive)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::segmented_log::{self, SegmentedWal};
+    use crate::wal::{simple_wal, wal, ArchivedWalEntry, Json, MemoryStorage, WalEntry};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn set(i: u64) -> WalEntry {
+        WalEntry::Set(format!("k{i}"), format!("v{i}"))
+    }
+
+    fn keys<W: WriteAheadLog<WalEntry>>(wal: &mut W, from: u64) -> Vec<(u64, String)> {
+        wal.read_from(from)
+            .expect("")
+            .map(|entry| match entry.expect("") {
+                (index, WalEntry::Set(k, _)) => (index, k),
+                (_, WalEntry::Batch(_)) => panic!("expected a Set"),
+            })
+            .collect()
+    }
+
+    fn replayed_keys<W: WriteAheadLog<WalEntry>>(wal: &mut W) -> Vec<(u64, String)> {
+        let mut keys = vec![];
+        wal.replay(|index, entry| match entry {
+            ArchivedWalEntry::Set(k, _) => keys.push((index, k.to_string())),
+            ArchivedWalEntry::Batch(_) => panic!("expected a Set"),
+        })
+        .expect("");
+        keys
+    }
+
+    /// What any implementation must do, `open` reopening the same log every time.
+    fn honours_the_contract<W: WriteAheadLog<WalEntry>>(open: impl Fn() -> W) {
+        let mut wal = open();
+        assert_eq!((wal.first_index(), wal.last_index()), (1, 0));
+        assert!(keys(&mut wal, 1).is_empty());
+        for i in 1..=5 {
+            assert_eq!(wal.append(&set(i)).expect(""), i);
+        }
+        wal.sync().expect("");
+        let key = |i| (i, format!("k{i}"));
+        assert_eq!(keys(&mut wal, 3), [3, 4, 5].map(key));
+        assert!(keys(&mut wal, 6).is_empty());
+        assert_eq!(replayed_keys(&mut wal), keys(&mut wal, 1));
+
+        wal.truncate_after(3).expect("");
+        assert_eq!(wal.last_index(), 3);
+        assert_eq!(wal.append(&set(9)).expect(""), 4);
+        wal.close().expect("");
+
+        let mut wal = open();
+        assert_eq!(wal.last_index(), 4);
+        assert_eq!(
+            keys(&mut wal, 1),
+            [key(1), key(2), key(3), (4, "k9".into())]
+        );
+        assert_eq!(replayed_keys(&mut wal), keys(&mut wal, 1));
+        wal.truncate_after(0).expect("");
+        assert!(keys(&mut wal, 1).is_empty());
+        assert!(replayed_keys(&mut wal).is_empty());
+        assert_eq!(wal.append(&set(1)).expect(""), 1);
+    }
+
+    #[test]
+    fn simple_wal_honours_the_contract() {
+        let dir = TempDir::new().expect("");
+        let path = dir.path().join("simple.log");
+        honours_the_contract(|| {
+            simple_wal::WriteAheadLog::<WalEntry>::open(simple_wal::WALConfig {
+                path: path.to_str().expect("").into(),
+                ..Default::default()
+            })
+            .expect("")
+        });
+    }
+
+    #[test]
+    fn wal_honours_the_contract() {
+        let dir = TempDir::new().expect("");
+        let path = dir.path().join("wal.log");
+        honours_the_contract(|| {
+            wal::WriteAheadLog::<WalEntry>::open(wal::WALConfig {
+                path: path.to_str().expect("").into(),
+                ..Default::default()
+            })
+            .expect("")
+        });
+    }
+
+    #[test]
+    fn segmented_wal_honours_the_contract() {
+        let storage = MemoryStorage::default();
+        honours_the_contract(|| {
+            SegmentedWal::<WalEntry>::open(segmented_log::WALConfig {
+                path: "/wal".into(),
+                // Roll every couple of entries, so reads and truncation cross segments
+                max_log_size: 200,
+                storage: Arc::new(storage.clone()),
+                ..Default::default()
+            })
+            .expect("")
+        });
+    }
+
+    #[test]
+    fn segmented_wal_with_another_codec_honours_the_contract() {
+        let storage = MemoryStorage::default();
+        honours_the_contract(|| {
+            SegmentedWal::open_with_codec(
+                segmented_log::WALConfig {
+                    path: "/wal".into(),
+                    // Roll every couple of entries, so reads and truncation cross segments
+                    max_log_size: 200,
+                    storage: Arc::new(storage.clone()),
+                    ..Default::default()
+                },
+                Json,
+            )
+            .expect("")
+        });
+    }
+}
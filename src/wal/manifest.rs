@@ -0,0 +1,180 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::segmented_log::read_full_at;
+use super::storage::Storage;
+use super::{WalError, WalResult};
+
+/// Name of the manifest inside the WAL directory.
+pub(crate) const MANIFEST: &str = "MANIFEST";
+/// The next manifest is written here, then renamed over [`MANIFEST`].
+pub(crate) const MANIFEST_TMP: &str = "MANIFEST.tmp";
+
+const MAGIC: [u8; 8] = *b"PDSWALMF";
+const VERSION: u16 = 1;
+/// Magic, version, reserved, segment count, low-water mark and generation.
+const FIXED_LEN: usize = 8 + 2 + 2 + 4 + 8 + 8;
+
+/// What the WAL directory holds, so opening it needs neither globbing nor file name parsing.
+///
+/// Written to a temporary file, synced and renamed over the previous one: a crash leaves
+/// either the old or the new manifest, never half of one.
+///
+///┌─────────┬─────────┬──────────┬───────────┬────────────────┬────────────┬───────────────┬─────────┐
+///│ 8-byte  │ 2-byte  │ 2-byte   │ 4-byte    │ 8-byte         │ 8-byte     │ 8-byte × N    │ 4-byte  │
+///│ magic   │ version │ reserved │ N         │ low-water mark │ generation │ segment start │ crc32c  │
+///└─────────┴─────────┴──────────┴───────────┴────────────────┴────────────┴───────────────┴─────────┘
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    /// Start index of every live segment, ascending. The last one is the open segment.
+    pub(crate) segments: Vec<u64>,
+    /// Entries below this one may be gone, see
+    /// [`SegmentedWal::truncate_before`](super::segmented_log::SegmentedWal::truncate_before).
+    pub(crate) low_water_mark: u64,
+    /// Generation new entries are stamped with.
+    pub(crate) generation: u64,
+}
+
+impl Manifest {
+    pub(crate) fn path(dir: &Path) -> PathBuf {
+        dir.join(MANIFEST)
+    }
+
+    /// Reads the manifest of the WAL in `dir`, `None` if there is none yet.
+    pub(crate) fn load(storage: &dyn Storage, dir: &Path) -> WalResult<Option<Self>> {
+        let path = Self::path(dir);
+        let file = match storage.open(&path, false) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut bytes = vec![0u8; file.len()? as usize];
+        let read = read_full_at(&*file, &mut bytes, 0)?;
+        Self::from_le_bytes(&bytes[..read], &path).map(Some)
+    }
+
+    /// Atomically replaces the manifest of the WAL in `dir` with this one.
+    pub(crate) fn store(&self, storage: &dyn Storage, dir: &Path) -> WalResult<()> {
+        let tmp = dir.join(MANIFEST_TMP);
+        let file = storage.open(&tmp, true)?;
+        file.truncate(0)?;
+        file.append(&self.to_le_bytes())?;
+        file.sync()?;
+        storage.rename(&tmp, &Self::path(dir))?;
+        // Makes the rename durable, and the creation of any segment listed in it too
+        storage.sync_dir(dir)?;
+        Ok(())
+    }
+
+    fn to_le_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FIXED_LEN + 8 * self.segments.len() + 4);
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&[0u8; 2]);
+        buf.extend_from_slice(&(self.segments.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.low_water_mark.to_le_bytes());
+        buf.extend_from_slice(&self.generation.to_le_bytes());
+        for start in &self.segments {
+            buf.extend_from_slice(&start.to_le_bytes());
+        }
+        let crc = crc32c::crc32c(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn from_le_bytes(bytes: &[u8], path: &Path) -> WalResult<Self> {
+        let invalid = |reason: String| WalError::InvalidManifest {
+            path: path.into(),
+            reason,
+        };
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().expect(""));
+
+        if bytes.len() < FIXED_LEN + 4 || bytes[0..8] != MAGIC {
+            return Err(invalid("not a WAL manifest".into()));
+        }
+        let version = u16::from_le_bytes(bytes[8..10].try_into().expect("Issue with version"));
+        if version != VERSION {
+            return Err(invalid(format!(
+                "unsupported manifest version {version}, expected {VERSION}"
+            )));
+        }
+        let count = u32::from_le_bytes(bytes[12..16].try_into().expect("Issue with count"));
+        let len = FIXED_LEN + 8 * count as usize;
+        if bytes.len() != len + 4 {
+            return Err(invalid(format!(
+                "{} bytes do not hold {count} segments",
+                bytes.len()
+            )));
+        }
+        let crc = u32::from_le_bytes(bytes[len..].try_into().expect("Issue with checksum"));
+        if crc32c::crc32c(&bytes[..len]) != crc {
+            return Err(invalid("checksum mismatch".into()));
+        }
+
+        let segments: Vec<u64> = (FIXED_LEN..len).step_by(8).map(u64_at).collect();
+        if segments.is_empty() {
+            return Err(invalid("no segments".into()));
+        }
+        if !segments.is_sorted_by(|a, b| a < b) {
+            return Err(invalid("segments are not in order".into()));
+        }
+        Ok(Self {
+            segments,
+            low_water_mark: u64_at(16),
+            generation: u64_at(24),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::MemoryStorage;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            segments: vec![1, 40, 77],
+            low_water_mark: 12,
+            generation: 3,
+        }
+    }
+
+    fn storage() -> (MemoryStorage, &'static Path) {
+        let storage = MemoryStorage::default();
+        let dir = Path::new("/wal");
+        storage.create_dir(dir).expect("");
+        (storage, dir)
+    }
+
+    #[test]
+    fn store_replaces_the_previous_manifest() {
+        let (storage, dir) = storage();
+        assert_eq!(Manifest::load(&storage, dir).expect(""), None);
+
+        manifest().store(&storage, dir).expect("");
+        let next = Manifest {
+            segments: vec![40, 77, 90],
+            ..manifest()
+        };
+        next.store(&storage, dir).expect("");
+
+        assert_eq!(Manifest::load(&storage, dir).expect(""), Some(next));
+        assert!(!storage.exists(&dir.join(MANIFEST_TMP)));
+    }
+
+    #[test]
+    fn corruption_is_reported() {
+        let (storage, dir) = storage();
+        manifest().store(&storage, dir).expect("");
+        let file = storage.open(&Manifest::path(dir), false).expect("");
+        let mut bytes = file.map().expect("").to_vec();
+        bytes[20] ^= 0x01;
+        file.truncate(0).expect("");
+        file.append(&bytes).expect("");
+
+        assert!(matches!(
+            Manifest::load(&storage, dir),
+            Err(WalError::InvalidManifest { reason, .. }) if reason == "checksum mismatch"
+        ));
+    }
+}
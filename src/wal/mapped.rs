@@ -0,0 +1,303 @@
+use rkyv::util::AlignedVec;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use super::compression;
+use super::encryption::Cipher;
+use super::segment_header::{SegmentHeader, SEGMENT_HEADER_LEN};
+use super::storage::{FileBytes, StorageFile};
+use super::{
+    chain_hash, frame_checksum, is_blank, zero_copy, ChainHash, FrameHeader, LogEntry, WalEntry,
+    WalError, WalResult, FRAME_HEADER_LEN,
+};
+
+/// A segment file mapped in memory, read without copying any frame out of it.
+///
+/// Frames are padded on write so every blob starts 16 bytes aligned in the file. The mapping
+/// itself is page aligned, so the archives can be accessed right where they are.
+pub(crate) struct MappedSegment {
+    path: PathBuf,
+    header: SegmentHeader,
+    map: FileBytes,
+}
+
+impl MappedSegment {
+    /// Maps the whole segment `file`, found at `path`. See [`StorageFile::map`].
+    ///
+    /// Reading a mapping whose file shrinks underneath is undefined behaviour (SIGBUS at best),
+    /// so only map segments nobody truncates while the mapping lives: sealed ones, or the open
+    /// one while its [`SegmentedWal`](super::segmented_log::SegmentedWal) is borrowed mutably.
+    /// Appends are fine, they are simply not seen.
+    pub(crate) fn new(file: &dyn StorageFile, path: &Path) -> WalResult<Self> {
+        let map = file.map()?;
+        let header = SegmentHeader::from_le_bytes(&map, path)?;
+        Ok(Self {
+            path: path.into(),
+            header,
+            map,
+        })
+    }
+
+    /// Checks the hash of every frame, see [`chain_hash`], and returns the hash of the last
+    /// one. `prev` is the hash of the last frame of the previous segment, if it is known.
+    ///
+    /// Only the stored bytes are hashed, so neither the key nor the codec is needed.
+    pub(crate) fn verify_chain(&self, prev: Option<ChainHash>) -> WalResult<ChainHash> {
+        let broken = |index| WalError::BrokenChain {
+            segment: self.path.clone(),
+            index,
+        };
+        if prev.is_some_and(|prev| prev != self.header.prev_hash) {
+            return Err(broken(self.header.start_index));
+        }
+        let mut hash = self.header.prev_hash;
+        let mut offset = SEGMENT_HEADER_LEN;
+        while let Some((header, blob)) = raw_frame(&self.map, &mut offset)? {
+            hash = chain_hash(&hash, header.index, header.generation, blob);
+            if hash != header.hash {
+                return Err(broken(header.index));
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Walks the frames of the segment, checking each one as it goes. Encrypted frames are
+    /// decrypted with `cipher`.
+    pub(crate) fn frames<'a, T: LogEntry>(
+        &'a self,
+        cipher: Option<&'a Cipher>,
+    ) -> MappedFrames<'a, T> {
+        MappedFrames {
+            bytes: &self.map,
+            offset: SEGMENT_HEADER_LEN,
+            cipher,
+            plaintext: AlignedVec::new(),
+            scratch: AlignedVec::new(),
+            entry: PhantomData,
+        }
+    }
+}
+
+/// A frame borrowed from a [`MappedSegment`], or from the buffer its blob was decompressed
+/// into.
+pub struct MappedFrame<'a, T: LogEntry = WalEntry> {
+    pub index: u64,
+    pub generation: u64,
+    pub entry: &'a T::Archived,
+}
+
+/// Frames of a [`MappedSegment`] in order. Stops at the first frame that fails to check.
+///
+/// Not an [`Iterator`]: an encrypted or compressed frame is decrypted or decompressed into
+/// buffers reused for the next one, so each frame borrows the walk itself.
+#[derive(Debug)]
+pub(crate) struct MappedFrames<'a, T> {
+    bytes: &'a [u8],
+    offset: usize,
+    cipher: Option<&'a Cipher>,
+    plaintext: AlignedVec<16>,
+    scratch: AlignedVec<16>,
+    entry: PhantomData<fn() -> T>,
+}
+
+impl<T: LogEntry> MappedFrames<'_, T> {
+    /// The next frame, `None` once the segment is exhausted or after an error.
+    pub(crate) fn next(&mut self) -> Option<WalResult<MappedFrame<'_, T>>> {
+        let Self {
+            bytes,
+            offset,
+            cipher,
+            plaintext,
+            scratch,
+            ..
+        } = self;
+        match read_frame(bytes, offset, *cipher, plaintext, scratch) {
+            Ok(frame) => frame.map(Ok),
+            Err(e) => {
+                // Do not keep on failing at the same frame
+                *offset = bytes.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Reads the frame at `offset` of `bytes` and moves past it. An encrypted blob is decrypted
+/// into `plaintext`, a compressed one decompressed into `scratch`, and read from there. With a
+/// `cipher`, plain blobs are refused.
+fn read_frame<'a, T: LogEntry>(
+    bytes: &'a [u8],
+    offset: &mut usize,
+    cipher: Option<&Cipher>,
+    plaintext: &'a mut AlignedVec<16>,
+    scratch: &'a mut AlignedVec<16>,
+) -> WalResult<Option<MappedFrame<'a, T>>> {
+    let at = *offset;
+    let Some((header, mut blob)) = raw_frame(bytes, offset)? else {
+        return Ok(None);
+    };
+    header.check_flags(at as u64)?;
+    if let Some(cipher) = header.cipher(cipher, at as u64)? {
+        plaintext.clear();
+        plaintext.extend_from_slice(blob);
+        let len = cipher.open(
+            header.index,
+            header.generation,
+            header.flags,
+            plaintext,
+            at as u64,
+        )?;
+        blob = &plaintext[..len];
+    }
+    if header.is_compressed() {
+        compression::decompress_into(blob, at as u64, scratch)?;
+        blob = scratch;
+    }
+    let entry = zero_copy::<T>(blob)?;
+    Ok(Some(MappedFrame {
+        index: header.index,
+        generation: header.generation,
+        entry,
+    }))
+}
+
+/// Checks the frame at `offset` of `bytes` and moves past it, returning its header and its
+/// blob as stored. `None` at the end of the file or of the frames, see [`is_blank`].
+fn raw_frame<'a>(
+    bytes: &'a [u8],
+    offset: &mut usize,
+) -> WalResult<Option<(FrameHeader, &'a [u8])>> {
+    let at = *offset;
+    let rest = &bytes[at..];
+    if is_blank(&rest[..rest.len().min(FRAME_HEADER_LEN)]) {
+        return Ok(None);
+    }
+    let truncated = Err(WalError::TruncatedFrame { offset: at as u64 });
+    let Some((hdr, rest)) = rest.split_first_chunk::<FRAME_HEADER_LEN>() else {
+        return truncated;
+    };
+    let header = FrameHeader::from_le_bytes(hdr);
+    let frame_len = header.frame_len() as usize;
+    if rest.len() < frame_len - FRAME_HEADER_LEN {
+        return truncated;
+    }
+
+    let blob = &rest[..header.blob_len as usize];
+    if frame_checksum(hdr, blob) != header.crc {
+        return Err(WalError::CorruptFrame {
+            offset: at as u64,
+            reason: "checksum mismatch",
+        });
+    }
+    *offset += frame_len;
+    Ok(Some((header, blob)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{
+        ArchivedWalEntry, CodecId, Compression, FileSystem, FrameScratch, Rkyv, Storage,
+        WalEntryWithHeader, CHAIN_START,
+    };
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn open(path: &Path) -> MappedSegment {
+        MappedSegment::new(&*FileSystem.open(path, false).expect(""), path).expect("")
+    }
+
+    fn write_segment(path: &Path, values: &[&str]) {
+        write_compressed_segment(path, values, Compression::None);
+    }
+
+    fn write_compressed_segment(path: &Path, values: &[&str], compression: Compression) {
+        let mut bytes = SegmentHeader::new(1, CodecId::Rkyv, CHAIN_START)
+            .to_le_bytes()
+            .to_vec();
+        let (mut chain, mut scratch) = (CHAIN_START, FrameScratch::default());
+        for (i, v) in values.iter().enumerate() {
+            let entry = WalEntryWithHeader {
+                index: i as u64 + 1,
+                generation: 3,
+                entry: &WalEntry::Set(format!("k{i}"), (*v).into()),
+            };
+            let frame = entry
+                .encode::<Rkyv>(&mut scratch, compression, None, &mut chain)
+                .expect("");
+            bytes.extend(frame.header);
+            bytes.extend(frame.blob);
+            bytes.extend(frame.padding());
+        }
+        fs::write(path, bytes).expect("");
+    }
+
+    #[test]
+    fn frames_are_read_in_place() {
+        let dir = TempDir::new().expect("");
+        let path = dir.path().join("00000000000000000001.log");
+        // Values of different lengths so some frames need padding
+        write_segment(&path, &["a", "bbb", "ccccccccccccccccc"]);
+
+        let segment = open(&path);
+        let mut values = vec![];
+        let mut frames = segment.frames::<WalEntry>(None);
+        while let Some(frame) = frames.next() {
+            let frame = frame.expect("");
+            let ArchivedWalEntry::Set(_, v) = frame.entry else {
+                panic!("expected a Set");
+            };
+            let start = segment.map.as_ptr() as usize;
+            let at = frame.entry as *const ArchivedWalEntry as usize;
+            assert!(start <= at && at < start + segment.map.len());
+            assert_eq!(frame.generation, 3);
+            values.push((frame.index, v.to_string()));
+        }
+        assert_eq!(
+            values,
+            [(1, "a"), (2, "bbb"), (3, "ccccccccccccccccc")].map(|(i, v)| (i, v.to_string()))
+        );
+    }
+
+    #[test]
+    fn stops_at_a_corrupt_frame() {
+        let dir = TempDir::new().expect("");
+        let path = dir.path().join("00000000000000000001.log");
+        write_segment(&path, &["a", "b"]);
+        let mut bytes = fs::read(&path).expect("");
+        let frame_len = (bytes.len() - SEGMENT_HEADER_LEN) / 2;
+        let second = SEGMENT_HEADER_LEN + frame_len;
+        bytes[second + FRAME_HEADER_LEN] ^= 0x01;
+        fs::write(&path, &bytes).expect("");
+
+        let segment = open(&path);
+        let mut frames = segment.frames::<WalEntry>(None);
+        assert_eq!(frames.next().expect("").expect("").index, 1);
+        assert!(matches!(
+            frames.next(),
+            Some(Err(WalError::CorruptFrame { offset, .. })) if offset == second as u64
+        ));
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn compressed_frames_are_decompressed() {
+        let dir = TempDir::new().expect("");
+        let path = dir.path().join("00000000000000000001.log");
+        let long = "value ".repeat(50);
+        let values = ["a", &long, "b", &long];
+        write_compressed_segment(&path, &values, Compression::Lz4 { min_size: 64 });
+        assert!(fs::metadata(&path).expect("").len() < 2 * long.len() as u64);
+
+        let segment = open(&path);
+        let mut read = vec![];
+        let mut frames = segment.frames::<WalEntry>(None);
+        while let Some(frame) = frames.next() {
+            let ArchivedWalEntry::Set(_, v) = frame.expect("").entry else {
+                panic!("expected a Set");
+            };
+            read.push(v.to_string());
+        }
+        assert_eq!(read, values);
+    }
+}
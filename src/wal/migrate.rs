@@ -0,0 +1,176 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::segmented_log::{SegmentedWal, WALConfig};
+use super::simple_wal::WalReader;
+use super::{DurabilityPolicy, LogEntry, WalError, WalResult, WriteAheadLog};
+
+/// Converts the [`simple_wal`](super::simple_wal) log at `src`, length prefixed blobs with no
+/// index nor header, into a [`SegmentedWal`] written to `cfg.path`, which must not exist yet.
+///
+/// Entries get contiguous indexes from `cfg.start_index + 1` and are all stamped with
+/// `generation`. Each one is decoded as a `T` before it is written again, so a log that does not
+/// hold `T`s, or is damaged, fails the migration and nothing appears at `cfg.path`.
+///
+/// The log is written and synced under `<cfg.path>.migrating`, whatever `cfg.durability` says,
+/// then renamed to `cfg.path`: it appears whole or not at all, so `cfg.storage` must be able
+/// to rename directories. Returns how many entries were migrated. `src` is left untouched.
+pub fn migrate_simple_wal<T: LogEntry>(
+    src: &Path,
+    cfg: WALConfig,
+    generation: u64,
+) -> WalResult<u64> {
+    let dest = PathBuf::from(&cfg.path);
+    let storage = cfg.storage.clone();
+    if storage.exists(&dest) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dest.display()),
+        )
+        .into());
+    }
+    let mut tmp = dest.clone().into_os_string();
+    tmp.push(".migrating");
+    let tmp = PathBuf::from(tmp);
+
+    let entries = WalReader::<T>::open(src)?;
+    let mut wal = SegmentedWal::<T>::open(WALConfig {
+        path: tmp.to_str().ok_or(WalError::ShouldNotHappen)?.into(),
+        // Whatever an interrupted migration left there goes
+        truncate: true,
+        // Entries are appended without commit, so this only syncs each segment once sealed,
+        // and the open one by `sync` below: all of them are durable before the rename
+        durability: DurabilityPolicy::EveryWrite,
+        ..cfg
+    })?;
+    wal.set_generation(generation)?;
+    let mut migrated = 0;
+    for entry in entries {
+        let (_, entry) = entry.map_err(|e| WalError::Migration {
+            index: migrated + 1,
+            source: Box::new(e),
+        })?;
+        wal.append(&entry)?;
+        migrated += 1;
+    }
+    wal.sync()?;
+    wal.close()?;
+
+    storage.rename(&tmp, &dest)?;
+    let parent = match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    storage.sync_dir(parent)?;
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{simple_wal, Crash, FaultyStorage, MemoryStorage, WalEntry};
+    use std::fs;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn write_simple_wal(path: &Path, n: u64) {
+        let mut wal = simple_wal::WriteAheadLog::<WalEntry>::open(simple_wal::WALConfig {
+            path: path.to_str().expect("").into(),
+            ..Default::default()
+        })
+        .expect("");
+        for i in 0..n {
+            wal.write(WalEntry::Set(format!("k{i}"), format!("v{i}")))
+                .expect("");
+        }
+    }
+
+    fn cfg(dest: &Path) -> WALConfig {
+        WALConfig {
+            path: dest.to_str().expect("").into(),
+            max_log_size: 256,
+            start_index: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn entries_keep_their_order_under_new_indexes() {
+        let dir = TempDir::new().expect("");
+        let src = dir.path().join("simple.log");
+        write_simple_wal(&src, 12);
+        let dest = dir.path().join("wal");
+
+        assert_eq!(
+            migrate_simple_wal::<WalEntry>(&src, cfg(&dest), 7).expect(""),
+            12
+        );
+        assert!(!dir.path().join("wal.migrating").exists());
+        let mut wal = SegmentedWal::<WalEntry>::open(cfg(&dest)).expect("");
+        assert_eq!((wal.first_index(), wal.last_index()), (11, 22));
+        assert_eq!(wal.generation(), 7);
+        for (i, frame) in wal.read_from(11).expect("").enumerate() {
+            let frame = frame.expect("");
+            assert_eq!((frame.index, frame.generation), (11 + i as u64, 7));
+            assert!(matches!(
+                frame.deserialize().expect(""),
+                WalEntry::Set(k, _) if k == format!("k{i}")
+            ));
+        }
+        wal.verify_chain().expect("");
+
+        // Never over an existing log
+        assert!(matches!(
+            migrate_simple_wal::<WalEntry>(&src, cfg(&dest), 7),
+            Err(WalError::IO(e)) if e.kind() == io::ErrorKind::AlreadyExists
+        ));
+    }
+
+    #[test]
+    fn an_entry_that_does_not_decode_fails_the_whole_migration() {
+        let dir = TempDir::new().expect("");
+        let src = dir.path().join("simple.log");
+        write_simple_wal(&src, 3);
+        // A fourth blob that is no archived WalEntry
+        let mut bytes = fs::read(&src).expect("");
+        bytes.extend_from_slice(&8u32.to_le_bytes());
+        bytes.extend_from_slice(&[0xff; 8]);
+        fs::write(&src, bytes).expect("");
+        let dest = dir.path().join("wal");
+
+        assert!(matches!(
+            migrate_simple_wal::<WalEntry>(&src, cfg(&dest), 1),
+            Err(WalError::Migration { index: 4, .. })
+        ));
+        assert!(!dest.exists());
+
+        // Retried once the source is fixed, over what the failed attempt left
+        fs::remove_file(&src).expect("");
+        write_simple_wal(&src, 2);
+        assert_eq!(
+            migrate_simple_wal::<WalEntry>(&src, cfg(&dest), 1).expect(""),
+            2
+        );
+    }
+
+    #[test]
+    fn a_migrated_log_survives_a_power_loss() {
+        let dir = TempDir::new().expect("");
+        let src = dir.path().join("simple.log");
+        write_simple_wal(&src, 12);
+        let storage = FaultyStorage::new(MemoryStorage::default(), 3);
+        let cfg = |storage: &FaultyStorage| WALConfig {
+            storage: Arc::new(storage.clone()),
+            ..cfg(Path::new("/wal"))
+        };
+
+        assert_eq!(
+            migrate_simple_wal::<WalEntry>(&src, cfg(&storage), 1).expect(""),
+            12
+        );
+        let after = storage.crash(Crash::DropUnsynced).expect("");
+        let mut wal = SegmentedWal::<WalEntry>::open(cfg(&after)).expect("");
+        assert_eq!((wal.first_index(), wal.last_index()), (11, 22));
+        assert_eq!(wal.read_from(11).expect("").count(), 12);
+    }
+}
@@ -1,86 +1,401 @@
+mod background;
+mod codec;
+mod compression;
+mod durability;
+mod encryption;
+mod faulty_storage;
+pub mod group_commit;
+pub mod inspect;
+mod log;
+mod manifest;
+pub mod mapped;
+mod migrate;
+mod segment_header;
 pub mod segmented_log;
 pub mod simple_wal;
+mod storage;
+#[allow(clippy::module_inception)]
 pub mod wal;
 
+use rkyv::api::high::{HighDeserializer, HighSerializer, HighValidator};
+use rkyv::bytecheck::CheckBytes;
 use rkyv::rancor::Failure;
-use rkyv::{rancor::Error, Archive, Deserialize, Serialize};
+use rkyv::ser::allocator::{Arena, ArenaHandle};
+use rkyv::util::AlignedVec;
+use rkyv::{rancor::Error, Archive, Deserialize, Portable, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
 use thiserror::Error;
 
+use encryption::Cipher;
+
+pub use codec::{Bincode, Codec, CodecId, Json, Rkyv};
+pub use compression::{Compression, CompressionStats};
+pub use durability::DurabilityPolicy;
+pub use encryption::{Key, KeyFile, KeyProvider, KEY_LEN};
+pub use faulty_storage::{Crash, FaultyStorage, IoFault};
+pub use log::WriteAheadLog;
+pub use migrate::migrate_simple_wal;
+pub use storage::{FileBytes, FileSystem, MemoryStorage, Storage, StorageFile};
+
 #[derive(Error, Debug)]
 pub enum WalError {
     #[error("failed to serialize WAL entry: {0}")]
     Serialization(#[from] rkyv::rancor::Error),
     #[error("failed to deserialize WAL entry: {0}")]
     Deserialization(#[from] rkyv::rancor::Failure),
+    #[error("failed to encode or decode WAL entry as {codec:?}: {source}")]
+    Codec {
+        codec: CodecId,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("cannot migrate entry {index}: {source}")]
+    Migration { index: u64, source: Box<WalError> },
     #[error("failure in log file: {0}")]
     IO(#[from] std::io::Error),
-    #[error("failure truncating old wal files: {0}")]
-    Truncate(#[from] glob::PatternError),
+    #[error("invalid WAL path pattern: {0}")]
+    Pattern(#[from] glob::PatternError),
+    #[error("failure truncating old wal segment {}: {source}", segment.display())]
+    Truncate {
+        segment: std::path::PathBuf,
+        source: std::io::Error,
+    },
     #[error("failure truncating old wal files: {0}")]
     Glob(#[from] glob::GlobError),
+    #[error("WAL frame at offset {offset} failed authentication: wrong key or tampered with")]
+    AuthenticationFailed { offset: u64 },
+    #[error("WAL frame at offset {offset} is encrypted but no key was configured")]
+    MissingKey { offset: u64 },
+    #[error("WAL frame at offset {offset} is not encrypted but a key was configured")]
+    NotEncrypted { offset: u64 },
+    #[error("WAL hash chain is broken at index {index} in {}", segment.display())]
+    BrokenChain {
+        segment: std::path::PathBuf,
+        index: u64,
+    },
+    #[error("corrupted WAL frame at offset {offset}: {reason}")]
+    CorruptFrame { offset: u64, reason: &'static str },
+    #[error("truncated WAL frame at offset {offset}")]
+    TruncatedFrame { offset: u64 },
+    #[error("WAL is not contiguous: expected index {expected} but found {found} in {}", segment.display())]
+    NonContiguous {
+        segment: std::path::PathBuf,
+        expected: u64,
+        found: u64,
+    },
+    #[error("cannot read WAL segment {}: {reason}", segment.display())]
+    InvalidSegmentHeader {
+        segment: std::path::PathBuf,
+        reason: String,
+    },
+    #[error("WAL segment {} is in the manifest but does not exist", segment.display())]
+    MissingSegment { segment: std::path::PathBuf },
+    #[error("cannot read WAL manifest {}: {reason}", path.display())]
+    InvalidManifest {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    #[error("log index {0} is not in the WAL")]
+    IndexOutOfRange(u64),
+    #[error("the WAL was closed")]
+    Closed,
     #[error("This should not happen")]
     ShouldNotHappen,
 }
 
 pub type WalResult<T> = std::result::Result<T, WalError>;
 
-struct WalEntryWithHeader {
+struct WalEntryWithHeader<'a, T> {
     index: u64,
     generation: u64,
-    entry: WalEntry,
-}
-
-impl WalEntryWithHeader {
-    fn to_le_bytes(self) -> WalResult<Vec<u8>> {
-        // TODO: use arenas for more efficient memory management
-        // https://docs.rs/rkyv/latest/rkyv/api/high/fn.to_bytes_with_alloc.html
-        let mut buf = Vec::new();
-        // header placeholder:
-        buf.reserve(20);
-        buf.extend_from_slice(&[0u8; 20]);
-        {
-            buf.extend_from_slice(&self.entry.serialize()?);
+    entry: &'a T,
+}
+
+impl<T> WalEntryWithHeader<'_, T> {
+    /// Encodes the frame with `C` in the buffers of `scratch`, see [`EncodedFrame`].
+    ///
+    /// The blob is compressed first, then encrypted if there is a `cipher`. `chain` is the
+    /// hash of the previous frame, replaced by the hash of this one.
+    fn encode<'s, C: Codec<T>>(
+        &self,
+        scratch: &'s mut FrameScratch,
+        compression: Compression,
+        cipher: Option<&Cipher>,
+        chain: &mut ChainHash,
+    ) -> WalResult<EncodedFrame<'s>> {
+        let FrameScratch {
+            blob,
+            compressed,
+            arena,
+        } = scratch;
+        blob.clear();
+        C::encode(self.entry, blob, arena)?;
+        let raw_len = blob.len();
+        let mut flags = 0;
+        if compression.compress_into(blob, compressed) {
+            mem::swap(blob, compressed);
+            flags |= compression::COMPRESSED;
         }
-        let blob_len = (buf.len() - 20) as u32;
-        buf[0..8].copy_from_slice(&self.index.to_le_bytes());
-        buf[8..16].copy_from_slice(&self.generation.to_le_bytes());
-        buf[16..20].copy_from_slice(&blob_len.to_le_bytes());
-        Ok(buf)
+        let stored_len = blob.len();
+        if let Some(cipher) = cipher {
+            flags |= encryption::ENCRYPTED;
+            cipher.seal(self.index, self.generation, flags, blob);
+        }
+        let blob_len = blob.len() as u32;
+        *chain = chain_hash(chain, self.index, self.generation, blob);
+
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header[0..8].copy_from_slice(&self.index.to_le_bytes());
+        header[8..16].copy_from_slice(&self.generation.to_le_bytes());
+        header[16..20].copy_from_slice(&blob_len.to_le_bytes());
+        header[24] = flags;
+        header[32..64].copy_from_slice(chain);
+        let crc = frame_checksum(&header, blob);
+        header[20..24].copy_from_slice(&crc.to_le_bytes());
+        Ok(EncodedFrame {
+            header,
+            blob,
+            raw_len,
+            stored_len,
+        })
     }
 }
 
-#[derive(Archive, Deserialize, Serialize, Debug)]
-pub enum WalEntry {
-    Set(String, String),
-    Batch(HashMap<String, String>),
+/// Memory a log encodes its frames in, kept from one frame to the next: once it has grown to
+/// fit the entries of the log, writing them allocates nothing.
+#[derive(Default)]
+struct FrameScratch {
+    /// The blob, as encoded then compressed and encrypted.
+    blob: AlignedVec,
+    /// Where the blob is compressed to, swapped with it when that made it smaller.
+    compressed: AlignedVec,
+    /// Scratch space of the rkyv serializer, see [`Rkyv`].
+    arena: Arena,
 }
 
-impl WalEntry {
-    fn serialize(&self) -> WalResult<rkyv::util::AlignedVec> {
-        Ok(rkyv::to_bytes::<Error>(self)?)
+impl fmt::Debug for FrameScratch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameScratch")
+            .field("blob", &self.blob.capacity())
+            .field("compressed", &self.compressed.capacity())
+            .finish_non_exhaustive()
     }
+}
 
-    fn deserialize(bytes: &[u8]) -> WalResult<Self> {
-        // This is not too efficient since we are deserializing and thus copying data
-        // We Should pass around the archived reference
-        let archived = rkyv::access::<ArchivedWalEntry, Failure>(bytes)?;
-        Ok(rkyv::deserialize::<WalEntry, Error>(archived)?)
+/// A frame as [`WalEntryWithHeader::encode`] leaves it: the header, then the blob, still in
+/// the [`FrameScratch`], then [`EncodedFrame::padding`].
+struct EncodedFrame<'s> {
+    header: [u8; FRAME_HEADER_LEN],
+    blob: &'s [u8],
+    /// Length of the blob as encoded, before compression and encryption.
+    raw_len: usize,
+    /// Length of the blob once compressed, before encryption adds its tag and salt.
+    stored_len: usize,
+}
+
+impl EncodedFrame<'_> {
+    /// Zeros keeping the next frame's blob aligned too.
+    fn padding(&self) -> &'static [u8] {
+        &ZEROS[..padded_len(self.blob.len() as u32) - self.blob.len()]
     }
-    fn zero_copy(bytes: &[u8]) -> WalResult<&ArchivedWalEntry> {
-        Ok(rkyv::access::<ArchivedWalEntry, Failure>(bytes)?)
+
+    /// Bytes the frame takes, padding included.
+    fn len(&self) -> u64 {
+        (FRAME_HEADER_LEN + padded_len(self.blob.len() as u32)) as u64
     }
 }
 
+/// Padding and blank headers, see [`is_blank`].
+static ZEROS: [u8; FRAME_HEADER_LEN] = [0; FRAME_HEADER_LEN];
+
+/// Frame header: index, generation, blob len, the crc32c of the header and the blob, flags,
+/// reserved bytes (zero) and the chain hash. 64 bytes, so the blob stays 16 bytes aligned.
+const FRAME_HEADER_LEN: usize = 8 /*index*/ + 8 /*generation*/ + 4 /*blob len*/ + 4 /*crc32c*/
+    + 1 /*flags*/ + 7 /*reserved*/ + 32 /*chain hash*/;
+
+/// rkyv archives are read in place, so blobs start at multiples of this inside a segment.
+const FRAME_ALIGN: usize = 16;
+
+/// Blob length rounded up so the frame after it stays aligned.
+fn padded_len(blob_len: u32) -> usize {
+    (blob_len as usize).next_multiple_of(FRAME_ALIGN)
+}
+
+/// Decoded [`FRAME_HEADER_LEN`] bytes in front of every blob.
+struct FrameHeader {
+    index: u64,
+    generation: u64,
+    blob_len: u32,
+    crc: u32,
+    /// See [`compression::COMPRESSED`] and [`encryption::ENCRYPTED`].
+    flags: u8,
+    /// See [`chain_hash`].
+    hash: ChainHash,
+}
+
+impl FrameHeader {
+    fn from_le_bytes(hdr: &[u8; FRAME_HEADER_LEN]) -> Self {
+        Self {
+            index: u64::from_le_bytes(hdr[0..8].try_into().expect("Issue with index")),
+            generation: u64::from_le_bytes(hdr[8..16].try_into().expect("Issue with generation")),
+            blob_len: u32::from_le_bytes(hdr[16..20].try_into().expect("Issue with blob lenght")),
+            crc: u32::from_le_bytes(hdr[20..24].try_into().expect("Issue with checksum")),
+            flags: hdr[24],
+            hash: hdr[32..64].try_into().expect("Issue with chain hash"),
+        }
+    }
+
+    fn is_compressed(&self) -> bool {
+        self.flags & compression::COMPRESSED != 0
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.flags & encryption::ENCRYPTED != 0
+    }
+
+    /// The cipher to open the blob with, `None` if it is stored in plain. Once a key is
+    /// configured every frame must be encrypted: whoever can write the file could slip plain
+    /// entries in otherwise.
+    fn cipher<'c>(&self, cipher: Option<&'c Cipher>, offset: u64) -> WalResult<Option<&'c Cipher>> {
+        match (self.is_encrypted(), cipher) {
+            (true, None) => Err(WalError::MissingKey { offset }),
+            (false, Some(_)) => Err(WalError::NotEncrypted { offset }),
+            (true, cipher) => Ok(cipher),
+            (false, None) => Ok(None),
+        }
+    }
+
+    /// Rejects flags this version does not know, once the checksum says they were written so.
+    fn check_flags(&self, offset: u64) -> WalResult<()> {
+        if self.flags & !(compression::COMPRESSED | encryption::ENCRYPTED) != 0 {
+            return Err(WalError::CorruptFrame {
+                offset,
+                reason: "unknown frame flags",
+            });
+        }
+        Ok(())
+    }
+
+    /// Header plus padded blob
+    fn frame_len(&self) -> u64 {
+        (FRAME_HEADER_LEN + padded_len(self.blob_len)) as u64
+    }
+}
+
+/// CRC32C over the header (everything but the checksum itself) and the blob, not the padding.
+///
+/// Covering the header too means a flipped bit in the index or in the blob length is caught
+/// before we trust it.
+fn frame_checksum(header: &[u8; FRAME_HEADER_LEN], blob: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(&header[0..20]);
+    let crc = crc32c::crc32c_append(crc, &header[24..]);
+    crc32c::crc32c_append(crc, blob)
+}
+
+/// Whether the bytes where a frame header would start, as many as the file holds up to one
+/// header, are all zeros: space preallocated or cleared past the last frame, the end of the
+/// log. A real header never is, its checksum would not match.
+fn is_blank(header: &[u8]) -> bool {
+    header.iter().all(|&b| b == 0)
+}
+
+/// A blake3 hash linking a frame to all the frames before it.
+pub type ChainHash = [u8; 32];
+
+/// What the first frame of a log chains from.
+const CHAIN_START: ChainHash = [0; 32];
+
+/// Hash of a frame: blake3 of the hash of the previous frame, the index, the generation and
+/// the blob as stored, i.e. compressed or encrypted. Like in an audit log, editing, dropping
+/// or reordering any frame changes the hash of every frame after it, and the chain can be
+/// checked without the key.
+fn chain_hash(prev: &ChainHash, index: u64, generation: u64, blob: &[u8]) -> ChainHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(prev);
+    hasher.update(&index.to_le_bytes());
+    hasher.update(&generation.to_le_bytes());
+    hasher.update(blob);
+    hasher.finalize().into()
+}
+
+/// What a WAL can store: any type rkyv can archive, validate and read back.
+///
+/// Implemented for every such type, so each log gets its own entry type with a plain
+/// `#[derive(Archive, Serialize, Deserialize)]`: Raft entries, 2PC records, or [`WalEntry`]
+/// for the [`KVStore`](crate::KVStore).
+pub trait LogEntry:
+    Archive<
+        Archived: Portable
+                      + 'static
+                      + for<'a> CheckBytes<HighValidator<'a, Failure>>
+                      + Deserialize<Self, HighDeserializer<Error>>,
+    > + for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>
+    + Sized
+{
+}
+
+impl<T> LogEntry for T where
+    T: Archive<
+            Archived: Portable
+                          + 'static
+                          + for<'a> CheckBytes<HighValidator<'a, Failure>>
+                          + Deserialize<T, HighDeserializer<Error>>,
+        > + for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>
+{
+}
+
+fn serialize<T: LogEntry>(entry: &T) -> WalResult<AlignedVec> {
+    Ok(rkyv::to_bytes::<Error>(entry)?)
+}
+
+fn deserialize<T: LogEntry>(bytes: &[u8]) -> WalResult<T> {
+    // This is not too efficient since we are deserializing and thus copying data
+    // We Should pass around the archived reference
+    Ok(rkyv::deserialize::<T, Error>(zero_copy::<T>(bytes)?)?)
+}
+
+fn zero_copy<T: LogEntry>(bytes: &[u8]) -> WalResult<&T::Archived> {
+    Ok(rkyv::access::<T::Archived, Failure>(bytes)?)
+}
+
+/// Commands of the [`KVStore`](crate::KVStore), the default entry type of the WALs here.
+#[derive(Archive, Deserialize, Serialize, serde::Serialize, serde::Deserialize, Debug)]
+pub enum WalEntry {
+    Set(String, String),
+    Batch(HashMap<String, String>),
+}
+
 // Contains the binary file data and some useful metadata.
-pub struct WalFrame {
+pub struct WalFrame<T = WalEntry, C = Rkyv> {
     pub index: u64,
     pub generation: u64,
     pub buf: Vec<u8>,
+    entry: PhantomData<fn() -> (T, C)>,
+}
+
+impl<T, C> WalFrame<T, C> {
+    fn new(index: u64, generation: u64, buf: Vec<u8>) -> Self {
+        Self {
+            index,
+            generation,
+            buf,
+            entry: PhantomData,
+        }
+    }
+}
+
+impl<T: LogEntry> WalFrame<T, Rkyv> {
+    pub fn zero_copy(&self) -> WalResult<&T::Archived> {
+        zero_copy::<T>(&self.buf)
+    }
 }
 
-impl WalFrame {
-    pub fn zero_copy(&self) -> WalResult<&ArchivedWalEntry> {
-        WalEntry::zero_copy(&self.buf)
+impl<T, C: Codec<T>> WalFrame<T, C> {
+    /// An owned copy of the entry. With [`Rkyv`], borrowing it through [`WalFrame::zero_copy`]
+    /// is cheaper.
+    pub fn deserialize(&self) -> WalResult<T> {
+        C::decode(&self.buf)
     }
 }
@@ -0,0 +1,202 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::codec::CodecId;
+use super::segmented_log::read_full_at;
+use super::storage::StorageFile;
+use super::{ChainHash, WalError, WalResult};
+
+/// Identifies a segment file written by [`SegmentedWal`](super::segmented_log::SegmentedWal).
+const MAGIC: [u8; 8] = *b"PDSWAL\r\n";
+/// Bumped whenever the file or frame layout changes in a way older code can't read.
+pub(crate) const FORMAT_VERSION: u16 = 2;
+
+/// Fixed size so frames start right after it. A multiple of 16 to keep blobs aligned.
+pub(crate) const SEGMENT_HEADER_LEN: usize = 80;
+/// Where the crc32c of the header starts, it covers everything before.
+const CRC_AT: usize = SEGMENT_HEADER_LEN - 4;
+
+/// How frames are checksummed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Crc32c = 1,
+}
+
+/// Compression of the segment as a whole, none supported. Frames are compressed one by one
+/// instead, see [`Compression`](super::Compression).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentCompression {
+    None = 0,
+}
+
+/// First bytes of every segment file:
+///
+///┌─────────┬─────────┬──────────┬─────────────┬───────────┬─────────────┬────────────┬────────┬──────────┬────────────┬─────────┐
+///│ 8-byte  │ 2-byte  │ 1-byte   │ 1-byte      │ 4-byte    │ 8-byte      │ 8-byte     │ 1-byte │ 11-byte  │ 32-byte    │ 4-byte  │
+///│ magic   │ version │ checksum │ compression │ flags     │ start index │ created ms │ codec  │ reserved │ chain hash │ crc32c  │
+///└─────────┴─────────┴──────────┴─────────────┴───────────┴─────────────┴────────────┴────────┴──────────┴────────────┴─────────┘
+///
+/// The crc32c covers everything before it. Anything we do not recognise makes opening fail
+/// rather than guessing, so a newer or foreign file is never misparsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SegmentHeader {
+    pub(crate) version: u16,
+    pub(crate) checksum: ChecksumAlgorithm,
+    pub(crate) compression: SegmentCompression,
+    /// Feature bits, none defined yet.
+    pub(crate) flags: u32,
+    /// Index of the first entry in the segment.
+    pub(crate) start_index: u64,
+    pub(crate) created_at: SystemTime,
+    /// How the frame blobs are encoded, see [`Codec`](super::codec::Codec).
+    pub(crate) codec: CodecId,
+    /// Hash of the frame right before `start_index`, the one the first frame here chains
+    /// from. Kept so the chain can be verified from here once older segments are gone.
+    pub(crate) prev_hash: ChainHash,
+}
+
+impl SegmentHeader {
+    pub(crate) fn new(start_index: u64, codec: CodecId, prev_hash: ChainHash) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            checksum: ChecksumAlgorithm::Crc32c,
+            compression: SegmentCompression::None,
+            flags: 0,
+            start_index,
+            created_at: SystemTime::now(),
+            codec,
+            prev_hash,
+        }
+    }
+
+    pub(crate) fn to_le_bytes(&self) -> [u8; SEGMENT_HEADER_LEN] {
+        let created_ms = self
+            .created_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut buf = [0u8; SEGMENT_HEADER_LEN];
+        buf[0..8].copy_from_slice(&MAGIC);
+        buf[8..10].copy_from_slice(&self.version.to_le_bytes());
+        buf[10] = self.checksum as u8;
+        buf[11] = self.compression as u8;
+        buf[12..16].copy_from_slice(&self.flags.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.start_index.to_le_bytes());
+        buf[24..32].copy_from_slice(&created_ms.to_le_bytes());
+        buf[32] = self.codec as u8;
+        buf[44..CRC_AT].copy_from_slice(&self.prev_hash);
+        let crc = crc32c::crc32c(&buf[..CRC_AT]);
+        buf[CRC_AT..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decodes the header of `segment`, failing on anything this version can't read.
+    pub(crate) fn from_le_bytes(bytes: &[u8], segment: &Path) -> WalResult<Self> {
+        let invalid = |reason: String| WalError::InvalidSegmentHeader {
+            segment: segment.into(),
+            reason,
+        };
+        let Some(buf) = bytes.first_chunk::<SEGMENT_HEADER_LEN>() else {
+            return Err(invalid(format!(
+                "{} bytes is too short for a segment header",
+                bytes.len()
+            )));
+        };
+        if buf[0..8] != MAGIC {
+            return Err(invalid("bad magic bytes, not a WAL segment".into()));
+        }
+        let version = u16::from_le_bytes(buf[8..10].try_into().expect("Issue with version"));
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!(
+                "unsupported format version {version}, expected {FORMAT_VERSION}"
+            )));
+        }
+        let crc = u32::from_le_bytes(buf[CRC_AT..].try_into().expect("Issue with checksum"));
+        if crc32c::crc32c(&buf[..CRC_AT]) != crc {
+            return Err(invalid("header checksum mismatch".into()));
+        }
+        let checksum = match buf[10] {
+            1 => ChecksumAlgorithm::Crc32c,
+            other => return Err(invalid(format!("unknown checksum algorithm {other}"))),
+        };
+        let compression = match buf[11] {
+            0 => SegmentCompression::None,
+            other => return Err(invalid(format!("unknown compression {other}"))),
+        };
+        let flags = u32::from_le_bytes(buf[12..16].try_into().expect("Issue with flags"));
+        if flags != 0 {
+            return Err(invalid(format!("unknown flags {flags:#x}")));
+        }
+        let Some(codec) = CodecId::from_u8(buf[32]) else {
+            return Err(invalid(format!("unknown codec {}", buf[32])));
+        };
+        let start_index = u64::from_le_bytes(buf[16..24].try_into().expect("Issue with index"));
+        let created_ms = u64::from_le_bytes(buf[24..32].try_into().expect("Issue with timestamp"));
+        Ok(Self {
+            version,
+            checksum,
+            compression,
+            flags,
+            start_index,
+            created_at: UNIX_EPOCH + Duration::from_millis(created_ms),
+            codec,
+            prev_hash: buf[44..CRC_AT].try_into().expect("Issue with chain hash"),
+        })
+    }
+
+    /// Reads and decodes the header at the start of `file`.
+    pub(crate) fn read(file: &dyn StorageFile, segment: &Path) -> WalResult<Self> {
+        let mut buf = [0u8; SEGMENT_HEADER_LEN];
+        let read = read_full_at(file, &mut buf, 0)?;
+        Self::from_le_bytes(&buf[..read], segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(bytes: &[u8]) -> String {
+        match SegmentHeader::from_le_bytes(bytes, Path::new("wal_1.log")) {
+            Err(WalError::InvalidSegmentHeader { reason, .. }) => reason,
+            other => panic!("expected an invalid header, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let header = SegmentHeader::new(42, CodecId::Json, [7; 32]);
+        let decoded = SegmentHeader::from_le_bytes(&header.to_le_bytes(), Path::new("")).expect("");
+        assert_eq!(decoded.start_index, 42);
+        assert_eq!(decoded.prev_hash, [7; 32]);
+        assert_eq!(decoded.version, FORMAT_VERSION);
+        assert_eq!(decoded.codec, CodecId::Json);
+        // Stored with millisecond precision
+        let drift = header
+            .created_at
+            .duration_since(decoded.created_at)
+            .expect("");
+        assert!(drift < Duration::from_millis(1));
+    }
+
+    #[test]
+    fn rejects_what_it_does_not_understand() {
+        // A simple_wal file starts with a 4-byte length
+        let mut simple_wal = 12u32.to_le_bytes().to_vec();
+        simple_wal.resize(128, 7);
+        assert_eq!(rejected(&simple_wal), "bad magic bytes, not a WAL segment");
+
+        let mut newer = SegmentHeader::new(1, CodecId::Rkyv, [0; 32]).to_le_bytes();
+        newer[8..10].copy_from_slice(&3u16.to_le_bytes());
+        assert_eq!(rejected(&newer), "unsupported format version 3, expected 2");
+
+        let mut flipped = SegmentHeader::new(1, CodecId::Rkyv, [0; 32]).to_le_bytes();
+        flipped[20] ^= 0x01;
+        assert_eq!(rejected(&flipped), "header checksum mismatch");
+
+        assert_eq!(
+            rejected(&MAGIC),
+            "8 bytes is too short for a segment header"
+        );
+    }
+}
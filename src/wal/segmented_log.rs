@@ -1,270 +1,3025 @@
-#![allow(dead_code, unused, unused_imports)]
-use glob::glob;
-use rkyv::{access, rancor::Failure};
-use rkyv::{deserialize, rancor::Error, Archive, Deserialize, Serialize};
-use std::io::{self, IoSlice, Write};
-use std::io::{Read, Seek, SeekFrom};
+use std::cmp::Ordering;
+use std::io::{self, IoSlice};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::process::abort;
-use std::{collections::HashMap, fs::File};
-use std::{fs, mem};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 
-use super::simple_wal::WriteAheadLog;
-use super::{WalEntry, WalEntryWithHeader, WalError, WalFrame, WalResult};
+use super::background::BackgroundTask;
+use super::compression;
+use super::durability::Syncer;
+use super::log;
+use super::manifest::Manifest;
+use super::mapped::{MappedFrame, MappedSegment};
+use super::segment_header::{SegmentHeader, SEGMENT_HEADER_LEN};
+use super::storage::{FileSystem, Storage, StorageFile};
+use super::{
+    frame_checksum, is_blank, ChainHash, Cipher, Codec, CodecId, Compression, CompressionStats,
+    DurabilityPolicy, FrameHeader, FrameScratch, KeyProvider, LogEntry, Rkyv, WalEntry,
+    WalEntryWithHeader, WalError, WalFrame, WalResult, WriteAheadLog, CHAIN_START,
+    FRAME_HEADER_LEN, ZEROS,
+};
 
 const GENERATION: u64 = 0;
-const HEADER_LEN: usize = 8 /*index*/ + 8 /*generation*/ + 4 /*blob len*/;
+const HEADER_LEN: usize = FRAME_HEADER_LEN;
+/// Byte offset of the first frame of a segment, right after its [`SegmentHeader`].
+pub(super) const FRAMES_START: u64 = SEGMENT_HEADER_LEN as u64;
+/// Bytes of log between two entries of a segment's [`SparseIndex`].
+const SPARSE_INDEX_INTERVAL: u64 = 4 * 1024;
+/// Size at which segments roll unless [`WALConfig::max_log_size`] says otherwise.
+pub const DEFAULT_MAX_LOG_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Describes the bytes dropped from the end of the open segment while recovering from a crash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TornTail {
+    /// Segment file that was truncated.
+    pub segment: PathBuf,
+    /// Log index of the first frame that was dropped.
+    pub index: u64,
+    /// Byte offset the segment was truncated to, i.e. the end of the last valid frame.
+    pub offset: u64,
+    /// Number of bytes removed from the segment.
+    pub dropped_bytes: u64,
+}
+
+/// Remembers at which byte offset some of the frames of a segment start, one every
+/// [`SPARSE_INDEX_INTERVAL`] bytes, so finding an index only scans a few headers.
+#[derive(Debug)]
+struct SparseIndex {
+    /// (log index, byte offset), both ascending
+    entries: Vec<(u64, u64)>,
+    /// Every frame before this offset has been looked at
+    indexed_until: u64,
+    /// (log index, generation) of the last frame looked at
+    last: Option<(u64, u64)>,
+    /// Chain hash of that frame
+    last_hash: Option<ChainHash>,
+}
+
+impl Default for SparseIndex {
+    fn default() -> Self {
+        Self {
+            entries: vec![],
+            indexed_until: FRAMES_START,
+            last: None,
+            last_hash: None,
+        }
+    }
+}
+
+impl SparseIndex {
+    fn observe(&mut self, header: &FrameHeader, offset: u64) {
+        // Only frames seen in order count, e.g. not those of a reader starting mid-segment
+        if offset != self.indexed_until {
+            return;
+        }
+        let due = self
+            .entries
+            .last()
+            .is_none_or(|&(_, last)| offset - last >= SPARSE_INDEX_INTERVAL);
+        if due {
+            self.entries.push((header.index, offset));
+        }
+        self.last = Some((header.index, header.generation));
+        self.last_hash = Some(header.hash);
+        self.indexed_until = offset + header.frame_len();
+    }
+
+    /// Offset of the closest indexed frame at or before `index`.
+    fn floor(&self, index: u64) -> u64 {
+        match self.entries.partition_point(|&(i, _)| i <= index) {
+            0 => FRAMES_START,
+            n => self.entries[n - 1].1,
+        }
+    }
+}
 
 #[derive(Debug)]
-struct WalSegment {
-    start_index: u64,
-    file: File,
+pub(super) struct WalSegment {
+    pub(super) start_index: u64,
+    pub(super) path: PathBuf,
+    pub(super) file: Box<dyn StorageFile>,
+    pub(super) header: SegmentHeader,
+    /// Byte offset of the next frame `read_next` will return.
+    pub(super) read_offset: u64,
+    /// Byte offset the next frame is written at, right after the last one. Only kept up to date
+    /// for the open segment, once it was recovered or cut.
+    write_offset: u64,
+    /// From this offset on the file reads as zeros, as far as the writer knows. Before it,
+    /// whatever follows the last frame may be anything, e.g. what a recycled file held.
+    zeroed_from: u64,
+    sparse_index: SparseIndex,
 }
 
 impl WalSegment {
-    fn new(prefix: &str, start_index: u64) -> WalResult<Self> {
-        let path = Self::file_name(prefix, start_index);
+    /// Creates the segment starting at `start_index` and writes its [`SegmentHeader`].
+    /// `prev_hash` is the chain hash of the frame right before, see [`SegmentedWal::verify_chain`].
+    ///
+    /// Atomic: the file is prepared under a temporary name, synced, then renamed and the
+    /// directory synced. Its name only ever holds a complete segment, which is durable once
+    /// this returns. The file is a `recycled` one if given, written over in place, and grown
+    /// to `preallocate` bytes, see [`WALConfig::preallocate`].
+    fn new(
+        storage: &dyn Storage,
+        dir: &Path,
+        start_index: u64,
+        codec: CodecId,
+        prev_hash: ChainHash,
+        recycled: Option<PathBuf>,
+        preallocate: u64,
+    ) -> WalResult<Self> {
+        let path = Self::file_path(dir, start_index);
+        let tmp = Self::tmp_path(dir, start_index);
+        if let Some(recycled) = recycled {
+            storage.rename(&recycled, &tmp)?;
+        }
+        let file = storage.open(&tmp, true)?;
+        let header = SegmentHeader::new(start_index, codec, prev_hash);
+        let mut bytes = header.to_le_bytes().to_vec();
+        let len = file.len()?;
+        if len > FRAMES_START {
+            // What the file held must not pass for a first frame
+            bytes.resize(
+                bytes.len() + HEADER_LEN.min((len - FRAMES_START) as usize),
+                0,
+            );
+        }
+        file.write_at(&bytes, 0)?;
+        file.allocate(preallocate)?;
+        file.sync()?;
+        storage.rename(&tmp, &path)?;
+        sync_parent_dir(storage, &path)?;
+        Ok(Self {
+            file,
+            path,
+            header,
+            start_index,
+            read_offset: FRAMES_START,
+            write_offset: FRAMES_START,
+            zeroed_from: len.max(FRAMES_START),
+            sparse_index: SparseIndex::default(),
+        })
+    }
+
+    /// Opens an existing segment the manifest says starts at `start_index`, refusing files
+    /// whose header we do not understand or whose frames are not encoded with `codec`.
+    fn open(
+        storage: &dyn Storage,
+        dir: &Path,
+        start_index: u64,
+        codec: CodecId,
+    ) -> WalResult<Self> {
+        let path = Self::file_path(dir, start_index);
+        if !storage.exists(&path) {
+            return Err(WalError::MissingSegment { segment: path });
+        }
+        let file = storage.open(&path, false)?;
+        let header = SegmentHeader::read(&*file, &path)?;
+        if header.start_index != start_index {
+            return Err(WalError::InvalidSegmentHeader {
+                segment: path,
+                reason: format!(
+                    "header says the segment starts at index {} but the manifest says {start_index}",
+                    header.start_index
+                ),
+            });
+        }
+        if header.codec != codec {
+            return Err(WalError::InvalidSegmentHeader {
+                segment: path,
+                reason: format!("frames are encoded with {:?}, not {codec:?}", header.codec),
+            });
+        }
         Ok(Self {
-            file: open_file(&path, false)?,
             start_index,
+            file,
+            path,
+            header,
+            read_offset: FRAMES_START,
+            write_offset: FRAMES_START,
+            zeroed_from: FRAMES_START,
+            sparse_index: SparseIndex::default(),
         })
     }
 
-    fn open(path: &str) -> WalResult<Self> {
-        let start_index = Self::start_offset_from_file_name(path)?;
+    /// Opens a segment for reading only, starting at `offset`. Used by readers that live next
+    /// to the writer.
+    fn open_reader(
+        storage: &dyn Storage,
+        path: &Path,
+        start_index: u64,
+        offset: u64,
+    ) -> WalResult<Self> {
+        let file = storage.open(path, false)?;
         Ok(Self {
             start_index,
-            file: open_file(path, false)?,
+            header: SegmentHeader::read(&*file, path)?,
+            file,
+            path: path.into(),
+            read_offset: offset,
+            write_offset: FRAMES_START,
+            zeroed_from: FRAMES_START,
+            sparse_index: SparseIndex::default(),
         })
     }
 
-    fn file_name(prefix: &str, start_index: u64) -> String {
-        format!("{}_{}{}", prefix, start_index, ".log")
+    /// Opens the segment file at `path` for reading only, whatever the index it starts at, e.g.
+    /// to inspect it outside of any [`SegmentedWal`].
+    pub(super) fn open_file(storage: &dyn Storage, path: &Path) -> WalResult<Self> {
+        let file = storage.open(path, false)?;
+        let header = SegmentHeader::read(&*file, path)?;
+        Ok(Self {
+            start_index: header.start_index,
+            header,
+            file,
+            path: path.into(),
+            read_offset: FRAMES_START,
+            write_offset: FRAMES_START,
+            zeroed_from: FRAMES_START,
+            sparse_index: SparseIndex::default(),
+        })
     }
 
-    fn start_offset_from_file_name(file_name: &str) -> WalResult<u64> {
-        let s = file_name.split("_").last();
-        match s {
-            None => Err(WalError::ShouldNotHappen),
-            Some(idx) => idx.parse().map_err(|_| WalError::ShouldNotHappen),
-        }
+    /// Zero padded so segments also sort by name.
+    pub(super) fn file_path(dir: &Path, start_index: u64) -> PathBuf {
+        dir.join(format!("{start_index:020}.log"))
     }
 
-    fn size(&self) -> WalResult<u64> {
-        Ok(self.file.metadata()?.len())
+    /// Where [`WalSegment::new`] prepares the segment. Whatever a crash leaves there is
+    /// removed on open.
+    fn tmp_path(dir: &Path, start_index: u64) -> PathBuf {
+        dir.join(format!("{start_index:020}.log.tmp"))
     }
 
-    fn flush(&mut self) -> WalResult<()> {
-        Ok(self.file.flush()?)
+    pub(super) fn size(&self) -> WalResult<u64> {
+        Ok(self.file.len()?)
     }
 
-    /// Writes to a log file with the following structure
+    fn rewind(&mut self) {
+        self.read_offset = FRAMES_START;
+    }
+
+    /// Grows the file to `len` bytes of zeros if it is shorter, see [`WALConfig::preallocate`].
+    fn preallocate(&mut self, len: u64) -> WalResult<()> {
+        if self.file.len()? < len {
+            self.file.allocate(len)?;
+            // Once, so syncing the frames written into it later has no size to update
+            self.file.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Writes to a log file with the following structure, after the [`SegmentHeader`]
     ///
-    ///┌───────────┬────────────┬───────────┬───────────┐
-    ///│ 8-byte =  │ 8-byte =   │ 4-byte =  │ N bytes   │ …
-    ///│ log index │ generation │ blob size │ 〈blob〉  │
-    ///└───────────┴────────────┴───────────┴───────────┘
+    ///┌───────────┬────────────┬───────────┬───────────┬──────────┬───────────┬────────────┬───────────┬─────────┐
+    ///│ 8-byte =  │ 8-byte =   │ 4-byte =  │ 4-byte =  │ 1-byte = │ 7-byte =  │ 32-byte =  │ N bytes   │ padding │ …
+    ///│ log index │ generation │ blob size │ crc32c    │ flags    │ reserved  │ chain hash │ 〈blob〉  │ to 16   │
+    ///└───────────┴────────────┴───────────┴───────────┴──────────┴───────────┴────────────┴───────────┴─────────┘
     ///
-    /// It's not calling flush() constantly since we are not using a BufWriter as of now.
-    fn write_entry(&mut self, entry: WalEntryWithHeader) -> WalResult<()> {
-        let bytes = entry.to_le_bytes()?;
-        self.file.write_all(&bytes)?;
-        Ok(())
+    /// The crc32c covers the rest of the header and the blob. The chain hash links the frame to
+    /// the previous one, `chain` being the hash of the previous frame on entry and of this one
+    /// on return. Blobs are padded so every frame
+    /// starts 16 bytes aligned and archives can be read in place, see [`super::mapped`]. A
+    /// blob flagged as compressed is LZ4, see [`Compression`]. One flagged as encrypted is
+    /// XChaCha20-Poly1305 followed by its tag and nonce salt, see [`KeyProvider`].
+    ///
+    /// Frames are written at the write offset rather than appended, so they can go into
+    /// preallocated space. When what follows may not be zeros, a blank header goes right after
+    /// the frame in the same write, see [`is_blank`]: the log ends there until the next frame
+    /// overwrites it.
+    ///
+    /// The frame is encoded in `scratch` and written from there, header, blob and padding in
+    /// one vectored write, so nothing is allocated nor copied on the way.
+    fn write_entry<T, C: Codec<T>>(
+        &mut self,
+        entry: WalEntryWithHeader<T>,
+        scratch: &mut FrameScratch,
+        compression: Compression,
+        cipher: Option<&Cipher>,
+        chain: &mut ChainHash,
+        stats: &mut CompressionStats,
+    ) -> WalResult<u64> {
+        let mut next = *chain;
+        let frame = entry.encode::<C>(scratch, compression, cipher, &mut next)?;
+        let frame_len = frame.len();
+        let frame_end = self.write_offset + frame_len;
+        let blank = match frame_end < self.zeroed_from {
+            true => HEADER_LEN.min((self.zeroed_from - frame_end) as usize),
+            false => 0,
+        };
+        let mut bufs = [
+            IoSlice::new(&frame.header),
+            IoSlice::new(frame.blob),
+            IoSlice::new(frame.padding()),
+            IoSlice::new(&ZEROS[..blank]),
+        ];
+        let written = self.file.write_vectored_at(&mut bufs, self.write_offset);
+        // Even a failed write may have landed in part, the next frame must end the log itself
+        self.zeroed_from = self.zeroed_from.max(frame_end + blank as u64);
+        written?;
+        self.write_offset = frame_end;
+        *chain = next;
+        stats.record(frame.raw_len, frame.stored_len);
+        Ok(frame_len)
     }
 
     /// This reads the individual bytes from file but returns a wrapper around the zero copy data
-    fn read_next(&mut self) -> WalResult<Option<WalFrame>> {
+    ///
+    /// A blank header ends the segment like the end of the file, see [`is_blank`]. A frame that
+    /// is cut short is reported as [`WalError::TruncatedFrame`], one whose checksum does not
+    /// match as [`WalError::CorruptFrame`]. The read position is left at its start.
+    /// An encrypted frame that `cipher` can't authenticate is [`WalError::AuthenticationFailed`],
+    /// one in plain although there is a `cipher` [`WalError::NotEncrypted`].
+    pub(super) fn read_next<T, C>(
+        &mut self,
+        cipher: Option<&Cipher>,
+    ) -> WalResult<Option<WalFrame<T, C>>> {
+        let offset = self.read_offset;
+        let Some((header, mut buf)) = self.read_stored()? else {
+            return Ok(None);
+        };
+        if let Some(cipher) = header.cipher(cipher, offset)? {
+            let len = cipher.open(
+                header.index,
+                header.generation,
+                header.flags,
+                &mut buf,
+                offset,
+            )?;
+            buf.truncate(len);
+        }
+        if header.is_compressed() {
+            buf = compression::decompress(&buf, offset)?;
+        }
+
+        self.track(&header, offset)?;
+        self.read_offset = offset + header.frame_len();
+        Ok(Some(WalFrame::new(header.index, header.generation, buf)))
+    }
+
+    /// Like [`WalSegment::read_next`], but only checks the frame: its checksum, flags and
+    /// index. The blob is neither decrypted nor decompressed.
+    pub(super) fn check_next(&mut self) -> WalResult<Option<FrameHeader>> {
+        let offset = self.read_offset;
+        let Some((header, _)) = self.read_stored()? else {
+            return Ok(None);
+        };
+        self.track(&header, offset)?;
+        self.read_offset = offset + header.frame_len();
+        Ok(Some(header))
+    }
+
+    /// Reads the frame at the read position as stored, once its checksum and flags are checked.
+    fn read_stored(&self) -> WalResult<Option<(FrameHeader, Vec<u8>)>> {
+        let offset = self.read_offset;
+        let truncated = Err(WalError::TruncatedFrame { offset });
         let mut hdr = [0u8; HEADER_LEN];
 
-        let mut read = 0;
-        while read < HEADER_LEN {
-            let n = self.file.read(&mut hdr[read..])?;
-            if n == 0 {
-                // EOF before *any* header byte ⇒ log exhausted
-                if read == 0 {
-                    return Ok(None);
-                }
-                return Err(
-                    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated WAL header").into(),
-                );
-            }
-            read += n;
+        let read = read_full_at(&*self.file, &mut hdr, offset)?;
+        if is_blank(&hdr[..read]) {
+            // EOF before *any* header byte, or zeros past the last frame ⇒ log exhausted
+            return Ok(None);
+        }
+        if read < HEADER_LEN {
+            return truncated;
+        }
+        let header = FrameHeader::from_le_bytes(&hdr);
+
+        // Do not trust the length before the checksum says so: a garbage length must not make us
+        // allocate gigabytes.
+        if offset + header.frame_len() > self.size()? {
+            return truncated;
         }
 
-        let index = u64::from_le_bytes(hdr[0..8].try_into().expect("Issue with index"));
-        let generation = u64::from_le_bytes(hdr[8..16].try_into().expect("Issue with generation"));
-        let blob_len =
-            u32::from_le_bytes(hdr[16..20].try_into().expect("Issue with blob lenght")) as usize;
+        let mut buf = vec![0u8; header.blob_len as usize];
+        if read_full_at(&*self.file, &mut buf, offset + HEADER_LEN as u64)? < buf.len() {
+            return truncated;
+        }
+        if frame_checksum(&hdr, &buf) != header.crc {
+            return Err(WalError::CorruptFrame {
+                offset,
+                reason: "checksum mismatch",
+            });
+        }
+        header.check_flags(offset)?;
+        Ok(Some((header, buf)))
+    }
 
-        let mut buf = vec![0u8; blob_len];
-        if let Err(e) = self.file.read_exact(&mut buf) {
-            return Err(e.into());
+    /// Reads only the header of the frame at `offset`, if there is a complete frame there.
+    fn read_header_at(&self, offset: u64, size: u64) -> WalResult<Option<FrameHeader>> {
+        let mut hdr = [0u8; HEADER_LEN];
+        if read_full_at(&*self.file, &mut hdr, offset)? < HEADER_LEN || is_blank(&hdr) {
+            return Ok(None);
         }
-        Ok(Some(WalFrame {
-            buf,
-            generation,
-            index,
-        }))
+        let header = FrameHeader::from_le_bytes(&hdr);
+        Ok((offset + header.frame_len() <= size).then_some(header))
     }
-}
 
-impl Drop for WalSegment {
-    /// Safeguard against "safe" exits.
+    fn info(&self) -> SegmentInfo {
+        SegmentInfo {
+            path: self.path.clone(),
+            start_index: self.start_index,
+        }
+    }
+
+    /// Index the next frame appended to this segment must have.
+    fn next_index(&self) -> u64 {
+        self.sparse_index
+            .last
+            .map_or(self.start_index, |(index, _)| index + 1)
+    }
+
+    /// Decodes the first frame, if any, so a missing or wrong key fails on open rather than on
+    /// the first read.
+    fn check_key(&mut self, cipher: Option<&Cipher>) -> WalResult<()> {
+        self.rewind();
+        let first = self.read_next::<(), ()>(cipher);
+        self.rewind();
+        first.map(drop)
+    }
+
+    /// Chain hash of the last frame of the segment, the one the next frame chains from.
+    fn chain_head(&mut self) -> WalResult<ChainHash> {
+        self.index_tail()?;
+        Ok(self.sparse_index.last_hash.unwrap_or(self.header.prev_hash))
+    }
+
+    /// Adds a frame read in order to the sparse index, checking its index follows the previous.
+    fn track(&mut self, header: &FrameHeader, offset: u64) -> WalResult<()> {
+        if offset == self.sparse_index.indexed_until && header.index != self.next_index() {
+            return Err(WalError::NonContiguous {
+                segment: self.path.clone(),
+                expected: self.next_index(),
+                found: header.index,
+            });
+        }
+        self.sparse_index.observe(header, offset);
+        Ok(())
+    }
+
+    /// Indexes the headers of the frames appended since the last lookup.
+    fn index_tail(&mut self) -> WalResult<()> {
+        let size = self.size()?;
+        let mut offset = self.sparse_index.indexed_until;
+        while let Some(header) = self.read_header_at(offset, size)? {
+            self.track(&header, offset)?;
+            offset += header.frame_len();
+        }
+        Ok(())
+    }
+
+    /// Byte offset of the frame holding `index`, or of the end of the segment if it is not here.
     ///
-    /// Does not work in external kill signals like sigkill, oom, power loss, segfault...
-    fn drop(&mut self) {
-        if let Err(e) = self.file.flush() {
-            eprintln!("WAL: failed to flush on drop: {e}");
-            // TODO: return this as a critical error in the error stack
+    /// Starts scanning headers from the closest [`SparseIndex`] entry. Frames appended since
+    /// the last lookup are indexed first.
+    fn offset_of(&mut self, index: u64) -> WalResult<u64> {
+        self.index_tail()?;
+        let size = self.size()?;
+        let mut offset = self.sparse_index.floor(index);
+        while let Some(header) = self.read_header_at(offset, size)? {
+            if header.index >= index {
+                break;
+            }
+            offset += header.frame_len();
         }
+        Ok(offset)
+    }
+
+    /// Drops everything from `offset`, which must be a frame boundary, to the end.
+    pub(super) fn cut(&mut self, offset: u64) -> WalResult<()> {
+        self.file.truncate(offset)?;
+        self.file.sync()?;
+        self.write_offset = offset;
+        self.zeroed_from = offset;
+        // Rebuilt rather than trimmed, so we also learn which frame is now the last one
+        self.sparse_index = SparseIndex::default();
+        self.index_tail()?;
+        self.read_offset = self.read_offset.min(offset);
+        Ok(())
+    }
+
+    /// Scans the whole segment and truncates it right after the last valid frame.
+    ///
+    /// Only meant for the open segment: a crash can leave a half-written frame at its end, or
+    /// right after the last frame what a recycled file held, possibly an older frame. Returns
+    /// what was dropped, if anything. The read position is rewound afterwards, the write
+    /// position is at the end of the last frame.
+    ///
+    /// A whole frame whose index is past the next one is neither: entries are missing, that is
+    /// [`WalError::NonContiguous`] and nothing is truncated.
+    ///
+    /// Checksums tell where the frames written whole end, blobs are neither decrypted nor
+    /// decompressed: one that does not decode was written so, it is no torn write.
+    fn recover(&mut self) -> WalResult<Option<TornTail>> {
+        self.rewind();
+        let mut next_index = self.start_index;
+        let torn = loop {
+            let offset = self.read_offset;
+            match self.check_next() {
+                Ok(None) => break None,
+                Ok(Some(header)) => next_index = header.index + 1,
+                // Recycled files only hold older entries, see `RecyclePool`
+                Err(
+                    e @ WalError::NonContiguous {
+                        expected, found, ..
+                    },
+                ) if found > expected => {
+                    return Err(e);
+                }
+                Err(
+                    WalError::CorruptFrame { .. }
+                    | WalError::TruncatedFrame { .. }
+                    | WalError::NonContiguous { .. },
+                ) => {
+                    let size = self.size()?;
+                    self.file.truncate(offset)?;
+                    self.file.sync()?;
+                    break Some(TornTail {
+                        segment: self.path.clone(),
+                        index: next_index,
+                        offset,
+                        dropped_bytes: size - offset,
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        (self.write_offset, self.zeroed_from) = match &torn {
+            Some(torn) => (torn.offset, torn.offset),
+            None => (self.read_offset, self.size()?),
+        };
+        self.rewind();
+        Ok(torn)
     }
 }
 
-fn open_file(path: &str, truncate: bool) -> WalResult<File> {
-    let mut f_opts = File::options();
-    f_opts.read(true).write(true).create(true);
-    match truncate {
-        true => f_opts.truncate(true),
-        false => f_opts.append(true),
+/// Makes creating, renaming or deleting files in the directory holding `path` durable.
+fn sync_parent_dir(storage: &dyn Storage, path: &Path) -> WalResult<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
+    storage.sync_dir(dir)?;
+    Ok(())
+}
+
+/// Like `read_exact_at` but returns how many bytes were read when EOF comes first.
+pub(crate) fn read_full_at(
+    file: &dyn StorageFile,
+    buf: &mut [u8],
+    offset: u64,
+) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// A segment of the log as seen by a [`WalReader`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub path: PathBuf,
+    /// Index of the first entry in the segment.
+    pub start_index: u64,
+}
 
-    Ok(f_opts.open(path)?)
+/// Where a [`WalReader`] will read its next frame from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalPosition {
+    pub segment: SegmentInfo,
+    /// Byte offset of the next frame inside the segment.
+    pub offset: u64,
+    /// Index the next frame will have.
+    pub next_index: u64,
 }
-struct WalEntryIterator {
+
+/// Streams the frames of a range of the log in order, across segments, one frame in memory at
+/// a time. See [`SegmentedWal::read_range`].
+///
+/// Reads through its own file handles so the WAL it comes from can keep appending. Once it has
+/// caught up with the writer, `next` returns `None` without consuming the reader: calling it
+/// again later returns the frames written in the meantime, also from segments rolled since.
+/// It stops at the end of the last frame the writer finished, so one still being written is
+/// never mistaken for a corrupt one.
+#[derive(Debug)]
+pub struct WalReader<T = WalEntry, C = Rkyv> {
+    /// `WALConfig::path`, to find segments rolled after the reader was created.
+    dir: PathBuf,
+    storage: Arc<dyn Storage>,
+    /// Sorted descending so we will pop from the end
     segments: Vec<WalSegment>,
+    next_index: u64,
+    /// Exclusive
+    end: Option<u64>,
+    cipher: Option<Cipher>,
+    /// How far the writer got, see [`PublishedEnd`].
+    published: Arc<PublishedEnd>,
+    entry: PhantomData<fn() -> (T, C)>,
+}
+
+impl<T, C: Codec<T>> WalReader<T, C> {
+    pub fn read_next(&mut self) -> WalResult<Option<WalFrame<T, C>>> {
+        if self.end.is_some_and(|end| self.next_index >= end) {
+            return Ok(None);
+        }
+        loop {
+            let is_tail = self.segments.len() == 1;
+            let Some(segment) = self.segments.last_mut() else {
+                return Ok(None);
+            };
+            let read = match self.published.end_of(segment.start_index) {
+                // What follows may be a frame still being written
+                Some(end) if segment.read_offset >= end => Ok(None),
+                _ => segment.read_next(self.cipher.as_ref()),
+            };
+            match read {
+                // E.g. the file was recycled under a reader that started mid-segment
+                Ok(Some(frame)) if frame.index != self.next_index => {
+                    return Err(WalError::NonContiguous {
+                        segment: segment.path.clone(),
+                        expected: self.next_index,
+                        found: frame.index,
+                    });
+                }
+                Ok(Some(frame)) => {
+                    self.next_index = frame.index + 1;
+                    return Ok(Some(frame));
+                }
+                // If we arrive here it means we have reached the end of current segment. We
+                // should roll to the next one
+                Ok(None) if !is_tail => {
+                    self.segments.pop();
+                }
+                // The writer may have rolled since we were created. Go through the current
+                // segment once more before moving on: it could have been appended to right
+                // before the roll.
+                Ok(None) => match self.rolled_segment()? {
+                    Some(rolled) => self.segments.insert(0, rolled),
+                    None => return Ok(None),
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn rolled_segment(&self) -> WalResult<Option<WalSegment>> {
+        // Nothing was read from the current segment yet, it is the newest one
+        if self
+            .segments
+            .last()
+            .is_some_and(|s| s.start_index == self.next_index)
+        {
+            return Ok(None);
+        }
+        let path = WalSegment::file_path(&self.dir, self.next_index);
+        match self.storage.exists(&path) {
+            true => Ok(Some(WalSegment::open_reader(
+                &*self.storage,
+                &path,
+                self.next_index,
+                FRAMES_START,
+            )?)),
+            false => Ok(None),
+        }
+    }
+
+    /// Where the next frame will be read from, `None` once the reader went past every segment.
+    pub fn position(&self) -> Option<WalPosition> {
+        self.segments.last().map(|segment| WalPosition {
+            segment: segment.info(),
+            offset: segment.read_offset,
+            next_index: self.next_index,
+        })
+    }
+
+    /// Segments still to be read, the current one first.
+    pub fn segments(&self) -> Vec<SegmentInfo> {
+        self.segments.iter().rev().map(WalSegment::info).collect()
+    }
+}
+
+impl<T, C: Codec<T>> Iterator for WalReader<T, C> {
+    type Item = WalResult<WalFrame<T, C>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.read_next();
+        if next.is_err() {
+            // Do not keep on failing at the same frame
+            self.segments.clear();
+        }
+        next.transpose()
+    }
+}
+
+/// When the background cleaner drops the oldest sealed segments, regardless of any low-water
+/// mark. Kafka style time and size based retention.
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    /// Total size of the sealed segments to keep.
+    pub max_bytes: Option<u64>,
+    /// Sealed segments last written longer ago than this are dropped.
+    pub max_age: Option<Duration>,
+    /// How often the cleaner checks.
+    pub check_every: Duration,
 }
 
-impl WalEntryIterator {
-    pub fn new(path: &str) -> Self {
+impl Default for RetentionPolicy {
+    fn default() -> Self {
         Self {
-            segments: Self::open_segments(path).expect("Err"),
+            max_bytes: None,
+            max_age: None,
+            check_every: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    fn is_enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.max_age.is_some()
+    }
+
+    /// How many of the oldest `segments` are out of the policy.
+    fn expired(&self, segments: &[WalSegment]) -> WalResult<usize> {
+        let now = SystemTime::now();
+        let mut total = segments
+            .iter()
+            .map(WalSegment::size)
+            .sum::<WalResult<u64>>()?;
+        let mut expired = 0;
+        for segment in segments {
+            let too_big = self.max_bytes.is_some_and(|max| total > max);
+            let age = now
+                .duration_since(segment.file.modified()?)
+                .unwrap_or_default();
+            let too_old = self.max_age.is_some_and(|max| age > max);
+            if !too_big && !too_old {
+                break;
+            }
+            total -= segment.size()?;
+            expired += 1;
+        }
+        Ok(expired)
+    }
+}
+
+/// Rolled segments, oldest first. Shared with the retention cleaner.
+type SealedSegments = Arc<Mutex<Vec<WalSegment>>>;
+/// Last manifest stored. Locked after [`SealedSegments`] when both are needed.
+type SharedManifest = Arc<Mutex<Manifest>>;
+
+/// How far the writer got: the start index of the open segment and the end of its last
+/// frame. Shared with the [`WalReader`]s, which never read past it. Beyond, a frame may still
+/// be half written, in a file that can be long enough already, e.g. preallocated.
+#[derive(Debug, Default)]
+struct PublishedEnd(Mutex<(u64, u64)>);
+
+impl PublishedEnd {
+    /// Once the frames up to the write offset of `segment` are whole.
+    fn publish(&self, segment: &WalSegment) {
+        *self.0.lock().expect("WAL writer panicked") = (segment.start_index, segment.write_offset);
+    }
+
+    /// Where readers must stop in the segment starting at `start_index`, `None` if it is
+    /// sealed and its frames end like the file does.
+    fn end_of(&self, start_index: u64) -> Option<u64> {
+        let (open, end) = *self.0.lock().expect("WAL writer panicked");
+        match start_index.cmp(&open) {
+            Ordering::Less => None,
+            Ordering::Equal => Some(end),
+            // Rolled to but not written yet, or dropped by a truncation
+            Ordering::Greater => Some(FRAMES_START),
         }
     }
+}
+
+/// Stores a copy of the manifest with `change` applied, then makes it the current one.
+fn update_manifest(
+    storage: &dyn Storage,
+    dir: &Path,
+    manifest: &Mutex<Manifest>,
+    change: impl FnOnce(&mut Manifest),
+) -> WalResult<()> {
+    let mut current = manifest.lock().expect("WAL cleaner panicked");
+    let mut next = current.clone();
+    change(&mut next);
+    next.store(storage, dir)?;
+    *current = next;
+    Ok(())
+}
+
+/// Deletes the `n` oldest sealed segments, dropping them from the manifest first. Their files
+/// may be kept in `pool`.
+fn remove_oldest(
+    storage: &dyn Storage,
+    dir: &Path,
+    segments: &mut Vec<WalSegment>,
+    manifest: &Mutex<Manifest>,
+    pool: &RecyclePool,
+    n: usize,
+) -> WalResult<()> {
+    if n == 0 {
+        return Ok(());
+    }
+    update_manifest(storage, dir, manifest, |m| {
+        m.segments.drain(..n);
+    })?;
+    pool.retire(storage, segments.drain(..n))
+}
+
+/// Deletes the files of segments the manifest no longer lists. If this fails midway, the files
+/// left are removed the next time the WAL is opened.
+fn remove_segments(
+    storage: &dyn Storage,
+    segments: impl IntoIterator<Item = WalSegment>,
+) -> WalResult<()> {
+    for segment in segments {
+        storage
+            .remove(&segment.path)
+            .map_err(|source| WalError::Truncate {
+                segment: segment.path.clone(),
+                source,
+            })?;
+    }
+    Ok(())
+}
+
+/// Files of deleted segments kept to become the next segments rolled, see
+/// [`WALConfig::recycle`]. Shared with the retention cleaner.
+///
+/// Only segments whose entries are all older than any entry written later end up here: after
+/// a crash, a frame a recycled file still holds then never passes for the next one, its index
+/// is too low.
+#[derive(Debug)]
+struct RecyclePool {
+    capacity: usize,
+    files: Mutex<Vec<PathBuf>>,
+}
 
-    fn open_segments(path: &str) -> WalResult<Vec<WalSegment>> {
-        // TODO: accept a starting index
-        let open_segment =
-            |p: PathBuf| WalSegment::open(p.to_str().ok_or(WalError::ShouldNotHappen)?);
-        let mut segments = glob(&format!("{}_*.log", path))?
+impl RecyclePool {
+    /// Recycled files are named after the segment they last were.
+    const EXTENSION: &'static str = "recycled";
+
+    /// Picks up the files a previous run kept in `dir`, deleting those beyond `capacity`.
+    fn load(storage: &dyn Storage, dir: &Path, capacity: usize) -> WalResult<Self> {
+        let mut files: Vec<PathBuf> = storage
+            .list(dir)?
             .into_iter()
-            .map(|p| p.map(open_segment)?)
-            .collect::<WalResult<Vec<WalSegment>>>()?;
-        // Sort descending so we will pop from the end
-        segments.sort_by(|a, b| b.start_index.cmp(&a.start_index));
+            .filter(|path| path.extension().is_some_and(|ext| ext == Self::EXTENSION))
+            .collect();
+        files.sort();
+        for extra in files.drain(capacity.min(files.len())..) {
+            storage.remove(&extra)?;
+        }
+        Ok(Self {
+            capacity,
+            files: Mutex::new(files),
+        })
     }
 
-    pub fn read_next(&mut self) -> WalResult<Option<WalFrame>> {
-        match self.segments.last_mut() {
-            None => return Ok(None),
-            Some(segment) => {
-                let next = segment.read_next()?;
-                if next.is_some() {
-                    return Ok(next);
-                }
+    fn files(&self) -> MutexGuard<'_, Vec<PathBuf>> {
+        self.files.lock().expect("WAL cleaner panicked")
+    }
+
+    /// Keeps the files of `segments`, which the manifest no longer lists, while there is room.
+    /// The others are deleted.
+    fn retire(
+        &self,
+        storage: &dyn Storage,
+        segments: impl IntoIterator<Item = WalSegment>,
+    ) -> WalResult<()> {
+        let mut files = self.files();
+        for segment in segments {
+            if files.len() == self.capacity {
+                remove_segments(storage, [segment])?;
+                continue;
             }
+            let recycled = segment.path.with_extension(Self::EXTENSION);
+            storage
+                .rename(&segment.path, &recycled)
+                .map_err(|source| WalError::Truncate {
+                    segment: segment.path.clone(),
+                    source,
+                })?;
+            files.push(recycled);
+        }
+        Ok(())
+    }
+
+    /// A recycled file, if there is one left. It is the caller's from now on.
+    fn take(&self) -> Option<PathBuf> {
+        self.files().pop()
+    }
+}
+
+/// Deletes every segment file in `dir` that is not in `keep`, and any half written manifest or
+/// segment.
+fn remove_unlisted(storage: &dyn Storage, dir: &Path, keep: &[u64]) -> WalResult<()> {
+    let listed: Vec<PathBuf> = keep
+        .iter()
+        .map(|&start| WalSegment::file_path(dir, start))
+        .collect();
+    let mut removed = false;
+    for path in storage.list(dir)? {
+        let is_segment = path.extension().is_some_and(|ext| ext == "log");
+        // MANIFEST_TMP or a segment WalSegment::new did not finish
+        let is_tmp = path.extension().is_some_and(|ext| ext == "tmp");
+        if (is_segment && !listed.contains(&path)) || is_tmp {
+            eprintln!("WAL: removing {}, not in the manifest", path.display());
+            storage.remove(&path)?;
+            removed = true;
         }
-        // If we arrive here it means we have reached the end of current segment. We should
-        // roll to the next one
-        let _ = self.segments.pop();
-        self.read_next()
     }
+    if removed {
+        storage.sync_dir(dir)?;
+    }
+    Ok(())
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct WALConfig {
+    /// Directory owned by the WAL, holding the segments and the manifest.
     pub path: String,
     pub truncate: bool,
+    /// Index the log starts after, e.g. the last index covered by a snapshot.
+    /// The first entry written to an empty log gets `start_index + 1`.
     pub start_index: u64,
 
+    /// Size a segment reaches before the next write rolls to a new one,
+    /// [`DEFAULT_MAX_LOG_SIZE`] by default.
     pub max_log_size: u64,
+    /// Entries a segment holds before the next write rolls to a new one. No limit by default.
+    pub max_segment_entries: Option<u64>,
+    /// Time since a segment was created after which the next write rolls to a new one. No
+    /// limit by default. Only writes roll: an idle segment stays open however old.
+    pub max_segment_age: Option<Duration>,
+    /// Grows every segment to `max_log_size` bytes of zeros up front, with `fallocate` on Linux,
+    /// so appending never grows the file and syncing a frame has no file size to update. Off by
+    /// default.
+    pub preallocate: bool,
+    /// How many files of segments dropped by [`SegmentedWal::truncate_before`] or the
+    /// retention policy to keep, reused for the next segments rolled rather than creating new
+    /// ones. Those [`SegmentedWal::truncate_after`] drops never are. None by default.
+    ///
+    /// A reader still going through a segment when its file is reused fails.
+    pub recycle: usize,
+    pub durability: DurabilityPolicy,
+    pub retention: RetentionPolicy,
+    /// Compression of the blobs written from now on, none by default.
+    pub compression: Compression,
+    /// Key to encrypt the blobs written and decrypt those read, none by default. Every frame
+    /// read must then be encrypted, a plain one is [`WalError::NotEncrypted`].
+    pub encryption: Option<Arc<dyn KeyProvider>>,
+    /// Where the files live, the file system by default.
+    pub storage: Arc<dyn Storage>,
 }
 
+impl Default for WALConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            truncate: false,
+            start_index: 0,
+            max_log_size: DEFAULT_MAX_LOG_SIZE,
+            max_segment_entries: None,
+            max_segment_age: None,
+            preallocate: false,
+            recycle: 0,
+            durability: DurabilityPolicy::default(),
+            retention: RetentionPolicy::default(),
+            compression: Compression::default(),
+            encryption: None,
+            storage: Arc::new(FileSystem),
+        }
+    }
+}
+
+impl WALConfig {
+    /// Bytes every segment gets up front, see [`WALConfig::preallocate`].
+    fn preallocated_len(&self) -> u64 {
+        match self.preallocate {
+            true => self.max_log_size,
+            false => 0,
+        }
+    }
+}
+
+/// A log of `T` entries encoded with `C`, split into segment files. See [`Codec`].
 #[derive(Debug)]
-pub struct SegmentedWal {
+pub struct SegmentedWal<T = WalEntry, C = Rkyv> {
     open_segment: WalSegment,
-    segments: Vec<WalSegment>,
+    segments: SealedSegments,
+    manifest: SharedManifest,
+    recycled: Arc<RecyclePool>,
     last_log_index: u64,
+    /// Stamped on every entry written, see [`SegmentedWal::set_generation`].
+    generation: u64,
     cfg: WALConfig,
+    recovered: Option<TornTail>,
+    syncer: Syncer,
+    compression_stats: CompressionStats,
+    cipher: Option<Cipher>,
+    /// Hash of the last frame, see [`SegmentedWal::verify_chain`].
+    chain_head: ChainHash,
+    /// Where frames are encoded before they are written, reused by every append.
+    scratch: FrameScratch,
+    published: Arc<PublishedEnd>,
+    /// Never read, only held: dropping it stops the retention cleaner.
+    #[allow(dead_code)]
+    cleaner: Option<BackgroundTask>,
+    entry: PhantomData<fn(T) -> (T, C)>,
 }
 
-impl SegmentedWal {
+impl<T: LogEntry> SegmentedWal<T> {
     /// Opens a new R/W WAL
     ///
     pub fn open(cfg: WALConfig) -> WalResult<Self> {
-        if cfg.truncate {
-            let path = Path::new(&cfg.path);
-            for log in glob(&format!("{}_*.log", &cfg.path))? {
-                log.map(|p| fs::remove_file(p))?;
+        Self::open_with_codec(cfg, Rkyv)
+    }
+}
+
+impl<T, C: Codec<T>> SegmentedWal<T, C> {
+    /// Opens a R/W WAL whose entries are encoded with `codec`, e.g.
+    /// `SegmentedWal::open_with_codec(cfg, Json)`. The log must have been written with it.
+    pub fn open_with_codec(cfg: WALConfig, _codec: C) -> WalResult<Self> {
+        let dir = Path::new(&cfg.path);
+        let storage = &*cfg.storage;
+        if cfg.truncate && storage.exists(dir) {
+            // Manifest first, a crash midway then leaves only unlisted segments behind
+            match storage.remove(&Manifest::path(dir)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
             }
+            remove_unlisted(storage, dir, &[])?;
+        }
+        storage.create_dir(dir)?;
+        let manifest = match Manifest::load(storage, dir)? {
+            Some(manifest) => manifest,
+            None => Self::create(storage, dir, cfg.start_index + 1)?,
+        };
+        // Left behind by a crash while rolling or truncating
+        remove_unlisted(storage, dir, &manifest.segments)?;
+        let recycled = Arc::new(RecyclePool::load(storage, dir, cfg.recycle)?);
+
+        let mut segments = Self::open_segments(storage, dir, &manifest)?;
+        let mut open_segment = segments.pop().ok_or(WalError::ShouldNotHappen)?;
+        let cipher = match &cfg.encryption {
+            Some(provider) => Some(Cipher::new(&**provider)?),
+            None => None,
+        };
+        // Only the open segment can end with a half written frame, rolled ones were complete.
+        let recovered = open_segment.recover()?;
+        open_segment.check_key(cipher.as_ref())?;
+        if let Some(torn) = &recovered {
+            eprintln!(
+                "WAL: dropped torn tail of {}: index {} at offset {} ({} bytes)",
+                torn.segment.display(),
+                torn.index,
+                torn.offset,
+                torn.dropped_bytes
+            );
         }
-        let mut segments = SegmentedWal::open_segments(&cfg)?;
-        let open_segment = segments.pop().ok_or(WalError::ShouldNotHappen)?;
-        // TODO: read from last log index???
+        open_segment.preallocate(cfg.preallocated_len())?;
+        let (last_log_index, generation) = Self::verify_contiguous(&mut segments, &open_segment)?;
+        // A generation may have been set without anything written with it yet
+        let generation = generation.max(manifest.generation);
+        let chain_head = open_segment.chain_head()?;
+        let published = Arc::new(PublishedEnd::default());
+        published.publish(&open_segment);
+
+        let segments = Arc::new(Mutex::new(segments));
+        let manifest = Arc::new(Mutex::new(manifest));
+        let cleaner = match cfg.retention.is_enabled() {
+            true => Some(Self::spawn_cleaner(
+                cfg.retention,
+                Arc::clone(&cfg.storage),
+                dir.into(),
+                Arc::clone(&segments),
+                Arc::clone(&manifest),
+                Arc::clone(&recycled),
+            )?),
+            false => None,
+        };
         Ok(Self {
-            last_log_index: 0,
+            syncer: Syncer::new(cfg.durability, &*open_segment.file)?,
+            last_log_index,
+            generation,
             segments,
+            manifest,
+            recycled,
             open_segment,
             cfg,
+            recovered,
+            compression_stats: CompressionStats::default(),
+            cipher,
+            chain_head,
+            scratch: FrameScratch::default(),
+            published,
+            cleaner,
+            entry: PhantomData,
         })
     }
 
-    fn open_segments(cfg: &WALConfig) -> WalResult<Vec<WalSegment>> {
-        let open_segment =
-            |p: PathBuf| WalSegment::open(p.to_str().ok_or(WalError::ShouldNotHappen)?);
-        let segments: Vec<WalSegment> = glob(&format!("{}_*.log", &cfg.path))?
-            .into_iter()
-            .map(|p| p.map(open_segment)?)
-            .collect::<WalResult<Vec<WalSegment>>>()?;
+    /// Starts an empty log in `dir`: its first segment, then the manifest listing it.
+    fn create(storage: &dyn Storage, dir: &Path, start_index: u64) -> WalResult<Manifest> {
+        WalSegment::new(storage, dir, start_index, C::ID, CHAIN_START, None, 0)?;
+        let manifest = Manifest {
+            segments: vec![start_index],
+            low_water_mark: start_index,
+            generation: GENERATION,
+        };
+        manifest.store(storage, dir)?;
+        Ok(manifest)
+    }
 
-        Ok(match segments.is_empty() {
-            true => vec![WalSegment::new(&cfg.path, cfg.start_index)?],
-            false => segments,
-        })
+    fn spawn_cleaner(
+        retention: RetentionPolicy,
+        storage: Arc<dyn Storage>,
+        dir: PathBuf,
+        segments: SealedSegments,
+        manifest: SharedManifest,
+        pool: Arc<RecyclePool>,
+    ) -> WalResult<BackgroundTask> {
+        let task = BackgroundTask::spawn("wal-cleaner", retention.check_every, move || {
+            let mut segments = segments.lock().expect("WAL writer panicked");
+            if let Err(e) = retention
+                .expired(&segments)
+                .and_then(|n| remove_oldest(&*storage, &dir, &mut segments, &manifest, &pool, n))
+            {
+                eprintln!("WAL: retention cleaner failed: {e}");
+            }
+        })?;
+        Ok(task)
+    }
+
+    /// Indexes every sealed segment and checks that each one starts right after the previous
+    /// one ends. Returns the index and generation of the last entry in the log.
+    ///
+    /// The open segment must have been scanned already.
+    fn verify_contiguous(
+        sealed: &mut [WalSegment],
+        open_segment: &WalSegment,
+    ) -> WalResult<(u64, u64)> {
+        let mut last = None;
+        let mut next_index = None;
+        for segment in sealed.iter_mut() {
+            segment.index_tail()?;
+            last = segment.sparse_index.last.or(last);
+            next_index = Some(segment.next_index());
+        }
+        match next_index {
+            Some(expected) if expected != open_segment.start_index => {
+                return Err(WalError::NonContiguous {
+                    segment: open_segment.path.clone(),
+                    expected,
+                    found: open_segment.start_index,
+                });
+            }
+            _ => {}
+        }
+        for pair in sealed.windows(2) {
+            if pair[0].next_index() != pair[1].start_index {
+                return Err(WalError::NonContiguous {
+                    segment: pair[1].path.clone(),
+                    expected: pair[0].next_index(),
+                    found: pair[1].start_index,
+                });
+            }
+        }
+
+        let last = open_segment.sparse_index.last.or(last);
+        Ok(last.unwrap_or((open_segment.start_index - 1, GENERATION)))
+    }
+
+    fn sealed(&self) -> MutexGuard<'_, Vec<WalSegment>> {
+        self.segments.lock().expect("WAL cleaner panicked")
+    }
+
+    /// What crash recovery dropped from the open segment while opening, if anything.
+    pub fn recovered(&self) -> Option<&TornTail> {
+        self.recovered.as_ref()
+    }
+
+    /// Opens the segments listed in the manifest, oldest first.
+    fn open_segments(
+        storage: &dyn Storage,
+        dir: &Path,
+        manifest: &Manifest,
+    ) -> WalResult<Vec<WalSegment>> {
+        if manifest.segments.is_empty() {
+            return Err(WalError::ShouldNotHappen);
+        }
+        // The newest segment too is listed only once renamed in place with its header synced, a
+        // short header is corruption like in any other segment
+        manifest
+            .segments
+            .iter()
+            .map(|&start| WalSegment::open(storage, dir, start, C::ID))
+            .collect()
+    }
+
+    fn dir(&self) -> &Path {
+        Path::new(&self.cfg.path)
+    }
+
+    fn storage(&self) -> &dyn Storage {
+        &*self.cfg.storage
     }
 }
 
-impl SegmentedWal {
-    pub fn write(&mut self, cmd: WalEntry) -> WalResult<()> {
+impl<T, C: Codec<T>> SegmentedWal<T, C> {
+    /// Appends `cmd` and returns once it is as durable as `WALConfig::durability` promises.
+    ///
+    /// Returns the log index assigned to the entry.
+    pub fn write(&mut self, cmd: T) -> WalResult<u64> {
+        let index = self.append(&cmd)?;
+        self.commit()?;
+        Ok(index)
+    }
+
+    /// Appends `cmd` without honouring the durability policy, see [`SegmentedWal::commit`].
+    pub(crate) fn append(&mut self, cmd: &T) -> WalResult<u64> {
         self.maybe_roll()?;
 
         let index = self.last_log_index + 1;
-        let generation = GENERATION;
+        let generation = self.generation;
 
         let entry = WalEntryWithHeader {
             index,
             generation,
             entry: cmd,
         };
-        self.open_segment.write_entry(entry)?;
+        let written = self.open_segment.write_entry::<T, C>(
+            entry,
+            &mut self.scratch,
+            self.cfg.compression,
+            self.cipher.as_ref(),
+            &mut self.chain_head,
+            &mut self.compression_stats,
+        )?;
+        self.published.publish(&self.open_segment);
+        self.syncer.appended(written);
         self.last_log_index = index;
-        Ok(())
+        Ok(index)
+    }
+
+    /// Makes everything appended so far as durable as `WALConfig::durability` promises.
+    pub(crate) fn commit(&mut self) -> WalResult<()> {
+        Ok(self.syncer.commit(&*self.open_segment.file)?)
     }
 
+    /// Rolls before a write once the open segment reached any of the limits of
+    /// [`WALConfig`]. A segment without entries never is.
     fn maybe_roll(&mut self) -> WalResult<()> {
-        if self.open_segment.size()? >= self.cfg.max_log_size {
-            // Should we add a message to roll the wal?
-            self.open_segment.flush()?;
-            // In place replacement
-            let replacement = WalSegment::new(&self.cfg.path, self.last_log_index + 1)?;
-            let old = mem::replace(&mut self.open_segment, replacement);
-            self.segments.push(old);
+        let segment = &self.open_segment;
+        let entries = self.open_entries();
+        let full = segment.write_offset >= self.cfg.max_log_size
+            || self
+                .cfg
+                .max_segment_entries
+                .is_some_and(|max| entries >= max);
+        let old = self.cfg.max_segment_age.is_some_and(|max| {
+            let age = segment.header.created_at.elapsed().unwrap_or_default();
+            age >= max
+        });
+        if entries > 0 && (full || old) {
+            self.roll()?;
         }
         Ok(())
     }
 
-    pub fn read_from() {
-        todo!()
+    /// Seals the open segment now, so the next entry starts a new one. E.g. right after a
+    /// snapshot up to [`SegmentedWal::last_index`]: segments then end where snapshots do, and
+    /// [`SegmentedWal::truncate_before`] the next snapshot drops everything the previous
+    /// one covers.
+    ///
+    /// Does nothing while the open segment holds no entry.
+    pub fn force_roll(&mut self) -> WalResult<()> {
+        match self.open_entries() {
+            0 => Ok(()),
+            _ => self.roll(),
+        }
     }
 
-    pub fn read_next(&mut self) -> WalResult<Option<WalFrame>> {
-        // TODO: Iterate since start index
-        let wf = self.open_segment.read_next()?;
+    /// Entries in the open segment.
+    fn open_entries(&self) -> u64 {
+        self.last_log_index + 1 - self.open_segment.start_index
+    }
 
-        if let Some(f) = &wf {
-            if f.index > self.last_log_index {
-                self.last_log_index = f.index;
-            }
+    /// Seals the open segment and starts the next one, in steps that each leave a log that
+    /// opens whole, whenever a crash stops them:
+    /// 1. the open segment is synced as the durability policy still owes it,
+    /// 2. the new segment is written and synced under a temporary name, renamed, and the
+    ///    directory synced, see [`WalSegment::new`]. Unlisted, it is deleted on open,
+    /// 3. the manifest listing it is stored, from then on the log opens with it,
+    /// 4. writes switch to it.
+    fn roll(&mut self) -> WalResult<()> {
+        // A sealed segment is never written again, make sure the policy was honoured
+        self.syncer.sync_pending(&*self.open_segment.file)?;
+        let start_index = self.last_log_index + 1;
+        let replacement = WalSegment::new(
+            self.storage(),
+            self.dir(),
+            start_index,
+            C::ID,
+            self.chain_head,
+            self.recycled.take(),
+            self.cfg.preallocated_len(),
+        )?;
+        update_manifest(self.storage(), self.dir(), &self.manifest, |m| {
+            m.segments.push(start_index)
+        })?;
+        self.syncer.switch_file(&*replacement.file)?;
+        let old = mem::replace(&mut self.open_segment, replacement);
+        self.published.publish(&self.open_segment);
+        self.sealed().push(old);
+        Ok(())
+    }
+
+    /// Index of the last entry in the log.
+    pub fn last_index(&self) -> u64 {
+        self.last_log_index
+    }
+
+    /// Generation of the last entry written or found when opening the log.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// How much [`WALConfig::compression`] saved on the entries written since opening.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compression_stats
+    }
+
+    /// Generation stamped on the following writes, e.g. the term of a new leader.
+    ///
+    /// Recorded in the manifest, so it survives a restart even before anything is written.
+    pub fn set_generation(&mut self, generation: u64) -> WalResult<()> {
+        if generation != self.generation {
+            update_manifest(self.storage(), self.dir(), &self.manifest, |m| {
+                m.generation = generation
+            })?;
+            self.generation = generation;
+        }
+        Ok(())
+    }
+
+    /// Highest mark passed to [`SegmentedWal::truncate_before`], or where the log started.
+    pub fn low_water_mark(&self) -> u64 {
+        self.manifest
+            .lock()
+            .expect("WAL cleaner panicked")
+            .low_water_mark
+    }
+
+    /// Lowest index still in the log.
+    pub fn first_index(&self) -> u64 {
+        self.sealed()
+            .first()
+            .unwrap_or(&self.open_segment)
+            .start_index
+    }
+
+    /// Low-water mark truncation: deletes every sealed segment whose entries are all below
+    /// `low_water_mark`, e.g. because a snapshot covers them. The open segment is never touched
+    /// so some entries below the mark can remain.
+    ///
+    /// The mark is recorded in the manifest. Returns how many segments were deleted, see
+    /// [`WALConfig::recycle`] for what becomes of their files.
+    pub fn truncate_before(&mut self, low_water_mark: u64) -> WalResult<usize> {
+        let mut segments = self.segments.lock().expect("WAL cleaner panicked");
+        // A segment only holds entries below the mark if the one after it starts at or below it
+        let expired = segments
+            .iter()
+            .skip(1)
+            .map(|s| s.start_index)
+            .chain([self.open_segment.start_index])
+            .take_while(|&next_start| next_start <= low_water_mark)
+            .count();
+        update_manifest(self.storage(), self.dir(), &self.manifest, |m| {
+            m.segments.drain(..expired);
+            m.low_water_mark = m.low_water_mark.max(low_water_mark);
+        })?;
+        self.recycled
+            .retire(self.storage(), segments.drain(..expired))?;
+        Ok(expired)
+    }
+
+    /// Suffix truncation: drops every entry after `index`, e.g. the uncommitted entries a new
+    /// leader does not agree with. The next write gets `index + 1`.
+    ///
+    /// Crash safe: the segments made only of later entries are dropped from the manifest, then
+    /// deleted, before the segment holding `index + 1` is cut at that frame. A crash midway
+    /// always leaves a prefix of the log that still holds everything up to `index`; truncating
+    /// again finishes the job.
+    pub fn truncate_after(&mut self, index: u64) -> WalResult<()> {
+        if index >= self.last_log_index {
+            return Ok(());
+        }
+        let cut_at = index + 1;
+        let mut sealed = self.segments.lock().expect("WAL cleaner panicked");
+        if cut_at < sealed.first().unwrap_or(&self.open_segment).start_index {
+            return Err(WalError::IndexOutOfRange(index));
         }
-        Ok(wf)
+
+        if self.open_segment.start_index > cut_at {
+            // The cut is in a sealed segment, which becomes the open one.
+            let pos = sealed.partition_point(|s| s.start_index <= cut_at) - 1;
+            let later = sealed.split_off(pos + 1);
+            let target = sealed.pop().ok_or(WalError::ShouldNotHappen)?;
+            let target_start = target.start_index;
+            update_manifest(self.storage(), self.dir(), &self.manifest, |m| {
+                m.segments.retain(|&start| start <= target_start)
+            })?;
+            let old_open = mem::replace(&mut self.open_segment, target);
+            remove_segments(
+                self.storage(),
+                [old_open].into_iter().chain(later.into_iter().rev()),
+            )?;
+            sync_parent_dir(self.storage(), &self.open_segment.path)?;
+            self.syncer.switch_file(&*self.open_segment.file)?;
+        }
+        drop(sealed);
+
+        let offset = self.open_segment.offset_of(cut_at)?;
+        self.open_segment.cut(offset)?;
+        self.published.publish(&self.open_segment);
+        self.open_segment.preallocate(self.cfg.preallocated_len())?;
+        self.last_log_index = index;
+        self.chain_head = self.open_segment.chain_head()?;
+        Ok(())
+    }
+
+    /// Iterates over the log starting at `index`. Past the last entry the iterator is empty.
+    pub fn read_from(&mut self, index: u64) -> WalResult<WalReader<T, C>> {
+        self.read_range(index..)
+    }
+
+    /// Iterates over the entries of the log within `range`, e.g. `from..to` or `..=last`.
+    ///
+    /// The segment holding the first index is found by binary search over the start indexes
+    /// and the frame inside it through the segment's sparse index.
+    pub fn read_range(&mut self, range: impl RangeBounds<u64>) -> WalResult<WalReader<T, C>> {
+        let end = match range.end_bound() {
+            // Nothing comes after u64::MAX, `..=u64::MAX` is unbounded
+            Bound::Included(&end) => end.checked_add(1),
+            Bound::Excluded(&end) => Some(end),
+            Bound::Unbounded => None,
+        };
+        let index = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => match start.checked_add(1) {
+                Some(index) => index,
+                // Nor does anything come after it here
+                None => return Ok(self.reader(vec![], u64::MAX, Some(u64::MAX))),
+            },
+            Bound::Unbounded => self.first_index(),
+        };
+        let mut sealed = self.segments.lock().expect("WAL cleaner panicked");
+        // Checked under the lock, the cleaner could drop segments in between otherwise
+        if index < sealed.first().unwrap_or(&self.open_segment).start_index {
+            return Err(WalError::IndexOutOfRange(index));
+        }
+        let (offset, first) = if self.open_segment.start_index <= index {
+            (self.open_segment.offset_of(index)?, sealed.len())
+        } else {
+            let pos = sealed.partition_point(|s| s.start_index <= index) - 1;
+            (sealed[pos].offset_of(index)?, pos)
+        };
+
+        let mut segments = sealed[first..]
+            .iter()
+            .chain([&self.open_segment])
+            .enumerate()
+            .map(|(i, s)| {
+                let start_at = if i == 0 { offset } else { FRAMES_START };
+                WalSegment::open_reader(self.storage(), &s.path, s.start_index, start_at)
+            })
+            .collect::<WalResult<Vec<WalSegment>>>()?;
+        segments.reverse();
+        Ok(self.reader(segments, index, end))
+    }
+
+    /// A reader of `segments`, sorted descending, from `next_index` up to `end` excluded.
+    fn reader(
+        &self,
+        segments: Vec<WalSegment>,
+        next_index: u64,
+        end: Option<u64>,
+    ) -> WalReader<T, C> {
+        WalReader {
+            dir: self.cfg.path.clone().into(),
+            storage: Arc::clone(&self.cfg.storage),
+            segments,
+            next_index,
+            end,
+            cipher: self.cipher.clone(),
+            published: Arc::clone(&self.published),
+            entry: PhantomData,
+        }
+    }
+
+    /// Proves no entry of the log was edited, removed or reordered by recomputing the hash
+    /// chain over every frame, across segments. Returns the hash of the last frame.
+    ///
+    /// The chain starts at the first segment still around, from the hash its header recorded,
+    /// so it still verifies after [`SegmentedWal::truncate_before`]. Entries dropped from the
+    /// very end can only be noticed by comparing the returned hash with one kept elsewhere.
+    pub fn verify_chain(&mut self) -> WalResult<ChainHash> {
+        let mapped = self
+            .sealed()
+            .iter()
+            .chain([&self.open_segment])
+            .map(|s| MappedSegment::new(&*s.file, &s.path))
+            .collect::<WalResult<Vec<MappedSegment>>>()?;
+        let mut head = None;
+        for segment in &mapped {
+            head = Some(segment.verify_chain(head)?);
+        }
+        let head = head.ok_or(WalError::ShouldNotHappen)?;
+        if head != self.chain_head {
+            return Err(WalError::BrokenChain {
+                segment: self.open_segment.path.clone(),
+                index: self.last_log_index,
+            });
+        }
+        Ok(head)
+    }
+}
+
+impl<T: LogEntry> SegmentedWal<T, Rkyv> {
+    /// Calls `apply` with every entry of the log in order, borrowed straight from the segment
+    /// files mapped in memory. Nothing is copied or allocated per entry.
+    ///
+    /// Meant to rebuild state on startup. Holding `&mut self` means nothing can truncate the
+    /// segments while they are mapped.
+    pub fn replay(&mut self, apply: impl FnMut(MappedFrame<'_, T>)) -> WalResult<()> {
+        self.replay_mapped(apply)
+    }
+}
+
+impl<T: LogEntry, C: Codec<T>> SegmentedWal<T, C> {
+    /// [`SegmentedWal::replay`], only right for logs whose blobs are archives.
+    fn replay_mapped(&mut self, mut apply: impl FnMut(MappedFrame<'_, T>)) -> WalResult<()> {
+        let mapped = self
+            .sealed()
+            .iter()
+            .chain([&self.open_segment])
+            .map(|s| MappedSegment::new(&*s.file, &s.path))
+            .collect::<WalResult<Vec<MappedSegment>>>()?;
+        for segment in mapped {
+            let mut frames = segment.frames::<T>(self.cipher.as_ref());
+            while let Some(frame) = frames.next() {
+                apply(frame?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, C: Codec<T>> WriteAheadLog<T> for SegmentedWal<T, C> {
+    type Reader =
+        std::iter::Map<WalReader<T, C>, fn(WalResult<WalFrame<T, C>>) -> WalResult<(u64, T)>>;
+
+    fn append(&mut self, entry: &T) -> WalResult<u64> {
+        let index = SegmentedWal::append(self, entry)?;
+        self.commit()?;
+        Ok(index)
+    }
+
+    fn read_from(&mut self, index: u64) -> WalResult<Self::Reader> {
+        Ok(SegmentedWal::read_from(self, index)?
+            .map(|frame| frame.and_then(|f| Ok((f.index, f.deserialize()?)))))
+    }
+
+    /// Lends the archives of the mapped segments as they are when the codec is [`Rkyv`].
+    fn replay(&mut self, mut apply: impl FnMut(u64, &T::Archived)) -> WalResult<()>
+    where
+        T: LogEntry,
+    {
+        match C::ID {
+            CodecId::Rkyv => self.replay_mapped(|frame| apply(frame.index, frame.entry)),
+            _ => log::replay_archived_again(self, apply),
+        }
+    }
+
+    fn first_index(&self) -> u64 {
+        SegmentedWal::first_index(self)
+    }
+
+    fn last_index(&self) -> u64 {
+        SegmentedWal::last_index(self)
+    }
+
+    fn truncate_after(&mut self, index: u64) -> WalResult<()> {
+        SegmentedWal::truncate_after(self, index)
+    }
+
+    fn sync(&mut self) -> WalResult<()> {
+        Ok(self.syncer.sync(&*self.open_segment.file)?)
+    }
+
+    fn close(mut self) -> WalResult<()> {
+        Ok(self.syncer.sync_pending(&*self.open_segment.file)?)
+    }
+}
+
+impl<T, C> Drop for SegmentedWal<T, C> {
+    /// Syncs whatever the durability policy still owes before the segments get closed.
+    fn drop(&mut self) {
+        if let Err(e) = self.syncer.sync_pending(&*self.open_segment.file) {
+            eprintln!("WAL: failed to sync on drop: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::manifest::{MANIFEST, MANIFEST_TMP};
+    use crate::wal::storage::FileAt;
+    use crate::wal::{
+        ArchivedWalEntry, Bincode, Compression, Crash, FaultyStorage, IoFault, Json, KeyFile,
+        MemoryStorage,
+    };
+    use rkyv::{Archive, Deserialize, Serialize};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn cfg(dir: &TempDir) -> WALConfig {
+        WALConfig {
+            path: dir.path().join("wal").to_str().expect("").into(),
+            max_log_size: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    fn segment_path(dir: &TempDir, start_index: u64) -> PathBuf {
+        dir.path()
+            .join("wal")
+            .join(format!("{start_index:020}.log"))
+    }
+
+    fn write_entries(cfg: WALConfig, n: usize) {
+        let mut wal = SegmentedWal::open(cfg).expect("open wal");
+        for i in 0..n {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("write");
+        }
+    }
+
+    fn read_all(wal: &mut SegmentedWal) -> Vec<u64> {
+        wal.read_range(..)
+            .expect("read")
+            .map(|frame| frame.expect("read").index)
+            .collect()
+    }
+
+    #[test]
+    fn clean_reopen_reports_nothing() {
+        let dir = TempDir::new().expect("");
+        write_entries(cfg(&dir), 3);
+
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("reopen");
+        assert_eq!(wal.recovered(), None);
+        assert_eq!(read_all(&mut wal), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn torn_tail_is_truncated_on_open() {
+        let dir = TempDir::new().expect("");
+        write_entries(cfg(&dir), 3);
+        let path = segment_path(&dir, 1);
+        let valid_len = fs::metadata(&path).expect("").len();
+        // Half a header, as if we crashed while appending index 4
+        let mut f = OpenOptions::new().append(true).open(&path).expect("");
+        f.write_all(&4u64.to_le_bytes()[..5]).expect("");
+
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("reopen");
+        assert_eq!(
+            wal.recovered(),
+            Some(&TornTail {
+                segment: path.clone(),
+                index: 4,
+                offset: valid_len,
+                dropped_bytes: 5,
+            })
+        );
+        assert_eq!(fs::metadata(&path).expect("").len(), valid_len);
+        assert_eq!(read_all(&mut wal), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn flipped_bit_drops_frame_and_everything_after() {
+        let dir = TempDir::new().expect("");
+        write_entries(cfg(&dir), 3);
+        let path = segment_path(&dir, 1);
+        let mut bytes = fs::read(&path).expect("");
+        // All three entries serialize to the same size
+        let frame_len = (bytes.len() - SEGMENT_HEADER_LEN) / 3;
+        let second = SEGMENT_HEADER_LEN + frame_len;
+        // First byte of the second frame's blob
+        bytes[second + HEADER_LEN] ^= 0x01;
+        fs::write(&path, &bytes).expect("");
+
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("reopen");
+        let torn = wal.recovered().expect("should report the corruption");
+        assert_eq!(torn.index, 2);
+        assert_eq!(torn.offset, second as u64);
+        assert_eq!(torn.dropped_bytes, 2 * frame_len as u64);
+        assert_eq!(read_all(&mut wal), vec![1]);
+    }
+
+    #[test]
+    fn recovery_checks_checksums_without_decoding() {
+        let dir = TempDir::new().expect("");
+        let compressed = || WALConfig {
+            compression: Compression::Lz4 { min_size: 0 },
+            ..cfg(&dir)
+        };
+        let mut wal = SegmentedWal::open(compressed()).expect("");
+        for i in 0..3 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".repeat(100)))
+                .expect("");
+        }
+        let second = wal.open_segment.offset_of(2).expect("");
+        drop(wal);
+
+        // The second blob is no LZ4 anymore, but written so: its checksum holds
+        let file = FileSystem.open(&segment_path(&dir, 1), false).expect("");
+        let mut hdr = [0u8; HEADER_LEN];
+        read_full_at(&*file, &mut hdr, second).expect("");
+        let header = FrameHeader::from_le_bytes(&hdr);
+        assert!(header.is_compressed());
+        let mut blob = vec![0xFF; header.blob_len as usize];
+        // Keeps the uncompressed size up front
+        read_full_at(&*file, &mut blob[..4], second + HEADER_LEN as u64).expect("");
+        let crc = frame_checksum(&hdr, &blob);
+        hdr[20..24].copy_from_slice(&crc.to_le_bytes());
+        file.write_at(&hdr, second).expect("");
+        file.write_at(&blob, second + HEADER_LEN as u64).expect("");
+
+        let mut wal = SegmentedWal::<WalEntry>::open(compressed()).expect("reopen");
+        assert_eq!(wal.recovered(), None);
+        assert_eq!(wal.last_index(), 3);
+        assert!(matches!(
+            wal.read_from(2).expect("").next(),
+            Some(Err(WalError::CorruptFrame { offset, .. })) if offset == second
+        ));
+    }
+
+    #[test]
+    fn read_from_starts_at_the_requested_index_across_segments() {
+        let dir = TempDir::new().expect("");
+        write_entries(
+            WALConfig {
+                max_log_size: 200,
+                ..cfg(&dir)
+            },
+            20,
+        );
+        let mut wal = SegmentedWal::<WalEntry>::open(WALConfig {
+            max_log_size: 200,
+            ..cfg(&dir)
+        })
+        .expect("");
+        assert!(!wal.sealed().is_empty());
+
+        for start in [1, 7, 20] {
+            let indexes: Vec<u64> = wal
+                .read_from(start)
+                .expect("")
+                .map(|f| f.expect("").index)
+                .collect();
+            assert_eq!(indexes, (start..=20).collect::<Vec<u64>>());
+        }
+        assert_eq!(wal.read_from(21).expect("").count(), 0);
+        assert!(matches!(
+            wal.read_from(0),
+            Err(WalError::IndexOutOfRange(0))
+        ));
+    }
+
+    #[test]
+    fn read_from_uses_the_sparse_index() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("");
+        for i in 0..1000 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+
+        let first = wal.read_from(777).expect("").next().expect("").expect("");
+        assert_eq!(first.index, 777);
+
+        let sparse = &wal.open_segment.sparse_index;
+        assert!(sparse.entries.len() > 1);
+        let floor = sparse.floor(777);
+        assert!(0 < floor && floor <= wal.open_segment.offset_of(777).expect(""));
+    }
+
+    #[test]
+    fn read_from_indexes_frames_appended_since_last_lookup() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("");
+        wal.write(WalEntry::Set("a".into(), "1".into())).expect("");
+        let mut reader = wal.read_from(1).expect("");
+        assert_eq!(reader.read_next().expect("").map(|f| f.index), Some(1));
+        assert!(reader.read_next().expect("").is_none());
+
+        wal.write(WalEntry::Set("b".into(), "2".into())).expect("");
+        let mut reader = wal.read_from(2).expect("");
+        assert_eq!(reader.read_next().expect("").map(|f| f.index), Some(2));
+    }
+
+    #[test]
+    fn replay_maps_every_segment_in_order() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let mut wal = SegmentedWal::<WalEntry>::open(small_segments(&dir)).expect("");
+        assert!(!wal.sealed().is_empty());
+
+        let mut replayed = vec![];
+        wal.replay(|frame| {
+            let ArchivedWalEntry::Set(k, _) = frame.entry else {
+                panic!("expected a Set");
+            };
+            replayed.push((frame.index, k.to_string()));
+        })
+        .expect("");
+        let expected: Vec<(u64, String)> = (1..=20).map(|i| (i, format!("k{}", i - 1))).collect();
+        assert_eq!(replayed, expected);
+    }
+
+    fn small_segments(dir: &TempDir) -> WALConfig {
+        WALConfig {
+            max_log_size: 200,
+            ..cfg(dir)
+        }
+    }
+
+    fn start_indexes(wal: &SegmentedWal) -> Vec<u64> {
+        wal.sealed()
+            .iter()
+            .chain([&wal.open_segment])
+            .map(|s| s.start_index)
+            .collect()
+    }
+
+    #[test]
+    fn truncate_before_only_drops_whole_segments_below_the_mark() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+        let starts = start_indexes(&wal);
+        assert!(starts.len() > 3, "{starts:?}");
+
+        // In the middle of the third segment: the first two go, the third stays.
+        let mark = starts[2] + 1;
+        assert_eq!(wal.truncate_before(mark).expect(""), 2);
+        assert_eq!(start_indexes(&wal), starts[2..]);
+        assert_eq!(wal.first_index(), starts[2]);
+        assert!(!segment_path(&dir, starts[0]).exists());
+        assert!(!segment_path(&dir, starts[1]).exists());
+
+        assert_eq!(wal.read_from(mark).expect("").count() as u64, 20 - mark + 1);
+        assert!(matches!(
+            wal.read_from(starts[1]),
+            Err(WalError::IndexOutOfRange(_))
+        ));
+
+        // Way past the end: only the open segment is left.
+        wal.truncate_before(u64::MAX).expect("");
+        assert_eq!(start_indexes(&wal), vec![*starts.last().expect("")]);
+    }
+
+    fn preallocated(dir: &TempDir) -> WALConfig {
+        WALConfig {
+            max_log_size: 1024,
+            preallocate: true,
+            ..cfg(dir)
+        }
+    }
+
+    #[test]
+    fn preallocated_segments_end_at_their_last_frame() {
+        let dir = TempDir::new().expect("");
+        write_entries(preallocated(&dir), 3);
+        assert_eq!(fs::metadata(segment_path(&dir, 1)).expect("").len(), 1024);
+
+        let mut wal = SegmentedWal::open(preallocated(&dir)).expect("");
+        assert_eq!(wal.recovered(), None);
+        assert_eq!(wal.last_index(), 3);
+        for i in 3..20 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+        let starts = start_indexes(&wal);
+        assert!(starts.len() > 1, "{starts:?}");
+        for start in &starts {
+            assert!(fs::metadata(segment_path(&dir, *start)).expect("").len() >= 1024);
+        }
+        assert_eq!(read_indexes(&mut wal), (1..=20).collect::<Vec<_>>());
+        wal.verify_chain().expect("");
+        let mut replayed = 0;
+        wal.replay(|_| replayed += 1).expect("");
+        assert_eq!(replayed, 20);
+
+        // Cutting the open segment keeps its space
+        wal.truncate_after(18).expect("");
+        let open = segment_path(&dir, *starts.last().expect(""));
+        assert!(fs::metadata(&open).expect("").len() >= 1024);
+        assert_eq!(
+            wal.write(WalEntry::Set("k".into(), "v".into())).expect(""),
+            19
+        );
+        drop(wal);
+        let mut wal = SegmentedWal::open(preallocated(&dir)).expect("");
+        assert_eq!(wal.recovered(), None);
+        assert_eq!(read_indexes(&mut wal), (1..=19).collect::<Vec<_>>());
+    }
+
+    fn recycled_files(dir: &TempDir) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir.path().join("wal"))
+            .expect("")
+            .map(|e| e.expect("").path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "recycled"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn segments_dropped_below_the_mark_are_recycled() {
+        let dir = TempDir::new().expect("");
+        let recycling = |recycle| WALConfig {
+            recycle,
+            ..small_segments(&dir)
+        };
+        write_entries(recycling(2), 20);
+        let mut wal = SegmentedWal::open(recycling(2)).expect("");
+        let starts = start_indexes(&wal);
+        assert!(starts.len() > 4, "{starts:?}");
+
+        // Only as many as asked for are kept, the oldest first
+        assert_eq!(wal.truncate_before(starts[3]).expect(""), 3);
+        let recycled: Vec<PathBuf> = starts[..2]
+            .iter()
+            .map(|&start| segment_path(&dir, start).with_extension("recycled"))
+            .collect();
+        assert_eq!(recycled_files(&dir), recycled);
+        assert!(!segment_path(&dir, starts[2]).exists());
+
+        // Reused by the next rolls, written over from the start
+        for i in 20..40 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+        assert!(recycled_files(&dir).is_empty());
+        assert_eq!(read_indexes(&mut wal), (starts[3]..=40).collect::<Vec<_>>());
+        wal.verify_chain().expect("");
+        drop(wal);
+        let mut wal = SegmentedWal::<WalEntry>::open(recycling(2)).expect("");
+        assert_eq!(wal.recovered(), None);
+        assert_eq!(wal.last_index(), 40);
+
+        // Files kept by a previous run count against the new capacity
+        wal.truncate_before(u64::MAX).expect("");
+        assert_eq!(recycled_files(&dir).len(), 2);
+        drop(wal);
+        SegmentedWal::<WalEntry>::open(recycling(1)).expect("");
+        assert_eq!(recycled_files(&dir).len(), 1);
+    }
+
+    #[test]
+    fn older_frame_right_after_the_last_one_is_dropped_on_open() {
+        // What a crash can leave in a recycled file, once the blank header that hid it is lost
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 6);
+        let sealed = fs::read(segment_path(&dir, 1)).expect("");
+        let header = FrameHeader::from_le_bytes(
+            sealed[FRAMES_START as usize..][..HEADER_LEN]
+                .try_into()
+                .expect(""),
+        );
+        let older = &sealed[FRAMES_START as usize..][..header.frame_len() as usize];
+
+        let wal = SegmentedWal::<WalEntry>::open(small_segments(&dir)).expect("");
+        let (path, end) = (wal.open_segment.path.clone(), wal.open_segment.write_offset);
+        drop(wal);
+        let file = OpenOptions::new().write(true).open(&path).expect("");
+        file.write_all_at(older, end).expect("");
+
+        let wal = SegmentedWal::<WalEntry>::open(small_segments(&dir)).expect("");
+        assert_eq!(wal.last_index(), 6);
+        let torn = wal.recovered().expect("");
+        assert_eq!((torn.index, torn.offset), (7, end));
+    }
+
+    #[test]
+    fn later_frame_right_after_the_last_one_fails_the_open() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("");
+        write_n(&mut wal, 3);
+        let (path, end) = (wal.open_segment.path.clone(), wal.open_segment.write_offset);
+        let last = wal.open_segment.offset_of(3).expect("") as usize;
+        drop(wal);
+
+        // A whole frame, checksum included, but entry 4 is missing before it
+        let mut frame = fs::read(&path).expect("")[last..end as usize].to_vec();
+        frame[..8].copy_from_slice(&5u64.to_le_bytes());
+        let (hdr, blob) = frame.split_at_mut(HEADER_LEN);
+        let header = FrameHeader::from_le_bytes(&(*hdr).try_into().expect(""));
+        let crc = frame_checksum(
+            &(*hdr).try_into().expect(""),
+            &blob[..header.blob_len as usize],
+        );
+        hdr[20..24].copy_from_slice(&crc.to_le_bytes());
+        let file = OpenOptions::new().write(true).open(&path).expect("");
+        file.write_all_at(&frame, end).expect("");
+        let size = fs::metadata(&path).expect("").len();
+
+        assert!(matches!(
+            SegmentedWal::<WalEntry>::open(cfg(&dir)),
+            Err(WalError::NonContiguous {
+                expected: 4,
+                found: 5,
+                ..
+            })
+        ));
+        assert_eq!(fs::metadata(&path).expect("").len(), size);
+    }
+
+    fn write_n(wal: &mut SegmentedWal, n: usize) {
+        for i in 0..n {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+    }
+
+    #[test]
+    fn segments_roll_by_entry_count() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(WALConfig {
+            max_segment_entries: Some(3),
+            ..cfg(&dir)
+        })
+        .expect("");
+        write_n(&mut wal, 10);
+        assert_eq!(start_indexes(&wal), vec![1, 4, 7, 10]);
+    }
+
+    #[test]
+    fn segments_roll_by_age_on_the_next_write() {
+        let dir = TempDir::new().expect("");
+        let aging = || WALConfig {
+            max_segment_age: Some(Duration::from_millis(50)),
+            ..cfg(&dir)
+        };
+        let mut wal = SegmentedWal::open(aging()).expect("");
+        write_n(&mut wal, 2);
+        assert_eq!(start_indexes(&wal), vec![1]);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(start_indexes(&wal), vec![1]);
+        write_n(&mut wal, 1);
+        assert_eq!(start_indexes(&wal), vec![1, 3]);
+
+        // The age comes from the header, it survives a restart
+        drop(wal);
+        std::thread::sleep(Duration::from_millis(60));
+        let mut wal = SegmentedWal::open(aging()).expect("");
+        write_n(&mut wal, 1);
+        assert_eq!(start_indexes(&wal), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn force_roll_lines_segments_up_with_snapshots() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("");
+        write_n(&mut wal, 3);
+        // Snapshot at 3
+        wal.force_roll().expect("");
+        wal.force_roll().expect("");
+        assert_eq!(start_indexes(&wal), vec![1, 4]);
+        write_n(&mut wal, 2);
+
+        // Snapshot at 5: everything the first one covered goes
+        wal.force_roll().expect("");
+        assert_eq!(wal.truncate_before(4).expect(""), 1);
+        assert_eq!(start_indexes(&wal), vec![4, 6]);
+        assert_eq!(read_indexes(&mut wal), vec![4, 5]);
+    }
+
+    #[test]
+    fn default_config_keeps_writing_to_one_segment() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(WALConfig {
+            path: dir.path().join("wal").to_str().expect("").into(),
+            ..Default::default()
+        })
+        .expect("");
+        write_n(&mut wal, 10);
+        assert_eq!(start_indexes(&wal), vec![1]);
+
+        // Even with no size at all, a segment holds at least one entry
+        let mut wal = SegmentedWal::open(WALConfig {
+            max_log_size: 0,
+            truncate: true,
+            ..cfg(&dir)
+        })
+        .expect("");
+        write_n(&mut wal, 3);
+        assert_eq!(start_indexes(&wal), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn retention_keeps_the_newest_sealed_segments_within_max_bytes() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let wal = SegmentedWal::<WalEntry>::open(small_segments(&dir)).expect("");
+        let sealed = wal.sealed();
+        let newest = sealed.last().expect("").size().expect("");
+
+        let retention = RetentionPolicy {
+            max_bytes: Some(newest),
+            ..Default::default()
+        };
+        assert_eq!(retention.expired(&sealed).expect(""), sealed.len() - 1);
+
+        let retention = RetentionPolicy {
+            max_age: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        assert_eq!(retention.expired(&sealed).expect(""), 0);
+    }
+
+    #[test]
+    fn background_cleaner_enforces_retention() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let wal = SegmentedWal::<WalEntry>::open(WALConfig {
+            retention: RetentionPolicy {
+                max_age: Some(Duration::ZERO),
+                check_every: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..small_segments(&dir)
+        })
+        .expect("");
+
+        for _ in 0..200 {
+            if wal.sealed().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(wal.sealed().is_empty());
+        assert!(wal.open_segment.path.exists());
+    }
+
+    fn read_indexes(wal: &mut SegmentedWal) -> Vec<u64> {
+        let first = wal.first_index();
+        wal.read_from(first)
+            .expect("")
+            .map(|f| f.expect("").index)
+            .collect()
+    }
+
+    #[test]
+    fn truncate_after_in_the_open_segment() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("");
+        for i in 0..10 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+
+        wal.truncate_after(6).expect("");
+        assert_eq!(read_indexes(&mut wal), (1..=6).collect::<Vec<u64>>());
+
+        let next = wal
+            .write(WalEntry::Set("new".into(), "leader".into()))
+            .expect("");
+        assert_eq!(next, 7);
+        assert_eq!(read_indexes(&mut wal), (1..=7).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn truncate_after_in_a_sealed_segment_drops_later_segments() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+        for i in 0..20 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+        let starts = start_indexes(&wal);
+        assert!(starts.len() > 3, "{starts:?}");
+
+        // Right after the first entry of the second segment
+        wal.truncate_after(starts[1]).expect("");
+        assert_eq!(start_indexes(&wal), starts[..2]);
+        for start in &starts[2..] {
+            assert!(!segment_path(&dir, *start).exists());
+        }
+        assert_eq!(
+            read_indexes(&mut wal),
+            (1..=starts[1]).collect::<Vec<u64>>()
+        );
+
+        assert_eq!(
+            wal.write(WalEntry::Set("k".into(), "v".into())).expect(""),
+            starts[1] + 1
+        );
+        assert_eq!(
+            read_indexes(&mut wal),
+            (1..=starts[1] + 1).collect::<Vec<u64>>()
+        );
+    }
+
+    #[test]
+    fn truncate_after_on_a_segment_boundary_keeps_an_empty_open_segment() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+        for i in 0..20 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+        let starts = start_indexes(&wal);
+
+        wal.truncate_after(starts[1] - 1).expect("");
+        assert_eq!(start_indexes(&wal), starts[..2]);
+        assert_eq!(wal.open_segment.size().expect(""), FRAMES_START);
+        assert_eq!(read_indexes(&mut wal), (1..starts[1]).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn truncate_after_below_the_low_water_mark_fails() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+        for i in 0..20 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+        let starts = start_indexes(&wal);
+        wal.truncate_before(starts[2]).expect("");
+
+        assert!(matches!(
+            wal.truncate_after(starts[1]),
+            Err(WalError::IndexOutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn reopen_restores_last_index_and_generation() {
+        let dir = TempDir::new().expect("");
+        {
+            let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+            wal.write(WalEntry::Set("a".into(), "1".into())).expect("");
+            wal.set_generation(3).expect("");
+            for i in 0..10 {
+                wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                    .expect("");
+            }
+        }
+
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+        assert_eq!(wal.last_index(), 11);
+        assert_eq!(wal.generation(), 3);
+        assert_eq!(
+            wal.write(WalEntry::Set("b".into(), "2".into())).expect(""),
+            12
+        );
+        assert_eq!(read_indexes(&mut wal), (1..=12).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn reopen_after_rolling_to_an_empty_segment() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let prev_hash = SegmentedWal::<WalEntry>::open(small_segments(&dir))
+            .expect("")
+            .chain_head;
+        // Rolled, but nothing was written to the new segment yet
+        let wal_dir = dir.path().join("wal");
+        let mut manifest = Manifest::load(&FileSystem, &wal_dir).expect("").expect("");
+        manifest.segments.push(21);
+        manifest.store(&FileSystem, &wal_dir).expect("");
+        fs::write(
+            segment_path(&dir, 21),
+            SegmentHeader::new(21, CodecId::Rkyv, prev_hash).to_le_bytes(),
+        )
+        .expect("");
+
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+        assert_eq!(wal.open_segment.start_index, 21);
+        assert_eq!(wal.last_index(), 20);
+        assert_eq!(
+            wal.write(WalEntry::Set("b".into(), "2".into())).expect(""),
+            21
+        );
+        wal.verify_chain().expect("");
+    }
+
+    #[test]
+    fn chain_breaks_where_a_frame_was_rewritten() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let mut wal = SegmentedWal::<WalEntry>::open(small_segments(&dir)).expect("");
+        let head = wal.verify_chain().expect("");
+        assert_eq!(head, wal.chain_head);
+        let starts = start_indexes(&wal);
+        wal.truncate_before(starts[1]).expect("");
+        assert_eq!(wal.verify_chain().expect(""), head);
+        drop(wal);
+
+        // A value rewritten with a valid checksum, as only someone meaning it would
+        let path = segment_path(&dir, starts[2]);
+        let mut bytes = fs::read(&path).expect("");
+        let hdr: [u8; FRAME_HEADER_LEN] = bytes[FRAMES_START as usize..][..FRAME_HEADER_LEN]
+            .try_into()
+            .expect("");
+        let header = FrameHeader::from_le_bytes(&hdr);
+        let blob_at = FRAMES_START as usize + FRAME_HEADER_LEN;
+        let blob = &mut bytes[blob_at..blob_at + header.blob_len as usize];
+        let v = blob.iter().position(|&b| b == b'v').expect("");
+        blob[v] = b'w';
+        let crc = frame_checksum(&hdr, blob);
+        bytes[FRAMES_START as usize + 20..][..4].copy_from_slice(&crc.to_le_bytes());
+        fs::write(&path, &bytes).expect("");
+
+        let mut wal = SegmentedWal::<WalEntry>::open(small_segments(&dir)).expect("");
+        match wal.verify_chain() {
+            Err(WalError::BrokenChain { segment, index }) => {
+                assert_eq!((segment, index), (path, starts[2]));
+            }
+            other => panic!("expected a BrokenChain error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn missing_segment_is_detected_on_open() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let starts = start_indexes(&SegmentedWal::open(small_segments(&dir)).expect(""));
+        fs::remove_file(segment_path(&dir, starts[1])).expect("");
+
+        match SegmentedWal::<WalEntry>::open(small_segments(&dir)) {
+            Err(WalError::MissingSegment { segment }) => {
+                assert_eq!(segment, segment_path(&dir, starts[1]));
+            }
+            other => panic!("expected a MissingSegment error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn read_range_is_bounded() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+
+        let indexes =
+            |reader: WalReader| -> Vec<u64> { reader.map(|f| f.expect("").index).collect() };
+        assert_eq!(
+            indexes(wal.read_range(5..10).expect("")),
+            vec![5, 6, 7, 8, 9]
+        );
+        assert_eq!(indexes(wal.read_range(..=3).expect("")), vec![1, 2, 3]);
+        assert_eq!(indexes(wal.read_range(18..40).expect("")), vec![18, 19, 20]);
+        assert_eq!(indexes(wal.read_range(..).expect("")).len(), 20);
+        assert_eq!(
+            indexes(wal.read_range(18..=u64::MAX).expect("")),
+            vec![18, 19, 20]
+        );
+        let after_max = (Bound::Excluded(u64::MAX), Bound::Unbounded);
+        assert_eq!(
+            indexes(wal.read_range(after_max).expect("")),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn reader_follows_the_writer_across_rolls() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+        wal.write(WalEntry::Set("a".into(), "1".into())).expect("");
+        let mut reader = wal.read_from(1).expect("");
+        assert_eq!(reader.next().map(|f| f.expect("").index), Some(1));
+        assert!(reader.next().is_none());
+
+        for i in 0..20 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+        assert!(wal.sealed().len() > 1);
+        let indexes: Vec<u64> = reader.by_ref().map(|f| f.expect("").index).collect();
+        assert_eq!(indexes, (2..=21).collect::<Vec<u64>>());
+
+        let position = reader.position().expect("");
+        assert_eq!(position.next_index, 22);
+        assert_eq!(position.segment.start_index, wal.open_segment.start_index);
+        assert_eq!(position.offset, wal.open_segment.size().expect(""));
+    }
+
+    #[test]
+    fn reader_waits_for_a_frame_being_written() {
+        let dir = TempDir::new().expect("");
+        let mut wal = SegmentedWal::open(cfg(&dir)).expect("");
+        wal.write(WalEntry::Set("a".into(), "1".into())).expect("");
+        let mut reader = wal.read_from(1).expect("");
+        reader.next();
+
+        // Half of the next header is on disk
+        let path = segment_path(&dir, 1);
+        let mut f = OpenOptions::new().append(true).open(&path).expect("");
+        f.write_all(&2u64.to_le_bytes()).expect("");
+        assert!(reader.next().is_none());
+        assert_eq!(reader.position().expect("").next_index, 2);
+    }
+
+    #[test]
+    fn reader_keeps_up_with_a_writer_on_preallocated_segments() {
+        const ENTRIES: u64 = 20000;
+        let mut wal = SegmentedWal::open(WALConfig {
+            path: "/wal".into(),
+            max_log_size: 4096,
+            preallocate: true,
+            // Writes one slice at a time, a frame is visible long before it is whole
+            storage: Arc::new(MemoryStorage::default()),
+            ..Default::default()
+        })
+        .expect("");
+        let mut reader = wal.read_from(1).expect("");
+        let writer = std::thread::spawn(move || {
+            for i in 0..ENTRIES {
+                let value = "v".repeat(i as usize % 100);
+                wal.write(WalEntry::Set(format!("k{i}"), value)).expect("");
+            }
+        });
+
+        let mut indexes = vec![];
+        loop {
+            // Checked first, so nothing is left to read once it is done and we caught up
+            let done = writer.is_finished();
+            match reader
+                .read_next()
+                .expect("a frame being written is not corrupt")
+            {
+                Some(frame) => indexes.push(frame.index),
+                None if done => break,
+                None => std::thread::yield_now(),
+            }
+        }
+        writer.join().expect("");
+        assert_eq!(indexes, (1..=ENTRIES).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn reader_fails_on_a_segment_written_over_under_it() {
+        let dir = TempDir::new().expect("");
+        let other = TempDir::new().expect("");
+        let write = |cfg: WALConfig| {
+            let mut wal = SegmentedWal::open(cfg).expect("");
+            for _ in 0..3 {
+                wal.write(WalEntry::Set("k".into(), "v".into())).expect("");
+            }
+            wal
+        };
+        let mut wal = write(cfg(&dir));
+        let mut reader = wal.read_from(2).expect("");
+        // Same frames in the same places, but from another log
+        write(WALConfig {
+            start_index: 100,
+            ..cfg(&other)
+        });
+        let bytes = fs::read(segment_path(&other, 101)).expect("");
+        fs::write(segment_path(&dir, 1), bytes).expect("");
+
+        assert!(matches!(
+            reader.next(),
+            Some(Err(WalError::NonContiguous {
+                expected: 2,
+                found: 102,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn reader_lists_the_segments_left() {
+        let dir = TempDir::new().expect("");
+        write_entries(small_segments(&dir), 20);
+        let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+        let starts = start_indexes(&wal);
+
+        let reader = wal.read_from(starts[1] + 1).expect("");
+        let segments: Vec<u64> = reader.segments().iter().map(|s| s.start_index).collect();
+        assert_eq!(segments, starts[1..]);
+        assert_eq!(
+            reader.position().expect("").segment.path,
+            segment_path(&dir, starts[1])
+        );
+    }
+
+    #[test]
+    fn corrupt_frame_is_an_error_when_reading() {
+        let dir = TempDir::new().expect("");
+        write_entries(cfg(&dir), 1);
+        let path = segment_path(&dir, 1);
+        let mut bytes = fs::read(&path).expect("");
+        bytes[SEGMENT_HEADER_LEN] ^= 0x01;
+        fs::write(&path, &bytes).expect("");
+
+        let mut segment =
+            WalSegment::open(&FileSystem, &dir.path().join("wal"), 1, CodecId::Rkyv).expect("");
+        assert!(matches!(
+            segment.read_next::<WalEntry, Rkyv>(None),
+            Err(WalError::CorruptFrame {
+                offset: FRAMES_START,
+                reason: "checksum mismatch"
+            })
+        ));
+    }
+
+    #[test]
+    fn open_rejects_a_file_in_another_format() {
+        let dir = TempDir::new().expect("");
+        write_entries(cfg(&dir), 1);
+        // simple_wal frames start with a 4-byte length
+        let mut foreign = 60u32.to_le_bytes().to_vec();
+        foreign.resize(128, 0);
+        fs::write(segment_path(&dir, 1), foreign).expect("");
+
+        assert!(matches!(
+            SegmentedWal::<WalEntry>::open(cfg(&dir)),
+            Err(WalError::InvalidSegmentHeader { segment, .. }) if segment == segment_path(&dir, 1)
+        ));
+    }
+
+    #[test]
+    fn incomplete_header_of_the_newest_segment_is_an_error() {
+        let dir = TempDir::new().expect("");
+        write_entries(cfg(&dir), 2);
+        // Rolling never lists a segment before its header is synced, so this is corruption
+        let wal_dir = dir.path().join("wal");
+        let mut manifest = Manifest::load(&FileSystem, &wal_dir).expect("").expect("");
+        manifest.segments.push(3);
+        manifest.store(&FileSystem, &wal_dir).expect("");
+        fs::write(segment_path(&dir, 3), b"PDS").expect("");
+
+        assert!(matches!(
+            SegmentedWal::<WalEntry>::open(cfg(&dir)),
+            Err(WalError::InvalidSegmentHeader { segment, .. }) if segment == segment_path(&dir, 3)
+        ));
+    }
+
+    #[test]
+    fn segments_not_in_the_manifest_are_removed_on_open() {
+        let dir = TempDir::new().expect("");
+        write_entries(cfg(&dir), 2);
+        // As if we crashed while rolling, before the manifest listed the new segment
+        fs::write(segment_path(&dir, 3), b"").expect("");
+        fs::write(dir.path().join("wal").join(MANIFEST_TMP), b"half").expect("");
+        let tmp = WalSegment::tmp_path(&dir.path().join("wal"), 3);
+        fs::write(&tmp, b"PDS").expect("");
+
+        let wal = SegmentedWal::open(cfg(&dir)).expect("");
+        assert_eq!(start_indexes(&wal), vec![1]);
+        assert!(!segment_path(&dir, 3).exists());
+        assert!(!dir.path().join("wal").join(MANIFEST_TMP).exists());
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn a_crash_anywhere_in_a_roll_leaves_a_log_that_opens() {
+        let dir = Path::new("/wal");
+        let mut rolled = false;
+        for (ops, crash) in (0..16).flat_map(|ops| Crash::ALL.map(|crash| (ops, crash))) {
+            let storage = FaultyStorage::new(MemoryStorage::default(), ops);
+            let cfg = |storage: &FaultyStorage| WALConfig {
+                path: "/wal".into(),
+                max_log_size: u64::MAX,
+                durability: DurabilityPolicy::EveryWrite,
+                storage: Arc::new(storage.clone()),
+                ..Default::default()
+            };
+            let mut wal = SegmentedWal::open(cfg(&storage)).expect("");
+            wal.write(WalEntry::Set("k".into(), "v".into())).expect("");
+            storage.fail_after(ops, IoFault::Eio);
+            let roll = wal.roll();
+
+            let after = storage.crash(crash).expect("");
+            let mut wal = SegmentedWal::open(cfg(&after))
+                .unwrap_or_else(|e| panic!("{ops} ops, {crash:?}: {e}"));
+            assert_eq!(wal.last_index(), 1);
+            let starts = start_indexes(&wal);
+            assert!(starts == [1] || starts == [1, 2], "{starts:?}");
+            rolled |= roll.is_ok() && starts == [1, 2];
+            let leftovers = after.list(dir).expect("");
+            assert!(
+                leftovers
+                    .iter()
+                    .all(|p| p.extension() != Some("tmp".as_ref())),
+                "{leftovers:?}"
+            );
+            assert_eq!(
+                wal.write(WalEntry::Set("k".into(), "v".into())).expect(""),
+                2
+            );
+            assert_eq!(read_indexes(&mut wal), [1, 2]);
+        }
+        assert!(rolled);
+    }
+
+    #[test]
+    fn a_frame_that_failed_half_written_does_not_outlive_the_next_one() {
+        for ops in 0..3 {
+            let storage = FaultyStorage::new(MemoryStorage::default(), ops);
+            let cfg = || WALConfig {
+                path: "/wal".into(),
+                max_log_size: u64::MAX,
+                storage: Arc::new(storage.clone()),
+                ..Default::default()
+            };
+            let mut wal = SegmentedWal::open(cfg()).expect("");
+            wal.write(WalEntry::Set("k".into(), "v".into())).expect("");
+            // Some of the header, blob and padding writes land before one fails
+            storage.fail_after(ops, IoFault::Eio);
+            let long = WalEntry::Set("k".into(), "v".repeat(200));
+            assert!(wal.write(long).is_err());
+            // Shorter, so the end of the failed frame is still there after it
+            let short = WalEntry::Set("k".into(), "w".into());
+            assert_eq!(wal.write(short).expect(""), 2);
+            // Sealed, the segment is not recovered on open anymore
+            wal.force_roll().expect("");
+            wal.write(WalEntry::Set("k".into(), "v".into())).expect("");
+            drop(wal);
+
+            let mut wal = SegmentedWal::open(cfg()).unwrap_or_else(|e| panic!("{ops} ops: {e}"));
+            assert_eq!(read_indexes(&mut wal), [1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn path_is_a_directory_and_may_contain_underscores() {
+        let dir = TempDir::new().expect("");
+        let cfg = || WALConfig {
+            path: dir.path().join("my_wal_1").to_str().expect("").into(),
+            max_log_size: 200,
+            ..Default::default()
+        };
+        write_entries(cfg(), 20);
+
+        let wal = SegmentedWal::<WalEntry>::open(cfg()).expect("");
+        assert!(!wal.sealed().is_empty());
+        assert_eq!(wal.last_index(), 20);
+        assert!(dir.path().join("my_wal_1").join(MANIFEST).exists());
+        assert!(dir
+            .path()
+            .join("my_wal_1")
+            .join(format!("{:020}.log", 1))
+            .exists());
+    }
+
+    #[test]
+    fn manifest_keeps_low_water_mark_and_generation() {
+        let dir = TempDir::new().expect("");
+        {
+            let mut wal = SegmentedWal::open(small_segments(&dir)).expect("");
+            for i in 0..20 {
+                wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                    .expect("");
+            }
+            let starts = start_indexes(&wal);
+            assert_eq!(wal.truncate_before(starts[1]).expect(""), 1);
+            wal.set_generation(7).expect("");
+        }
+
+        let wal = SegmentedWal::<WalEntry>::open(small_segments(&dir)).expect("");
+        assert_eq!(wal.low_water_mark(), wal.first_index());
+        // Nothing was written with it yet
+        assert_eq!(wal.generation(), 7);
+    }
+
+    #[test]
+    fn runs_on_memory_storage() {
+        let storage = MemoryStorage::default();
+        let cfg = || WALConfig {
+            path: "/wal".into(),
+            max_log_size: 200,
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        };
+        write_entries(cfg(), 20);
+
+        let mut wal = SegmentedWal::open(cfg()).expect("");
+        let starts = start_indexes(&wal);
+        assert!(starts.len() > 2, "{starts:?}");
+        assert_eq!(wal.truncate_before(starts[1]).expect(""), 1);
+        assert_eq!(read_indexes(&mut wal), (starts[1]..=20).collect::<Vec<_>>());
+        assert!(!storage.exists(&WalSegment::file_path(Path::new("/wal"), starts[0])));
+
+        drop(wal);
+        let wal = SegmentedWal::<WalEntry>::open(cfg()).expect("");
+        assert_eq!(wal.last_index(), 20);
+        assert_eq!(wal.first_index(), starts[1]);
+    }
+
+    #[test]
+    fn header_of_a_new_segment_survives_a_crash() {
+        let storage = FaultyStorage::new(MemoryStorage::default(), 1);
+        let mut wal = SegmentedWal::open(WALConfig {
+            path: "/wal".into(),
+            max_log_size: 0,
+            durability: DurabilityPolicy::EveryWrite,
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        })
+        .expect("");
+        wal.write(WalEntry::Set("k".into(), "v".into())).expect("");
+        // Rolled, but nothing written to the new segment yet
+        wal.maybe_roll().expect("");
+
+        let after = storage.crash(Crash::DropUnsynced).expect("");
+        let path = WalSegment::file_path(Path::new("/wal"), 2);
+        let file = after.open(&path, false).expect("");
+        assert_eq!(file.len().expect(""), FRAMES_START);
+        SegmentHeader::read(&*file, &path).expect("");
+    }
+
+    /// A log type of its own, e.g. for Raft.
+    #[derive(Archive, Serialize, Deserialize, Debug, PartialEq)]
+    struct RaftEntry {
+        term: u64,
+        command: Vec<u8>,
+    }
+
+    #[test]
+    fn logs_any_archivable_type() {
+        let storage = MemoryStorage::default();
+        let cfg = || WALConfig {
+            path: "/raft".into(),
+            max_log_size: 200,
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        };
+        let mut wal = SegmentedWal::<RaftEntry>::open(cfg()).expect("");
+        for term in 1..=10 {
+            let command = vec![term as u8; term as usize];
+            wal.write(RaftEntry { term, command }).expect("");
+        }
+        drop(wal);
+
+        let mut wal = SegmentedWal::<RaftEntry>::open(cfg()).expect("");
+        let mut terms = vec![];
+        wal.replay(|frame| terms.push(frame.entry.term.to_native()))
+            .expect("");
+        assert_eq!(terms, (1..=10).collect::<Vec<u64>>());
+
+        let frame = wal.read_from(4).expect("").next().expect("").expect("");
+        assert_eq!(frame.zero_copy().expect("").command.as_slice(), [4; 4]);
+        assert_eq!(
+            frame.deserialize().expect(""),
+            RaftEntry {
+                term: 4,
+                command: vec![4; 4]
+            }
+        );
+    }
+
+    #[test]
+    fn json_logs_are_readable_and_refused_by_other_codecs() {
+        let storage = MemoryStorage::default();
+        let cfg = || WALConfig {
+            path: "/json".into(),
+            max_log_size: 200,
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        };
+        let mut wal = SegmentedWal::open_with_codec(cfg(), Json).expect("");
+        for i in 0..10 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+        assert!(!wal.sealed().is_empty());
+        drop(wal);
+
+        let mut wal = SegmentedWal::<WalEntry, Json>::open_with_codec(cfg(), Json).expect("");
+        let frame = wal.read_from(8).expect("").next().expect("").expect("");
+        assert_eq!(frame.buf, br#"{"Set":["k7","v"]}"#);
+        assert!(matches!(
+            frame.deserialize().expect(""),
+            WalEntry::Set(k, v) if k == "k7" && v == "v"
+        ));
+        drop(wal);
+
+        assert!(matches!(
+            SegmentedWal::<WalEntry>::open(cfg()),
+            Err(WalError::InvalidSegmentHeader { .. })
+        ));
+        assert!(matches!(
+            SegmentedWal::<WalEntry, Bincode>::open_with_codec(cfg(), Bincode),
+            Err(WalError::InvalidSegmentHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn compressed_and_raw_frames_replay_together() {
+        let storage = MemoryStorage::default();
+        let cfg = |compression| WALConfig {
+            path: "/lz4".into(),
+            max_log_size: u64::MAX,
+            compression,
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        };
+        let value = |i: usize| format!("{i}").repeat(100);
+        let mut wal = SegmentedWal::open(cfg(Compression::None)).expect("");
+        wal.write(WalEntry::Set("k0".into(), value(0))).expect("");
+        assert_eq!(wal.compression_stats().ratio(), 1.0);
+        drop(wal);
+
+        let mut wal = SegmentedWal::open(cfg(Compression::Lz4 { min_size: 64 })).expect("");
+        for i in 1..4 {
+            wal.write(WalEntry::Set(format!("k{i}"), value(i)))
+                .expect("");
+        }
+        // Too small to be worth it
+        wal.write(WalEntry::Set("k4".into(), "v".into())).expect("");
+        let stats = wal.compression_stats();
+        assert_eq!((stats.frames, stats.compressed_frames), (4, 3));
+        assert!(stats.ratio() < 0.5, "{stats:?}");
+
+        let mut values = vec![];
+        wal.replay(|frame| {
+            let ArchivedWalEntry::Set(_, v) = frame.entry else {
+                panic!("expected a Set");
+            };
+            values.push(v.to_string());
+        })
+        .expect("");
+        let expected = [value(0), value(1), value(2), value(3), "v".into()];
+        assert_eq!(values, expected);
+
+        let read: Vec<String> = wal
+            .read_from(1)
+            .expect("")
+            .map(|frame| match frame.expect("").deserialize().expect("") {
+                WalEntry::Set(_, v) => v,
+                WalEntry::Batch(_) => panic!("expected a Set"),
+            })
+            .collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn encryption_does_not_count_as_compression() {
+        let keys = TempDir::new().expect("");
+        let key: Arc<dyn KeyProvider> =
+            Arc::new(KeyFile::generate(keys.path().join("wal.key")).expect(""));
+        let storage = MemoryStorage::default();
+        let cfg = |compression| WALConfig {
+            path: "/secret".into(),
+            max_log_size: u64::MAX,
+            compression,
+            encryption: Some(Arc::clone(&key)),
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        };
+        let value = "secret".repeat(100);
+        let mut wal = SegmentedWal::open(cfg(Compression::None)).expect("");
+        for i in 0..3 {
+            wal.write(WalEntry::Set(format!("k{i}"), value.clone()))
+                .expect("");
+        }
+        let stats = wal.compression_stats();
+        assert_eq!((stats.frames, stats.compressed_frames), (3, 0));
+        assert_eq!(stats.ratio(), 1.0);
+        drop(wal);
+
+        let mut wal = SegmentedWal::open(cfg(Compression::Lz4 { min_size: 64 })).expect("");
+        wal.write(WalEntry::Set("k3".into(), value)).expect("");
+        // Too small to be worth it
+        wal.write(WalEntry::Set("k4".into(), "v".into())).expect("");
+        let stats = wal.compression_stats();
+        assert_eq!((stats.frames, stats.compressed_frames), (2, 1));
+        assert!(stats.ratio() < 0.5, "{stats:?}");
+    }
+
+    #[test]
+    fn encrypted_frames_need_their_key() {
+        let keys = TempDir::new().expect("");
+        let key = KeyFile::generate(keys.path().join("wal.key")).expect("");
+        let other = KeyFile::generate(keys.path().join("other.key")).expect("");
+        let storage = MemoryStorage::default();
+        let cfg = |key: Option<&KeyFile>| WALConfig {
+            path: "/secret".into(),
+            max_log_size: u64::MAX,
+            compression: Compression::Lz4 { min_size: 64 },
+            encryption: key.map(|k| Arc::new(k.clone()) as Arc<dyn KeyProvider>),
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        };
+        let mut wal = SegmentedWal::open(cfg(Some(&key))).expect("");
+        for i in 0..5 {
+            let value = format!("secret-{i}").repeat(i * 10 + 1);
+            wal.write(WalEntry::Set(format!("k{i}"), value)).expect("");
+        }
+        drop(wal);
+        let path = WalSegment::file_path(Path::new("/secret"), 1);
+        let bytes = storage.open(&path, false).expect("").map().expect("");
+        assert!(!bytes.windows(6).any(|w| w == b"secret"));
+
+        let mut wal = SegmentedWal::open(cfg(Some(&key))).expect("");
+        let mut values = vec![];
+        wal.replay(|frame| {
+            let ArchivedWalEntry::Set(_, v) = frame.entry else {
+                panic!("expected a Set");
+            };
+            values.push(v.len());
+        })
+        .expect("");
+        assert_eq!(values, [8, 88, 168, 248, 328]);
+        let frame = wal.read_from(2).expect("").next().expect("").expect("");
+        assert!(matches!(
+            frame.deserialize().expect(""),
+            WalEntry::Set(k, v) if k == "k1" && v == "secret-1".repeat(11)
+        ));
+        drop(wal);
+
+        assert!(matches!(
+            SegmentedWal::<WalEntry>::open(cfg(None)),
+            Err(WalError::MissingKey {
+                offset: FRAMES_START
+            })
+        ));
+        assert!(matches!(
+            SegmentedWal::<WalEntry>::open(cfg(Some(&other))),
+            Err(WalError::AuthenticationFailed {
+                offset: FRAMES_START
+            })
+        ));
+        let mut segment =
+            WalSegment::open(&storage, Path::new("/secret"), 1, CodecId::Rkyv).expect("");
+        let wrong = Cipher::new(&other).expect("");
+        assert!(matches!(
+            segment.read_next::<WalEntry, Rkyv>(Some(&wrong)),
+            Err(WalError::AuthenticationFailed {
+                offset: FRAMES_START
+            })
+        ));
+    }
+
+    #[test]
+    fn plain_frames_are_refused_once_a_key_is_set() {
+        let keys = TempDir::new().expect("");
+        let key: Arc<dyn KeyProvider> =
+            Arc::new(KeyFile::generate(keys.path().join("wal.key")).expect(""));
+        let storage = MemoryStorage::default();
+        let cfg = || WALConfig {
+            path: "/secret".into(),
+            encryption: Some(Arc::clone(&key)),
+            storage: Arc::new(storage.clone()),
+            ..Default::default()
+        };
+        let mut wal = SegmentedWal::open(cfg()).expect("");
+        for i in 0..3 {
+            wal.write(WalEntry::Set(format!("k{i}"), "v".into()))
+                .expect("");
+        }
+        let second = wal.open_segment.offset_of(2).expect("");
+        drop(wal);
+
+        // Whoever can write the file passes the second frame off as plain, checksum included
+        let file = storage
+            .open(&WalSegment::file_path(Path::new("/secret"), 1), false)
+            .expect("");
+        let mut hdr = [0u8; HEADER_LEN];
+        read_full_at(&*file, &mut hdr, second).expect("");
+        let header = FrameHeader::from_le_bytes(&hdr);
+        let mut blob = vec![0; header.blob_len as usize];
+        read_full_at(&*file, &mut blob, second + HEADER_LEN as u64).expect("");
+        hdr[24] &= !crate::wal::encryption::ENCRYPTED;
+        let crc = frame_checksum(&hdr, &blob);
+        hdr[20..24].copy_from_slice(&crc.to_le_bytes());
+        file.write_at(&hdr, second).expect("");
+
+        let mut wal = SegmentedWal::<WalEntry>::open(cfg()).expect("");
+        assert!(matches!(
+            wal.read_from(2).expect("").next(),
+            Some(Err(WalError::NotEncrypted { offset })) if offset == second
+        ));
+        assert!(matches!(
+            wal.replay(|_| {}),
+            Err(WalError::NotEncrypted { offset }) if offset == second
+        ));
     }
 }
@@ -1,61 +1,75 @@
 #![allow(dead_code, unused, unused_imports)]
 use rkyv::{access, rancor::Failure};
-use rkyv::{deserialize, rancor::Error, Archive, Deserialize, Serialize};
+use rkyv::{rancor::Error, Archive, Deserialize, Serialize};
 use std::io::{self, Write};
 use std::io::{Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::path::Path;
 use std::process::abort;
 use std::{collections::HashMap, fs::File};
 
-#[derive(Archive, Deserialize, Serialize, Debug)]
-pub enum WalEntry {
-    Set(String, String),
-    Batch(HashMap<String, String>),
-}
+use super::durability::Syncer;
+use super::storage::FileAt;
+use super::{
+    deserialize, serialize, zero_copy, DurabilityPolicy, LogEntry, WalEntry, WalError, WalResult,
+};
 
-impl WalEntry {
-    fn serialize(&self) -> rkyv::util::AlignedVec {
-        rkyv::to_bytes::<Error>(self).expect("serialize WalEntry")
-    }
-
-    fn deserialize(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
-        // This is not too efficient since we are deserializing and thus copying data
-        // We Should pass around the archived reference
-        let archived = rkyv::access::<ArchivedWalEntry, Failure>(bytes)?;
-        Ok(rkyv::deserialize::<WalEntry, Error>(archived)?)
-    }
-    fn zero_copy(bytes: &[u8]) -> Result<&ArchivedWalEntry, Box<dyn std::error::Error>> {
-        Ok(rkyv::access::<ArchivedWalEntry, Failure>(bytes)?)
-    }
-}
+const LEN_PREFIX: u64 = 4;
 
 #[derive(Default, Debug)]
 pub struct WALConfig {
     pub path: String,
     pub truncate: bool,
+    pub durability: DurabilityPolicy,
 }
 
 #[derive(Debug)]
-pub struct WriteAheadLog {
+pub struct WriteAheadLog<T = WalEntry> {
     file: File,
+    /// Frames carry no index, the first one is entry 1.
+    last_log_index: u64,
+    syncer: Syncer,
+    entry: PhantomData<fn(T) -> T>,
 }
 
-impl WriteAheadLog {
+impl<T: LogEntry> WriteAheadLog<T> {
     /// Opens a new R/W WAL
     ///
     pub fn open(cfg: WALConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut f_opts = File::options();
-        f_opts.read(true).write(true).create(true);
-        match cfg.truncate {
-            true => f_opts.truncate(true),
-            false => f_opts.append(true),
+        let f = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&cfg.path)?;
+        if cfg.truncate {
+            f.set_len(0)?;
+        }
+        let mut wal = Self {
+            syncer: Syncer::new(cfg.durability, &f)?,
+            file: f,
+            last_log_index: 0,
+            entry: PhantomData,
         };
+        wal.last_log_index = wal.scan(u64::MAX)?.1;
+        Ok(wal)
+    }
 
-        let f = f_opts.open(&cfg.path)?;
-        Ok(Self { file: f })
+    /// Walks the frames from the start of the file until `count` were seen or the file ends.
+    /// Returns the offset reached and how many frames were walked.
+    fn scan(&self, count: u64) -> WalResult<(u64, u64)> {
+        let (mut offset, mut seen) = (0, 0);
+        while seen < count {
+            let Some(blob_len) = blob_len_at(&self.file, offset)? else {
+                break;
+            };
+            offset += LEN_PREFIX + blob_len as u64;
+            seen += 1;
+        }
+        Ok((offset, seen))
     }
 }
 
-impl WriteAheadLog {
+impl<T: LogEntry> WriteAheadLog<T> {
     /// Writes to a log file with the following structure
     ///
     ///┌──────────────┬───────────┐┌──────────────┬───────────┐┌──────────────┬───────────┐
@@ -64,16 +78,23 @@ impl WriteAheadLog {
     ///└──────────────┴───────────┘└──────────────┴───────────┘└──────────────┴───────────┘
     ///
     /// It's not calling flush() constantly since we are not using a BufWriter as of now.
-    pub fn write(&mut self, cmd: WalEntry) -> Result<(), std::io::Error> {
-        let blob = cmd.serialize();
+    /// Returns once the entry is as durable as `WALConfig::durability` promises.
+    pub fn write(&mut self, cmd: T) -> Result<(), std::io::Error> {
+        self.write_entry(&cmd)
+    }
+
+    fn write_entry(&mut self, cmd: &T) -> Result<(), std::io::Error> {
+        let blob = serialize(cmd).map_err(io::Error::other)?;
         let blob_len = blob.len() as u32;
 
         self.file.write_all(&blob_len.to_le_bytes())?;
         self.file.write_all(&blob)?;
+        self.syncer.written(&self.file, 4 + blob.len() as u64)?;
+        self.last_log_index += 1;
         Ok(())
     }
 
-    pub fn read(&mut self) -> Vec<Result<WalEntry, Box<dyn std::error::Error + 'static>>> {
+    pub fn read(&mut self) -> Vec<Result<T, Box<dyn std::error::Error + 'static>>> {
         // Of course, reading the entire file and sending a Vec is not optimal.
         // We should just have a generator
 
@@ -82,7 +103,7 @@ impl WriteAheadLog {
             return vec![Err(Box::new(e))];
         }
 
-        let mut out: Vec<Result<WalEntry, Box<dyn std::error::Error>>> = Vec::new();
+        let mut out: Vec<Result<T, Box<dyn std::error::Error>>> = Vec::new();
         let mut archive_lenght_marker = [0u8; 4];
 
         loop {
@@ -90,8 +111,7 @@ impl WriteAheadLog {
             match buf {
                 Err(e) => out.push(Err(e)),
                 Ok(Some(wf)) => {
-                    let deser = WalEntry::deserialize(&wf.buf);
-                    out.push(deser);
+                    out.push(wf.deserialize());
                 }
                 Ok(None) => break,
             }
@@ -101,7 +121,7 @@ impl WriteAheadLog {
     }
 
     /// This reads the individual bytes from file but returns a wrapper around the zero copy data
-    pub fn read_next(&mut self) -> Result<Option<WalFrame>, Box<dyn std::error::Error>> {
+    pub fn read_next(&mut self) -> Result<Option<WalFrame<T>>, Box<dyn std::error::Error>> {
         let mut archive_lenght_marker = [0u8; 4];
         // 1. Read the length prefix
         match self.file.read_exact(&mut archive_lenght_marker) {
@@ -115,11 +135,14 @@ impl WriteAheadLog {
         if let Err(e) = self.file.read_exact(&mut buf) {
             return Err(Box::new(e));
         }
-        Ok(Some(WalFrame { buf }))
+        Ok(Some(WalFrame {
+            buf,
+            entry: PhantomData,
+        }))
     }
 }
 
-impl Drop for WriteAheadLog {
+impl<T> Drop for WriteAheadLog<T> {
     /// Safeguard against "safe" exits.
     ///
     /// Does not work in external kill signals like sigkill, oom, power loss, segfault...
@@ -128,16 +151,141 @@ impl Drop for WriteAheadLog {
             eprintln!("WAL: failed to flush on drop: {e}");
             // TODO: return this as a critical error in the error stack
         }
+        if let Err(e) = self.syncer.sync_pending(&self.file) {
+            eprintln!("WAL: failed to sync on drop: {e}");
+        }
+    }
+}
+
+impl<T: LogEntry> super::WriteAheadLog<T> for WriteAheadLog<T> {
+    type Reader = WalReader<T>;
+
+    fn append(&mut self, entry: &T) -> WalResult<u64> {
+        self.write_entry(entry)?;
+        Ok(self.last_log_index)
+    }
+
+    fn read_from(&mut self, index: u64) -> WalResult<WalReader<T>> {
+        if index < 1 {
+            return Err(WalError::IndexOutOfRange(index));
+        }
+        let (offset, _) = self.scan(index - 1)?;
+        Ok(WalReader {
+            file: self.file.try_clone()?,
+            offset,
+            next_index: index,
+            entry: PhantomData,
+        })
+    }
+
+    fn first_index(&self) -> u64 {
+        1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.last_log_index
+    }
+
+    fn truncate_after(&mut self, index: u64) -> WalResult<()> {
+        if index >= self.last_log_index {
+            return Ok(());
+        }
+        let (offset, _) = self.scan(index)?;
+        self.file.set_len(offset)?;
+        self.file.sync_data()?;
+        self.last_log_index = index;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> WalResult<()> {
+        Ok(self.syncer.sync(&self.file)?)
+    }
+
+    fn close(mut self) -> WalResult<()> {
+        Ok(self.syncer.sync_pending(&self.file)?)
+    }
+}
+
+/// Entries of a [`WriteAheadLog`] from some index on, see
+/// [`WriteAheadLog::read_from`](super::WriteAheadLog::read_from).
+///
+/// Reads through its own file handle, at its own offset, so the log can keep appending.
+#[derive(Debug)]
+pub struct WalReader<T = WalEntry> {
+    file: File,
+    offset: u64,
+    next_index: u64,
+    entry: PhantomData<fn() -> T>,
+}
+
+impl<T: LogEntry> WalReader<T> {
+    /// Reads the log at `path` from its first entry, without opening it for writing.
+    pub fn open(path: &Path) -> WalResult<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            offset: 0,
+            next_index: 1,
+            entry: PhantomData,
+        })
+    }
+
+    fn read_next(&mut self) -> WalResult<Option<(u64, T)>> {
+        let Some(blob_len) = blob_len_at(&self.file, self.offset)? else {
+            return Ok(None);
+        };
+        let mut blob = vec![0u8; blob_len as usize];
+        self.file
+            .read_exact_at(&mut blob, self.offset + LEN_PREFIX)
+            .map_err(|e| truncated(e, self.offset))?;
+        let index = self.next_index;
+        self.offset += LEN_PREFIX + blob_len as u64;
+        self.next_index += 1;
+        Ok(Some((index, deserialize(&blob)?)))
+    }
+}
+
+impl<T: LogEntry> Iterator for WalReader<T> {
+    type Item = WalResult<(u64, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.read_next();
+        if next.is_err() {
+            // Do not keep on failing at the same frame
+            self.offset = u64::MAX;
+        }
+        next.transpose()
+    }
+}
+
+/// Reads the length prefix of the frame at `offset`, `None` at the end of `file`.
+fn blob_len_at(file: &File, offset: u64) -> WalResult<Option<u32>> {
+    let mut len = [0u8; LEN_PREFIX as usize];
+    match file.read_exact_at(&mut len, offset) {
+        Ok(()) => Ok(Some(u32::from_le_bytes(len))),
+        Err(_) if offset >= file.metadata()?.len() => Ok(None),
+        Err(e) => Err(truncated(e, offset)),
+    }
+}
+
+fn truncated(e: io::Error, offset: u64) -> WalError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => WalError::TruncatedFrame { offset },
+        _ => e.into(),
     }
 }
 
 // Contains the entry bytes and it's zero copy
-pub struct WalFrame {
+pub struct WalFrame<T = WalEntry> {
     pub buf: Vec<u8>,
+    entry: PhantomData<fn() -> T>,
 }
 
-impl WalFrame {
-    pub fn zero_copy(&self) -> Result<&ArchivedWalEntry, Box<dyn std::error::Error>> {
-        WalEntry::zero_copy(&self.buf)
+impl<T: LogEntry> WalFrame<T> {
+    pub fn zero_copy(&self) -> Result<&T::Archived, Box<dyn std::error::Error>> {
+        Ok(zero_copy::<T>(&self.buf)?)
+    }
+
+    fn deserialize(&self) -> Result<T, Box<dyn std::error::Error>> {
+        Ok(deserialize(&self.buf)?)
     }
 }
@@ -0,0 +1,569 @@
+use memmap2::Mmap;
+use rkyv::util::AlignedVec;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, IoSlice};
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The whole content of a file, at least 16 bytes aligned so archives can be read in place.
+pub type FileBytes = Box<dyn Deref<Target = [u8]> + Send + Sync>;
+
+/// Where the WAL keeps its files: the file system, memory, or anything in between.
+pub trait Storage: Debug + Send + Sync {
+    /// Opens the file at `path` for reading and writing, creating it if `create` is set.
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>>;
+    /// Files directly inside `dir`.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    /// Atomically replaces `to` with `from`, a file or a directory and everything in it.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Creates `dir` and its parents if needed.
+    fn create_dir(&self, dir: &Path) -> io::Result<()>;
+    /// Makes files created, renamed or removed in `dir` durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+}
+
+/// An open file of a [`Storage`]. Every handle to the same file sees the same bytes.
+pub trait StorageFile: Debug + Send + Sync {
+    /// Writes all of `buf` at the end of the file.
+    fn append(&self, buf: &[u8]) -> io::Result<()>;
+    /// Writes all of `buf` at `offset`, growing the file if it goes past the end.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /// Writes all of `bufs` one after the other from `offset`, as `write_at` would their
+    /// concatenation. What `bufs` holds afterwards is unspecified.
+    fn write_vectored_at(&self, bufs: &mut [IoSlice<'_>], mut offset: u64) -> io::Result<()> {
+        for buf in bufs.iter() {
+            self.write_at(buf, offset)?;
+            offset += buf.len() as u64;
+        }
+        Ok(())
+    }
+    /// Reads from `offset`, returns how many bytes were read. 0 means EOF.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    fn len(&self) -> io::Result<u64>;
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Makes everything written so far durable.
+    fn sync(&self) -> io::Result<()>;
+    /// Cuts the file down to `len` bytes.
+    fn truncate(&self, len: u64) -> io::Result<()>;
+    /// Grows the file to `len` bytes if it is shorter, the new ones reading as zeros. Writing
+    /// over them later does not need to grow the file anymore.
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        match self.len()? < len {
+            true => self.truncate(len),
+            false => Ok(()),
+        }
+    }
+    /// Last time the file was written to.
+    fn modified(&self) -> io::Result<SystemTime>;
+    fn try_clone(&self) -> io::Result<Box<dyn StorageFile>>;
+    /// The current content of the file. Must not be truncated while the result lives.
+    fn map(&self) -> io::Result<FileBytes>;
+}
+
+/// Regular files through `std::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSystem;
+
+impl Storage for FileSystem {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        // Not in append mode, which would make writes at an offset append too
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(create)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    /// Windows can neither open a directory as a file nor sync one, renames there are durable
+    /// once done.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+        #[cfg(not(unix))]
+        let _ = dir;
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+}
+
+/// Reads and writes at an offset, `pread`/`pwrite` style, wherever `std` has them.
+///
+/// Windows only has calls that may do part of the work and move the file cursor, which nothing
+/// here uses.
+pub(crate) trait FileAt {
+    /// Reads from `offset`, returns how many bytes were read. 0 means EOF.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    /// Writes all of `buf` at `offset`.
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /// Fills `buf` from `offset`, [`io::ErrorKind::UnexpectedEof`] if EOF comes first.
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl FileAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+}
+
+#[cfg(windows)]
+impl FileAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_write(self, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageFile for File {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.write_all_at(buf, StorageFile::len(self)?)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write_all_at(buf, offset)
+    }
+
+    /// One `pwritev` on Linux, repeated until everything is written: like `write`, it may
+    /// write only part of `bufs`, even stopping in the middle of one.
+    #[cfg(target_os = "linux")]
+    fn write_vectored_at(&self, mut bufs: &mut [IoSlice<'_>], mut offset: u64) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        // Most buffers a single call takes, UIO_MAXIOV
+        const IOV_MAX: usize = 1024;
+        IoSlice::advance_slices(&mut bufs, 0);
+        while !bufs.is_empty() {
+            let count = bufs.len().min(IOV_MAX);
+            let at = libc::off_t::try_from(offset)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // SAFETY: IoSlice is ABI compatible with iovec, and `count` of them are there
+            let written =
+                unsafe { libc::pwritev(self.as_raw_fd(), bufs.as_ptr().cast(), count as _, at) };
+            match written {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n if n > 0 => {
+                    offset += n as u64;
+                    IoSlice::advance_slices(&mut bufs, n as usize);
+                }
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileAt::read_at(self, buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+
+    /// Reserves the blocks with `fallocate` on Linux, so neither they nor the size of the file
+    /// have to be updated as it is written. Elsewhere, or on file systems that can't, the file
+    /// is only grown.
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        if StorageFile::len(self)? >= len {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            let len = libc::off_t::try_from(len)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // SAFETY: plain syscall on a descriptor we own, mode 0 grows the file with zeros
+            match unsafe { libc::fallocate(self.as_raw_fd(), 0, 0, len) } {
+                0 => return Ok(()),
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        self.set_len(len)
+    }
+
+    fn modified(&self) -> io::Result<SystemTime> {
+        self.metadata()?.modified()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::try_clone(self)?))
+    }
+
+    fn map(&self) -> io::Result<FileBytes> {
+        // SAFETY: reading a mapping whose file shrinks is undefined behaviour, which is why
+        // callers must not truncate the file while the mapping lives.
+        Ok(Box::new(unsafe { Mmap::map(self)? }))
+    }
+}
+
+/// Files kept in memory, e.g. to run tests and simulations without touching the disk.
+///
+/// Clones share the same files, so a WAL can be closed and opened again on the same
+/// "disk". Nothing is ever lost: syncing is a no-op.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    inner: Arc<Mutex<MemoryFs>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryFs {
+    files: HashMap<PathBuf, Arc<MemoryFile>>,
+    dirs: HashSet<PathBuf>,
+}
+
+impl MemoryStorage {
+    fn fs(&self) -> std::sync::MutexGuard<'_, MemoryFs> {
+        self.inner.lock().expect("memory storage poisoned")
+    }
+}
+
+/// Where `path` ends up once the directory `from` is renamed to `to`.
+pub(super) fn moved(path: PathBuf, from: &Path, to: &Path) -> PathBuf {
+    match path.strip_prefix(from) {
+        Ok(rest) => to.join(rest),
+        Err(_) => path,
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Box<dyn StorageFile>> {
+        let mut fs = self.fs();
+        let file = match fs.files.get(path) {
+            Some(file) => Arc::clone(file),
+            None if create => {
+                let file = Arc::new(MemoryFile::default());
+                fs.files.insert(path.into(), Arc::clone(&file));
+                file
+            }
+            None => return Err(not_found(path)),
+        };
+        Ok(Box::new(MemoryHandle(file)))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let fs = self.fs();
+        if !fs.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(fs
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        // Like unlinking: open handles keep the content
+        self.fs()
+            .files
+            .remove(path)
+            .map(drop)
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut fs = self.fs();
+        if let Some(file) = fs.files.remove(from) {
+            fs.files.insert(to.into(), file);
+            return Ok(());
+        }
+        if !fs.dirs.contains(from) {
+            return Err(not_found(from));
+        }
+        fs.files = mem::take(&mut fs.files)
+            .into_iter()
+            .map(|(path, file)| (moved(path, from, to), file))
+            .collect();
+        fs.dirs = mem::take(&mut fs.dirs)
+            .into_iter()
+            .map(|dir| moved(dir, from, to))
+            .collect();
+        Ok(())
+    }
+
+    fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut fs = self.fs();
+        for dir in dir.ancestors() {
+            fs.dirs.insert(dir.into());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let fs = self.fs();
+        fs.files.contains_key(path) || fs.dirs.contains(path)
+    }
+}
+
+#[derive(Debug)]
+struct MemoryFile {
+    data: Mutex<Vec<u8>>,
+    modified: Mutex<SystemTime>,
+}
+
+impl Default for MemoryFile {
+    fn default() -> Self {
+        Self {
+            data: Mutex::default(),
+            modified: Mutex::new(SystemTime::now()),
+        }
+    }
+}
+
+impl MemoryFile {
+    fn data(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.data.lock().expect("memory file poisoned")
+    }
+
+    fn touch(&self) {
+        *self.modified.lock().expect("memory file poisoned") = SystemTime::now();
+    }
+}
+
+/// An open [`MemoryFile`].
+#[derive(Debug, Clone)]
+struct MemoryHandle(Arc<MemoryFile>);
+
+impl StorageFile for MemoryHandle {
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.0.data().extend_from_slice(buf);
+        self.0.touch();
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut data = self.0.data();
+        let (start, end) = (offset as usize, offset as usize + buf.len());
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        drop(data);
+        self.0.touch();
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.0.data();
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.data().len() as u64)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.0.data().resize(len as usize, 0);
+        self.0.touch();
+        Ok(())
+    }
+
+    fn modified(&self) -> io::Result<SystemTime> {
+        Ok(*self.0.modified.lock().expect("memory file poisoned"))
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn map(&self) -> io::Result<FileBytes> {
+        // A snapshot, copied once into an aligned buffer
+        let mut bytes = AlignedVec::<16>::new();
+        bytes.extend_from_slice(&self.0.data());
+        Ok(Box::new(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_files_are_shared_between_handles_and_clones() {
+        let storage = MemoryStorage::default();
+        let dir = Path::new("/wal");
+        storage.create_dir(dir).expect("");
+        let writer = storage.open(&dir.join("a"), true).expect("");
+        writer.append(b"hello").expect("");
+
+        let reopened = storage.clone();
+        let reader = reopened.open(&dir.join("a"), false).expect("");
+        let mut buf = [0u8; 8];
+        assert_eq!(reader.read_at(&mut buf, 1).expect(""), 4);
+        assert_eq!(&buf[..4], b"ello");
+        assert_eq!(reopened.list(dir).expect(""), vec![dir.join("a")]);
+
+        reopened.rename(&dir.join("a"), &dir.join("b")).expect("");
+        assert!(!storage.exists(&dir.join("a")));
+        writer.truncate(2).expect("");
+        assert_eq!(
+            &**storage
+                .open(&dir.join("b"), false)
+                .expect("")
+                .map()
+                .expect(""),
+            b"he"
+        );
+
+        assert_eq!(
+            storage.open(&dir.join("a"), false).expect_err("").kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // Directories move with their files
+        storage.rename(dir, Path::new("/moved")).expect("");
+        assert!(!storage.exists(dir));
+        assert_eq!(
+            storage.list(Path::new("/moved")).expect(""),
+            vec![Path::new("/moved/b")]
+        );
+    }
+
+    #[test]
+    fn allocated_space_reads_as_zeros_until_written() {
+        let dir = tempfile::TempDir::new().expect("");
+        let storages: [(Box<dyn Storage>, PathBuf); 2] = [
+            (Box::new(FileSystem), dir.path().to_path_buf()),
+            (Box::new(MemoryStorage::default()), PathBuf::from("/wal")),
+        ];
+        for (storage, dir) in storages {
+            storage.create_dir(&dir).expect("");
+            let file = storage.open(&dir.join("a"), true).expect("");
+            file.append(b"head").expect("");
+            file.allocate(4096).expect("");
+            assert_eq!(file.len().expect(""), 4096);
+            file.write_at(b"mid", 8).expect("");
+            file.append(b"tail").expect("");
+            // Never shrinks
+            file.allocate(16).expect("");
+
+            let bytes = file.map().expect("");
+            assert_eq!(bytes.len(), 4100);
+            assert_eq!(&bytes[..12], b"head\0\0\0\0mid\0");
+            assert!(bytes[12..4096].iter().all(|&b| b == 0));
+            assert_eq!(&bytes[4096..], b"tail");
+        }
+    }
+
+    #[test]
+    fn vectored_writes_land_like_one_write_of_their_concatenation() {
+        let dir = tempfile::TempDir::new().expect("");
+        let storages: [(Box<dyn Storage>, PathBuf); 2] = [
+            (Box::new(FileSystem), dir.path().to_path_buf()),
+            (Box::new(MemoryStorage::default()), PathBuf::from("/wal")),
+        ];
+        // More buffers than one pwritev takes, some of them empty
+        let parts: Vec<Vec<u8>> = (0..3000u32)
+            .map(|i| vec![i as u8; (i % 5) as usize])
+            .collect();
+        let expected = parts.concat();
+        for (storage, dir) in storages {
+            storage.create_dir(&dir).expect("");
+            let file = storage.open(&dir.join("a"), true).expect("");
+            file.append(b"head").expect("");
+            let mut bufs: Vec<IoSlice> = parts.iter().map(|p| IoSlice::new(p)).collect();
+            file.write_vectored_at(&mut bufs, 2).expect("");
+
+            let bytes = file.map().expect("");
+            assert_eq!(&bytes[..2], b"he");
+            assert_eq!(&bytes[2..], expected.as_slice());
+        }
+    }
+}
@@ -1,46 +1,36 @@
 #![allow(dead_code, unused, unused_imports)]
 use rkyv::{access, rancor::Failure};
-use rkyv::{deserialize, rancor::Error, Archive, Deserialize, Serialize};
+use rkyv::{rancor::Error, Archive, Deserialize, Serialize};
 use std::io::{self, IoSlice, Write};
 use std::io::{Read, Seek, SeekFrom};
+use std::marker::PhantomData;
 use std::process::abort;
 use std::{collections::HashMap, fs::File};
 
+use super::durability::Syncer;
+use super::storage::FileAt;
+use super::{
+    deserialize, serialize, zero_copy, DurabilityPolicy, LogEntry, WalEntry, WalError, WalResult,
+};
+
 const GENERATION: u64 = 0;
 const HEADER_LEN: usize = 8 /*index*/ + 8 /*generation*/ + 4 /*blob len*/;
 
-#[derive(Archive, Deserialize, Serialize, Debug)]
-pub enum WalEntry {
-    Set(String, String),
-    Batch(HashMap<String, String>),
-}
-
-impl WalEntry {
-    fn serialize(&self) -> rkyv::util::AlignedVec {
-        rkyv::to_bytes::<Error>(self).expect("serialize WalEntry")
-    }
-
-    fn deserialize(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
-        // This is not too efficient since we are deserializing and thus copying data
-        // We Should pass around the archived reference
-        let archived = rkyv::access::<ArchivedWalEntry, Failure>(bytes)?;
-        Ok(rkyv::deserialize::<WalEntry, Error>(archived)?)
-    }
-    fn zero_copy(bytes: &[u8]) -> Result<&ArchivedWalEntry, Box<dyn std::error::Error>> {
-        Ok(rkyv::access::<ArchivedWalEntry, Failure>(bytes)?)
-    }
-}
-
 // Contains the binary file data and some useful metadata.
-pub struct WalFrame {
+pub struct WalFrame<T = WalEntry> {
     pub index: u64,
     pub generation: u64,
     pub buf: Vec<u8>,
+    entry: PhantomData<fn() -> T>,
 }
 
-impl WalFrame {
-    pub fn zero_copy(&self) -> Result<&ArchivedWalEntry, Box<dyn std::error::Error>> {
-        WalEntry::zero_copy(&self.buf)
+impl<T: LogEntry> WalFrame<T> {
+    pub fn zero_copy(&self) -> Result<&T::Archived, Box<dyn std::error::Error>> {
+        Ok(zero_copy::<T>(&self.buf)?)
+    }
+
+    fn deserialize(&self) -> Result<T, Box<dyn std::error::Error>> {
+        Ok(deserialize(&self.buf)?)
     }
 }
 
@@ -48,35 +38,75 @@ impl WalFrame {
 pub struct WALConfig {
     pub path: String,
     pub truncate: bool,
+    pub durability: DurabilityPolicy,
 }
 
 #[derive(Debug)]
-pub struct WriteAheadLog {
+pub struct WriteAheadLog<T = WalEntry> {
     file: File,
     last_log_index: u64,
+    generation: u64,
+    syncer: Syncer,
+    entry: PhantomData<fn(T) -> T>,
 }
 
-impl WriteAheadLog {
+impl<T: LogEntry> WriteAheadLog<T> {
     /// Opens a new R/W WAL
     ///
     pub fn open(cfg: WALConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut f_opts = File::options();
-        f_opts.read(true).write(true).create(true);
-        match cfg.truncate {
-            true => f_opts.truncate(true),
-            false => f_opts.append(true),
-        };
-
-        let f = f_opts.open(&cfg.path)?;
-        // TODO: read from last log index???
-        Ok(Self {
+        let f = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&cfg.path)?;
+        if cfg.truncate {
+            f.set_len(0)?;
+        }
+        let mut wal = Self {
+            syncer: Syncer::new(cfg.durability, &f)?,
             file: f,
             last_log_index: 0,
-        })
+            generation: GENERATION,
+            entry: PhantomData,
+        };
+        wal.recover(&cfg.path)?;
+        Ok(wal)
+    }
+
+    /// Scans the whole log to restore the last index and generation, so new writes carry on
+    /// from there. Fails if the indexes are not contiguous.
+    fn recover(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut expected = 1;
+        while let Some(frame) = self.read_next()? {
+            if frame.index != expected {
+                return Err(Box::new(WalError::NonContiguous {
+                    segment: path.into(),
+                    expected,
+                    found: frame.index,
+                }));
+            }
+            expected += 1;
+            self.generation = frame.generation;
+        }
+        // Leave it ready to be replayed from the start
+        self.file.rewind()?;
+        Ok(())
+    }
+
+    /// Offset of the frame of `index`, or of the end of the file if the log stops before it.
+    fn offset_of(&self, index: u64) -> WalResult<u64> {
+        let mut offset = 0;
+        while let Some((header_index, blob_len)) = header_at(&self.file, offset)? {
+            if header_index >= index {
+                break;
+            }
+            offset += (HEADER_LEN + blob_len) as u64;
+        }
+        Ok(offset)
     }
 }
 
-impl WriteAheadLog {
+impl<T: LogEntry> WriteAheadLog<T> {
     /// Writes to a log file with the following structure
     ///
     ///┌───────────┬────────────┬───────────┬───────────┐
@@ -85,12 +115,17 @@ impl WriteAheadLog {
     ///└───────────┴────────────┴───────────┴───────────┘
     ///
     /// It's not calling flush() constantly since we are not using a BufWriter as of now.
-    pub fn write(&mut self, cmd: WalEntry) -> Result<(), std::io::Error> {
-        let blob = cmd.serialize();
+    /// Returns once the entry is as durable as `WALConfig::durability` promises.
+    pub fn write(&mut self, cmd: T) -> Result<(), std::io::Error> {
+        self.write_entry(&cmd)
+    }
+
+    fn write_entry(&mut self, cmd: &T) -> Result<(), std::io::Error> {
+        let blob = serialize(cmd).map_err(io::Error::other)?;
 
         let blob_len = blob.len() as u32;
         let new_index = self.last_log_index + 1;
-        let generation = GENERATION;
+        let generation = self.generation;
         let mut header = [0u8; 20];
         header[0..8].copy_from_slice(&new_index.to_le_bytes());
         header[8..16].copy_from_slice(&generation.to_le_bytes());
@@ -100,12 +135,14 @@ impl WriteAheadLog {
         // buffer!!! So better be slow as of now
         self.file.write_all(&header)?;
         self.file.write_all(&blob)?;
+        self.syncer
+            .written(&self.file, (HEADER_LEN + blob.len()) as u64)?;
 
         self.last_log_index = new_index;
         Ok(())
     }
 
-    pub fn read(&mut self) -> Vec<Result<WalEntry, Box<dyn std::error::Error + 'static>>> {
+    pub fn read(&mut self) -> Vec<Result<T, Box<dyn std::error::Error + 'static>>> {
         // Of course, reading the entire file and sending a Vec is not optimal.
         // We should just have a generator
 
@@ -114,7 +151,7 @@ impl WriteAheadLog {
             return vec![Err(Box::new(e))];
         }
 
-        let mut out: Vec<Result<WalEntry, Box<dyn std::error::Error>>> = Vec::new();
+        let mut out: Vec<Result<T, Box<dyn std::error::Error>>> = Vec::new();
         let mut archive_lenght_marker = [0u8; 4];
 
         loop {
@@ -122,8 +159,7 @@ impl WriteAheadLog {
             match buf {
                 Err(e) => out.push(Err(e)),
                 Ok(Some(wf)) => {
-                    let deser = WalEntry::deserialize(&wf.buf);
-                    out.push(deser);
+                    out.push(wf.deserialize());
                 }
                 Ok(None) => break,
             }
@@ -133,7 +169,7 @@ impl WriteAheadLog {
     }
 
     /// This reads the individual bytes from file but returns a wrapper around the zero copy data
-    pub fn read_next(&mut self) -> Result<Option<WalFrame>, Box<dyn std::error::Error>> {
+    pub fn read_next(&mut self) -> Result<Option<WalFrame<T>>, Box<dyn std::error::Error>> {
         let mut hdr = [0u8; HEADER_LEN];
 
         let mut read = 0;
@@ -169,11 +205,116 @@ impl WriteAheadLog {
             buf,
             generation,
             index,
+            entry: PhantomData,
         }))
     }
 }
 
-impl Drop for WriteAheadLog {
+impl<T: LogEntry> super::WriteAheadLog<T> for WriteAheadLog<T> {
+    type Reader = WalReader<T>;
+
+    fn append(&mut self, entry: &T) -> WalResult<u64> {
+        self.write_entry(entry)?;
+        Ok(self.last_log_index)
+    }
+
+    fn read_from(&mut self, index: u64) -> WalResult<WalReader<T>> {
+        if index < 1 {
+            return Err(WalError::IndexOutOfRange(index));
+        }
+        Ok(WalReader {
+            offset: self.offset_of(index)?,
+            file: self.file.try_clone()?,
+            entry: PhantomData,
+        })
+    }
+
+    fn first_index(&self) -> u64 {
+        1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.last_log_index
+    }
+
+    fn truncate_after(&mut self, index: u64) -> WalResult<()> {
+        if index >= self.last_log_index {
+            return Ok(());
+        }
+        self.file.set_len(self.offset_of(index + 1)?)?;
+        self.file.sync_data()?;
+        self.last_log_index = index;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> WalResult<()> {
+        Ok(self.syncer.sync(&self.file)?)
+    }
+
+    fn close(mut self) -> WalResult<()> {
+        Ok(self.syncer.sync_pending(&self.file)?)
+    }
+}
+
+/// Entries of a [`WriteAheadLog`] from some index on, see
+/// [`WriteAheadLog::read_from`](super::WriteAheadLog::read_from).
+///
+/// Reads through its own file handle, at its own offset, so the log can keep appending.
+#[derive(Debug)]
+pub struct WalReader<T = WalEntry> {
+    file: File,
+    offset: u64,
+    entry: PhantomData<fn() -> T>,
+}
+
+impl<T: LogEntry> WalReader<T> {
+    fn read_next(&mut self) -> WalResult<Option<(u64, T)>> {
+        let Some((index, blob_len)) = header_at(&self.file, self.offset)? else {
+            return Ok(None);
+        };
+        let mut blob = vec![0u8; blob_len];
+        self.file
+            .read_exact_at(&mut blob, self.offset + HEADER_LEN as u64)
+            .map_err(|e| truncated(e, self.offset))?;
+        self.offset += (HEADER_LEN + blob_len) as u64;
+        Ok(Some((index, deserialize(&blob)?)))
+    }
+}
+
+impl<T: LogEntry> Iterator for WalReader<T> {
+    type Item = WalResult<(u64, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.read_next();
+        if next.is_err() {
+            // Do not keep on failing at the same frame
+            self.offset = u64::MAX;
+        }
+        next.transpose()
+    }
+}
+
+/// Reads the index and blob length of the frame at `offset`, `None` at the end of `file`.
+fn header_at(file: &File, offset: u64) -> WalResult<Option<(u64, usize)>> {
+    let mut hdr = [0u8; HEADER_LEN];
+    match file.read_exact_at(&mut hdr, offset) {
+        Ok(()) => {}
+        Err(_) if offset >= file.metadata()?.len() => return Ok(None),
+        Err(e) => return Err(truncated(e, offset)),
+    }
+    let index = u64::from_le_bytes(hdr[0..8].try_into().expect("Issue with index"));
+    let blob_len = u32::from_le_bytes(hdr[16..20].try_into().expect("Issue with blob lenght"));
+    Ok(Some((index, blob_len as usize)))
+}
+
+fn truncated(e: io::Error, offset: u64) -> WalError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => WalError::TruncatedFrame { offset },
+        _ => e.into(),
+    }
+}
+
+impl<T> Drop for WriteAheadLog<T> {
     /// Safeguard against "safe" exits.
     ///
     /// Does not work in external kill signals like sigkill, oom, power loss, segfault...
@@ -182,5 +323,44 @@ impl Drop for WriteAheadLog {
             eprintln!("WAL: failed to flush on drop: {e}");
             // TODO: return this as a critical error in the error stack
         }
+        if let Err(e) = self.syncer.sync_pending(&self.file) {
+            eprintln!("WAL: failed to sync on drop: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn open(file: &NamedTempFile) -> WriteAheadLog {
+        WriteAheadLog::open(WALConfig {
+            path: file.path().to_str().expect("").into(),
+            ..Default::default()
+        })
+        .expect("")
+    }
+
+    #[test]
+    fn reopen_continues_after_the_last_index() {
+        let tmp = NamedTempFile::new().expect("");
+        {
+            let mut wal = open(&tmp);
+            wal.write(WalEntry::Set("a".into(), "1".into())).expect("");
+            wal.write(WalEntry::Set("b".into(), "2".into())).expect("");
+        }
+        {
+            let mut wal = open(&tmp);
+            assert_eq!(wal.last_log_index, 2);
+            wal.write(WalEntry::Set("c".into(), "3".into())).expect("");
+        }
+
+        let mut wal = open(&tmp);
+        let mut indexes = vec![];
+        while let Some(frame) = wal.read_next().expect("") {
+            indexes.push(frame.index);
+        }
+        assert_eq!(indexes, vec![1, 2, 3]);
     }
 }